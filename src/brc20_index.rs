@@ -3,13 +3,27 @@ use crate::brc20_index::utils::{
 };
 
 use self::{
+    block_time::BlockTime,
     deploy::handle_deploy_operation,
+    error::{ErrorAction, IndexerError},
+    health::health,
+    metrics::metrics,
     mint::handle_mint_operation,
     mongo::MongoClient,
-    transfer::{handle_transfer_operation, Brc20ActiveTransfer},
-    user_balance::UserBalanceEntryType,
+    progress::{BlockSummary, Progress},
+    retry::{retry_metrics, RetryClass, RetryPolicy},
+    shutdown::Shutdown,
+    storage::StorageProfile,
+    tip::BlockNotifier,
+    transfer::{
+        active_transfers_at, handle_transfer_operation, ActiveTransferKey, ActiveTransfers,
+        Brc20ActiveTransfer,
+    },
+    user_balance::{EntrySource, UserBalanceEntryType},
     utils::{extract_and_process_witness_data, get_owner_of_vout, get_witness_data_from_raw_tx},
 };
+use crate::config::Config;
+use bitcoin::{Block, BlockHash, Transaction};
 use bitcoincore_rpc::bitcoincore_rpc_json::{
     GetRawTransactionResult, GetRawTransactionResultVin, GetRawTransactionResultVout,
     GetRawTransactionResultVoutScriptPubKey,
@@ -22,397 +36,865 @@ use mongodb::{
 };
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
-    thread::sleep,
-    time::{Duration, Instant},
+    collections::{HashMap, HashSet},
+    future::Future,
+    time::Instant,
 };
+use tracing::{info_span, Instrument};
 
+mod block_time;
 mod brc20_ticker;
 pub mod consts;
 mod deploy;
+pub mod error;
+pub mod health;
+pub mod inspect;
 mod invalid_brc20;
+pub mod mempool;
+pub mod metrics;
 mod mint;
 pub mod mongo;
+pub mod progress;
+#[cfg(test)]
+mod regression;
+pub mod retry;
+pub mod schema;
+pub mod shutdown;
+pub mod simulate;
+pub mod storage;
+mod tip;
 mod transfer;
+pub mod transfer_status;
 mod user_balance;
-mod utils;
+pub mod utils;
+pub mod verify;
 
+/// Indexes blocks from `start_block_height` until the configured stop height,
+/// or forever, following the chain tip.
+///
+/// On shutdown the current block is finished before returning, unless shutdown is
+/// forced, then the current block is abandoned and rolled back.
 pub async fn index_brc20(
     rpc: &Client,
     mongo_client: &MongoClient,
+    config: &Config,
     start_block_height: u32,
-) -> Result<(), Box<dyn std::error::Error>> {
+    shutdown: &Shutdown,
+) -> Result<(), IndexerError> {
+    let retry_policy = &config.retry_policy;
     let mut current_block_height = start_block_height;
+    let mut failed_attempts = 0;
+    let mut last_retry_metrics = retry_metrics();
+    let notifier = BlockNotifier::new(config.zmq_endpoint.as_deref(), config.tip_poll_interval)?;
+    metrics().set_indexed_height(i64::from(start_block_height) - 1);
+    let mut progress = Progress::new(start_block_height.into());
 
     loop {
-        match rpc.get_block_hash(current_block_height.into()) {
-            Ok(current_block_hash) => {
-                match rpc.get_block(&current_block_hash) {
-                    Ok(block) => {
-                        let length = block.txdata.len();
-                        info!(
-                            "Fetched block: {:?}, Transactions: {:?}, Block: {:?}",
-                            current_block_hash, length, current_block_height
-                        );
+        health().progress();
+
+        if shutdown.is_requested() {
+            info!(
+                "Shutting down, the last completed block is {}",
+                i64::from(current_block_height) - 1
+            );
+            return Ok(());
+        }
 
-                        let mut active_transfers_opt =
-                            mongo_client.load_active_transfers_with_retry().await?;
+        if let Some(stop_height) = config.stop_height {
+            if i64::from(current_block_height) > stop_height {
+                info!("Reached stop height {}", stop_height);
+                return Ok(());
+            }
+        }
 
-                        // If active_transfers_opt is None, initialize it with a new HashMap
-                        if active_transfers_opt.is_none() {
-                            active_transfers_opt = Some(HashMap::new());
-                        }
+        // Fetching writes nothing, it can be abandoned on shutdown
+        let fetched = tokio::select! {
+            fetched = fetch_block(rpc, retry_policy, current_block_height) => fetched,
+            _ = shutdown.requested() => continue,
+        };
 
-                        // Vectors for mongo bulk writes
-                        let mut mint_documents = Vec::new();
-                        let mut transfer_documents = Vec::new();
-                        let mut deploy_documents = Vec::new();
-                        let mut invalid_brc20_documents = Vec::new();
-                        let mut user_balance_entry_documents = Vec::new();
-                        let mut tickers: HashMap<String, Document> = HashMap::new();
-                        let mut user_balance_docs_to_update: HashMap<(String, String), Document> =
-                            HashMap::new();
-                        let mut user_balance_docs_to_insert: HashMap<(String, String), Document> =
-                            HashMap::new();
-
-                        // time to process the block
-                        let process_block_start_time = Instant::now();
-
-                        let mut tx_height = 0u32;
-                        for transaction in block.txdata {
-                            let txid = transaction.txid();
-                            // Get Raw Transaction Info
-                            let raw_tx = match rpc.get_raw_transaction_info(&txid, None) {
-                                Ok(tx) => tx,
-                                Err(e) => {
-                                    error!("Failed to get raw transaction info: {:?}", e);
-                                    continue; // This will skip the current iteration of the loop
-                                }
-                            };
-
-                            // Get witness data from raw transaction
-                            let witness_data = match get_witness_data_from_raw_tx(&raw_tx) {
-                                Ok(data) => data,
-                                Err(e) => {
-                                    error!("Failed to get witness data: {:?}", e);
-                                    continue;
-                                }
-                            };
-
-                            let mut inscription_found = false;
-                            for witness in witness_data {
-                                if let Some(inscription) = extract_and_process_witness_data(witness)
-                                {
-                                    // log raw brc20 data
-                                    let pretty_json =
-                                        serde_json::to_string(&inscription).unwrap_or_default();
-                                    info!("Raw Brc-20 data: {}", pretty_json);
-
-                                    // get owner address, inscription is first satoshi of first output
-                                    let owner = match get_owner_of_vout(&raw_tx, 0) {
-                                        Ok(owner) => owner,
-                                        Err(e) => {
-                                            error!("Failed to get owner: {:?}", e);
-                                            continue;
-                                        }
-                                    };
+        match &fetched {
+            Ok(_) => health().node_reachable(true),
+            Err(e) => health().record_error(e),
+        }
 
-                                    match &inscription.op[..] {
-                                        "deploy" => {
-                                            match handle_deploy_operation(
-                                                mongo_client,
-                                                inscription,
-                                                &raw_tx,
-                                                owner,
-                                                current_block_height,
-                                                tx_height,
-                                                &mut invalid_brc20_documents,
-                                            )
-                                            .await
-                                            {
-                                                Ok(deploy) => {
-                                                    inscription_found = deploy.is_valid();
-                                                    if inscription_found {
-                                                        deploy_documents.push(deploy.to_document());
-                                                    }
-                                                }
-                                                Err(e) => {
-                                                    error!(
-                                                        "Error handling deploy operation: {:?}",
-                                                        e
-                                                    );
-                                                }
-                                            };
-                                        }
-                                        "mint" => {
-                                            match handle_mint_operation(
-                                                mongo_client,
-                                                current_block_height,
-                                                tx_height,
-                                                owner,
-                                                inscription,
-                                                &raw_tx,
-                                                &mut tickers,
-                                                &mut invalid_brc20_documents,
-                                            )
-                                            .await
-                                            {
-                                                Ok((mint, user_balance_entry)) => {
-                                                    inscription_found = mint.is_valid();
-                                                    if inscription_found {
-                                                        mint_documents.push(mint.to_document());
-                                                        user_balance_entry_documents
-                                                            .push(user_balance_entry.to_document());
-
-                                                        // Update user balance docs
-                                                        match update_receiver_balance_document(
-                                                            mongo_client,
-                                                            &mut user_balance_docs_to_update,
-                                                            &mut user_balance_docs_to_insert,
-                                                            &user_balance_entry,
-                                                        )
-                                                        .await
-                                                        {
-                                                            Ok(_) => {}
-                                                            Err(e) => {
-                                                                error!(
-                                                                    "Error updating user balance docs: {:?}",
-                                                                    e
-                                                                );
-                                                            }
-                                                        }
-                                                    }
-                                                }
-                                                Err(e) => {
-                                                    error!(
-                                                        "Error handling mint operation: {:?}",
-                                                        e
-                                                    );
-                                                }
-                                            };
+        let block = match fetched {
+            Ok(Some(block)) => block,
+            Ok(None) => {
+                // The tip can be replaced without the chain getting longer
+                let last_block_height = i64::from(current_block_height) - 1;
+                if let Some(fork_height) =
+                    find_reorg(rpc, mongo_client, retry_policy, last_block_height).await?
+                {
+                    current_block_height = handle_reorg(mongo_client, fork_height).await?;
+                    continue;
+                }
+
+                // Caught up with the chain tip, wait for the next block
+                debug!("Waiting for block {}...", current_block_height);
+                tokio::select! {
+                    _ = notifier.wait() => {}
+                    _ = shutdown.requested() => {}
+                }
+                continue;
+            }
+            Err(e) if e.action() == ErrorAction::Retry => {
+                failed_attempts += 1;
+                let backoff = retry_policy.backoff_with_jitter(failed_attempts);
+                error!(
+                    "Failed to fetch block {}: {}, retrying in {:?}...",
+                    current_block_height, e, backoff
+                );
+                tokio::select! {
+                    _ = tokio::time::sleep(backoff) => {}
+                    _ = shutdown.requested() => {}
+                }
+                continue;
+            }
+            Err(e) => {
+                error!(
+                    "Failed to fetch block {}: {}, aborting",
+                    current_block_height, e
+                );
+                return Err(e);
+            }
+        };
+
+        // The block must extend the last indexed one, otherwise the chain was reorganized
+        let last_block_height = i64::from(current_block_height) - 1;
+        let last_block_hash = mongo_client
+            .get_completed_block_hash(last_block_height)
+            .await?;
+        if last_block_hash.is_some_and(|hash| hash != block.header.prev_blockhash) {
+            if let Some(fork_height) =
+                find_reorg(rpc, mongo_client, retry_policy, last_block_height).await?
+            {
+                current_block_height = handle_reorg(mongo_client, fork_height).await?;
+            }
+            continue;
+        }
+
+        info!(
+            "Fetched block: {:?}, Transactions: {:?}, Block: {:?}",
+            block.block_hash(),
+            block.txdata.len(),
+            current_block_height
+        );
+
+        let block_span =
+            info_span!("block", height = current_block_height, hash = %block.block_hash());
+
+        // A forced shutdown drops the block half written, it's rolled back below
+        let indexed = tokio::select! {
+            indexed = index_block(rpc, mongo_client, config, block, current_block_height)
+                .instrument(block_span) => indexed,
+            _ = shutdown.forced() => {
+                warn!("Abandoning block {}", current_block_height);
+                rollback_to_block_height(mongo_client, current_block_height.into()).await?;
+                continue;
+            }
+        };
+
+        match &indexed {
+            Ok(_) => health().storage_reachable(true),
+            Err(e) => health().record_error(e),
+        }
+
+        match indexed {
+            Ok(summary) => {
+                failed_attempts = 0;
+                progress.block_completed(
+                    current_block_height.into(),
+                    metrics().node_height.get(),
+                    &summary,
+                );
+
+                // Only log the retry metrics when something was retried
+                let metrics = retry_metrics();
+                if metrics != last_retry_metrics {
+                    info!("Retry metrics: {:?}", metrics);
+                    last_retry_metrics = metrics;
+                }
+
+                // Increment the block height
+                current_block_height += 1;
+            }
+            Err(e) if e.action() == ErrorAction::Retry => {
+                failed_attempts += 1;
+                let backoff = retry_policy.backoff_with_jitter(failed_attempts);
+                error!(
+                    "Failed to index block {}: {}, retrying in {:?}...",
+                    current_block_height, e, backoff
+                );
+                tokio::select! {
+                    _ = tokio::time::sleep(backoff) => {}
+                    _ = shutdown.requested() => {}
+                }
+
+                // Remove whatever was written for this block before indexing it again
+                rollback_to_block_height(mongo_client, current_block_height.into()).await?;
+            }
+            Err(e) => {
+                error!(
+                    "Failed to index block {}: {}, aborting",
+                    current_block_height, e
+                );
+                return Err(e);
+            }
+        }
+    }
+}
+
+// Fetches the block at the given height, or None if the node doesn't have it yet
+async fn fetch_block(
+    rpc: &Client,
+    retry_policy: &RetryPolicy,
+    block_height: u32,
+) -> Result<Option<Block>, IndexerError> {
+    let block_count = retry_policy
+        .run(RetryClass::Rpc, "get_block_count", move || async move {
+            Ok(rpc.get_block_count()?)
+        })
+        .await?;
+    metrics().set_node_height(block_count as i64);
+
+    if u64::from(block_height) > block_count {
+        return Ok(None);
+    }
+
+    let block = retry_policy
+        .run(RetryClass::Rpc, "get_block", move || async move {
+            let block_hash = rpc.get_block_hash(block_height.into())?;
+            Ok(rpc.get_block(&block_hash)?)
+        })
+        .await?;
+
+    Ok(Some(block))
+}
+
+// Finds where the indexed blocks left the node's chain, see walk_back
+async fn find_reorg(
+    rpc: &Client,
+    mongo_client: &MongoClient,
+    retry_policy: &RetryPolicy,
+    block_height: i64,
+) -> Result<Option<i64>, IndexerError> {
+    let block_count = retry_policy
+        .run(RetryClass::Rpc, "get_block_count", move || async move {
+            Ok(rpc.get_block_count()?)
+        })
+        .await?;
+
+    let final_height = mongo_client.get_final_block_height().await?;
+
+    walk_back(
+        block_height,
+        final_height,
+        |height| mongo_client.get_completed_block_hash(height),
+        |height| async move {
+            // The node's chain can also have become shorter
+            if height as u64 > block_count {
+                return Ok(None);
+            }
+            let chain_hash = retry_policy
+                .run(RetryClass::Rpc, "get_block_hash", move || async move {
+                    Ok(rpc.get_block_hash(height as u64)?)
+                })
+                .await?;
+            Ok(Some(chain_hash))
+        },
+    )
+    .await
+}
+
+/// Walks back from `block_height` to find where the indexed blocks left the node's chain,
+/// comparing the `indexed_hash` of every block with its `chain_hash` on the node.
+///
+/// Returns the first block that isn't on the node's chain anymore, or None if
+/// `block_height` still is. Blocks completed without a recorded hash are assumed to be
+/// on the chain. Fails if a final block left the chain, those are never rolled back
+/// automatically.
+async fn walk_back<I, IF, C, CF>(
+    block_height: i64,
+    final_height: Option<i64>,
+    mut indexed_hash: I,
+    mut chain_hash: C,
+) -> Result<Option<i64>, IndexerError>
+where
+    I: FnMut(i64) -> IF,
+    IF: Future<Output = Result<Option<BlockHash>, IndexerError>>,
+    C: FnMut(i64) -> CF,
+    CF: Future<Output = Result<Option<BlockHash>, IndexerError>>,
+{
+    let mut fork_height = None;
+    let mut height = block_height;
+    while height >= 0 {
+        let indexed_hash = match indexed_hash(height).await? {
+            Some(hash) => hash,
+            None => break,
+        };
+        if chain_hash(height).await? == Some(indexed_hash) {
+            break;
+        }
+
+        if final_height.is_some_and(|final_height| height <= final_height) {
+            return Err(IndexerError::Protocol(format!(
+                "final block {} isn't on the node's chain anymore, the reorganization is deeper \
+                 than the confirmation depth, roll back below it with `rollback --to`",
+                height
+            )));
+        }
+
+        fork_height = Some(height);
+        height -= 1;
+    }
+
+    Ok(fork_height)
+}
+
+// Removes the blocks that left the chain, returns the block to continue indexing from
+async fn handle_reorg(mongo_client: &MongoClient, fork_height: i64) -> Result<u32, IndexerError> {
+    warn!(
+        "Chain reorganization, rolling back to block {}",
+        fork_height - 1
+    );
+    rollback_to_block_height(mongo_client, fork_height).await?;
+
+    u32::try_from(fork_height)
+        .map_err(|_| IndexerError::Protocol(format!("invalid fork height: {}", fork_height)))
+}
+
+/// Indexes all BRC20 operations of a block and writes the results to MongoDB.
+///
+/// Errors that only affect a single transaction, like undecodable witness data or
+/// protocol violations, are logged and the transaction is skipped. Every other error
+/// is returned, leaving it to the caller to retry or abort the block.
+async fn index_block(
+    rpc: &Client,
+    mongo_client: &MongoClient,
+    config: &Config,
+    block: Block,
+    current_block_height: u32,
+) -> Result<BlockSummary, IndexerError> {
+    let retry_policy = &config.retry_policy;
+    let block_hash = block.block_hash();
+    let mut summary = BlockSummary::default();
+
+    // Records are stamped with the time of the block, not the time they are written
+    let header = retry_policy
+        .run(
+            RetryClass::Rpc,
+            "get_block_header_info",
+            move || async move { Ok(rpc.get_block_header_info(&block_hash)?) },
+        )
+        .await?;
+    let block_time = BlockTime::from_secs(
+        block.header.time.into(),
+        header.median_time.unwrap_or(header.time) as u64,
+    );
+    let block_timer = metrics().block_processing_seconds.start_timer();
+    let mut active_transfers_opt = mongo_client.load_active_transfers_with_retry().await?;
+
+    // If active_transfers_opt is None, initialize it with a new map
+    if active_transfers_opt.is_none() {
+        active_transfers_opt = Some(ActiveTransfers::new());
+    }
+    // The active transfers stored before the block, only the difference is written after it
+    let stored_active_transfers: HashSet<ActiveTransferKey> = active_transfers_opt
+        .iter()
+        .flat_map(|active_transfers| active_transfers.keys().cloned())
+        .collect();
+
+    // Vectors for mongo bulk writes
+    let mut mint_documents = Vec::new();
+    let mut transfer_documents = Vec::new();
+    let mut deploy_documents = Vec::new();
+    let mut invalid_brc20_documents = Vec::new();
+    let mut user_balance_entry_documents = Vec::new();
+    let mut tickers: HashMap<String, Document> = HashMap::new();
+    let mut user_balance_docs_to_update: HashMap<(String, String), Document> = HashMap::new();
+    let mut user_balance_docs_to_insert: HashMap<(String, String), Document> = HashMap::new();
+
+    // time to process the block
+    let process_block_start_time = Instant::now();
+
+    let mut tx_height = 0u32;
+    for transaction in block.txdata {
+        let txid = transaction.txid();
+        let tx_span = info_span!("tx", %txid, tx_height);
+        async {
+            // Get Raw Transaction Info
+            let raw_tx = retry_policy
+                .run(
+                    RetryClass::Rpc,
+                    "get_raw_transaction_info",
+                    move || async move { Ok(rpc.get_raw_transaction_info(&txid, None)?) },
+                )
+                .await?;
+
+            // Get witness data from raw transaction
+            let witness_data = match get_witness_data_from_raw_tx(&raw_tx) {
+                Ok(data) => data,
+                Err(e) => {
+                    error!("Failed to get witness data: {:?}", e);
+                    return Ok(());
+                }
+            };
+
+            let mut inscription_found = false;
+            let mut inscription_index = 0;
+            for witness in witness_data {
+                if let Some(inscription) = extract_and_process_witness_data(witness) {
+                    // Inscriptions are numbered in the order they appear in the transaction
+                    let inscription_id = format!("{}i{}", txid, inscription_index);
+                    let inscription_span = info_span!(
+                        "inscription",
+                        id = %inscription_id,
+                        op = %inscription.op,
+                        tick = %inscription.tick,
+                    );
+                    inscription_index += 1;
+                    summary.inscriptions += 1;
+                    // The documents the inscription adds are keyed by its id
+                    let written = [
+                        deploy_documents.len(),
+                        mint_documents.len(),
+                        transfer_documents.len(),
+                        invalid_brc20_documents.len(),
+                    ];
+
+                    async {
+                        debug!(
+                            "Raw Brc-20 data: {}",
+                            serde_json::to_string(&inscription).unwrap_or_default()
+                        );
+
+                        // get owner address, inscription is first satoshi of first output
+                        let owner = match get_owner_of_vout(&raw_tx, 0, config.network) {
+                            Ok(owner) => owner,
+                            Err(e) => {
+                                error!("Failed to get owner: {:?}", e);
+                                return Ok(());
+                            }
+                        };
+
+                        match &inscription.op[..] {
+                            "deploy" => {
+                                match handle_deploy_operation(
+                                    mongo_client,
+                                    inscription,
+                                    &raw_tx,
+                                    owner,
+                                    current_block_height,
+                                    tx_height,
+                                    &mut invalid_brc20_documents,
+                                )
+                                .await
+                                {
+                                    Ok(deploy) => {
+                                        inscription_found = deploy.is_valid();
+                                        count_valid_operation("deploy", inscription_found);
+                                        if inscription_found {
+                                            summary.deploys += 1;
+                                            deploy_documents.push(deploy.to_document());
                                         }
-                                        "transfer" => {
-                                            match handle_transfer_operation(
+                                    }
+                                    Err(e) if e.action() == ErrorAction::Skip => {
+                                        error!("Error handling deploy operation: {}", e);
+                                    }
+                                    Err(e) => return Err(e),
+                                };
+                            }
+                            "mint" => {
+                                match handle_mint_operation(
+                                    mongo_client,
+                                    current_block_height,
+                                    tx_height,
+                                    owner,
+                                    inscription,
+                                    inscription_id.clone(),
+                                    &raw_tx,
+                                    &mut tickers,
+                                    &mut invalid_brc20_documents,
+                                )
+                                .await
+                                {
+                                    Ok((mint, user_balance_entry)) => {
+                                        inscription_found = mint.is_valid();
+                                        count_valid_operation("mint", inscription_found);
+                                        if inscription_found {
+                                            summary.mints += 1;
+                                            mint_documents.push(mint.to_document());
+                                            user_balance_entry_documents
+                                                .push(user_balance_entry.to_document());
+
+                                            // Update user balance docs
+                                            match update_receiver_balance_document(
                                                 mongo_client,
-                                                current_block_height,
-                                                tx_height,
-                                                inscription,
-                                                &raw_tx,
-                                                owner,
-                                                &mut active_transfers_opt,
                                                 &mut user_balance_docs_to_update,
                                                 &mut user_balance_docs_to_insert,
-                                                &mut invalid_brc20_documents,
+                                                &user_balance_entry,
                                             )
                                             .await
                                             {
-                                                Ok((transfer, user_balance_entry)) => {
-                                                    inscription_found = transfer.is_valid();
-                                                    if inscription_found {
-                                                        transfer_documents
-                                                            .push(transfer.to_document());
-
-                                                        user_balance_entry_documents
-                                                            .push(user_balance_entry.to_document());
-                                                    }
-                                                }
-                                                Err(e) => {
+                                                Ok(_) => {}
+                                                Err(e) if e.action() == ErrorAction::Skip => {
                                                     error!(
-                                                        "Error handling transfer inscription: {:?}",
+                                                        "Error updating user balance docs: {}",
                                                         e
                                                     );
                                                 }
-                                            };
-                                        }
-                                        _ => {
-                                            // Unexpected operation
-                                            error!("Unexpected operation: {}", inscription.op);
+                                                Err(e) => return Err(e),
+                                            }
                                         }
                                     }
-                                }
+                                    Err(e) if e.action() == ErrorAction::Skip => {
+                                        error!("Error handling mint operation: {}", e);
+                                    }
+                                    Err(e) => return Err(e),
+                                };
                             }
-
-                            // if no inscription found, check for transfer send
-                            if !inscription_found {
-                                if active_transfers_opt.is_none() {
-                                    active_transfers_opt = Some(HashMap::new());
-                                }
-                                if let Some(ref mut active_transfers) = &mut active_transfers_opt {
-                                    match check_for_transfer_send(
-                                        mongo_client,
-                                        &rpc,
-                                        &raw_tx,
-                                        current_block_height.into(),
-                                        tx_height.into(),
-                                        active_transfers,
-                                        &mut transfer_documents,
-                                        &mut user_balance_entry_documents,
-                                        &mut user_balance_docs_to_update,
-                                        &mut user_balance_docs_to_insert,
-                                    )
-                                    .await
-                                    {
-                                        Ok(_) => (),
-                                        Err(e) => {
-                                            error!("Error checking for transfer send: {:?}", e);
+                            "transfer" => {
+                                match handle_transfer_operation(
+                                    mongo_client,
+                                    current_block_height,
+                                    tx_height,
+                                    inscription,
+                                    inscription_id.clone(),
+                                    &raw_tx,
+                                    owner,
+                                    &mut active_transfers_opt,
+                                    &mut user_balance_docs_to_update,
+                                    &mut user_balance_docs_to_insert,
+                                    &mut invalid_brc20_documents,
+                                )
+                                .await
+                                {
+                                    Ok((transfer, user_balance_entry)) => {
+                                        inscription_found = transfer.is_valid();
+                                        count_valid_operation("transfer", inscription_found);
+                                        if inscription_found {
+                                            summary.transfers += 1;
+                                            transfer_documents.push(transfer.to_document());
+
+                                            user_balance_entry_documents
+                                                .push(user_balance_entry.to_document());
                                         }
-                                    };
-                                }
+                                    }
+                                    Err(e) if e.action() == ErrorAction::Skip => {
+                                        error!("Error handling transfer inscription: {}", e);
+                                    }
+                                    Err(e) => return Err(e),
+                                };
+                            }
+                            _ => {
+                                // Unexpected operation
+                                error!("Unexpected operation: {}", inscription.op);
                             }
-
-                            // Increment the tx height
-                            tx_height += 1;
                         }
 
-                        // time to process the block
-                        warn!(
-                            "Transactions Processed: {} in {:?}",
-                            tx_height,
-                            process_block_start_time.elapsed()
-                        );
+                        Ok::<(), IndexerError>(())
+                    }
+                    .instrument(inscription_span)
+                    .await?;
+
+                    for (documents, written) in [
+                        &mut deploy_documents,
+                        &mut mint_documents,
+                        &mut transfer_documents,
+                        &mut invalid_brc20_documents,
+                    ]
+                    .into_iter()
+                    .zip(written)
+                    {
+                        for document in &mut documents[written..] {
+                            document.insert("inscription_id", &inscription_id);
+                        }
+                    }
+                }
+            }
 
-                        // write the updated and new user balance documents back to MongoDB
-                        if !user_balance_docs_to_update.is_empty()
-                            || !user_balance_docs_to_insert.is_empty()
-                        {
-                            let start = Instant::now();
-                            let start_len = user_balance_docs_to_update.len();
-                            // This removes all UserBalance with 0 in all the balance fields.
-                            user_balance_docs_to_update.retain(|_, user_balance_doc| {
-                                let overall_balance = user_balance_doc
-                                    .get_f64("overall_balance")
-                                    .unwrap_or_default();
-                                let available_balance = user_balance_doc
-                                    .get_f64("available_balance")
-                                    .unwrap_or_default();
-                                let transferable_balance = user_balance_doc
-                                    .get_f64("transferable_balance")
-                                    .unwrap_or_default();
-
-                                overall_balance != 0.0
-                                    || available_balance != 0.0
-                                    || transferable_balance != 0.0
-                            });
-
-                            let len = user_balance_docs_to_update.len();
-
-                            warn!(
-                                "Zeroed User Balances removed: {} in {:?}",
-                                start_len - len,
-                                start.elapsed()
-                            );
-
-                            info!("Inserting User Balances...");
-                            // write user balance documents to mongodb
-                            match mongo_client
-                                .update_user_balances(
-                                    user_balance_docs_to_update,
-                                    user_balance_docs_to_insert,
-                                )
-                                .await
-                            {
-                                Ok(_) => {}
-                                Err(e) => {
-                                    error!("Failed to update user balance documents: {:?}", e);
-                                }
-                            }
+            // if no inscription found, check for transfer send
+            if !inscription_found {
+                if active_transfers_opt.is_none() {
+                    active_transfers_opt = Some(ActiveTransfers::new());
+                }
+                if let Some(ref mut active_transfers) = &mut active_transfers_opt {
+                    match check_for_transfer_send(
+                        mongo_client,
+                        rpc,
+                        config,
+                        &raw_tx,
+                        current_block_height.into(),
+                        tx_height.into(),
+                        &block_time,
+                        active_transfers,
+                        &mut transfer_documents,
+                        &mut user_balance_entry_documents,
+                        &mut user_balance_docs_to_update,
+                        &mut user_balance_docs_to_insert,
+                    )
+                    .await
+                    {
+                        Ok(sends) => summary.sends += sends,
+                        Err(e) if e.action() == ErrorAction::Skip => {
+                            error!("Error checking for transfer send: {}", e);
                         }
+                        Err(e) => return Err(e),
+                    };
+                }
+            }
 
-                        insert_documents_to_mongo_after_each_block(
-                            mongo_client,
-                            mint_documents,
-                            transfer_documents,
-                            deploy_documents,
-                            invalid_brc20_documents,
-                            user_balance_entry_documents,
-                        )
-                        .await?;
+            Ok::<(), IndexerError>(())
+        }
+        .instrument(tx_span)
+        .await?;
 
-                        // Bulk update tickers in mongodb
-                        if !tickers.is_empty() {
-                            // convert tickers hashmap to vec<Document>
-                            let tickers: Vec<Document> =
-                                tickers.into_iter().map(|(_, ticker)| ticker).collect();
+        // Increment the tx height
+        tx_height += 1;
+    }
 
-                            debug!("tickers main loop: {:?}", tickers);
+    // time to process the block
+    summary.transactions = tx_height;
+    summary.processing = process_block_start_time.elapsed();
+    info!(
+        "Transactions Processed: {} in {:?}",
+        tx_height, summary.processing
+    );
+
+    count_invalid_operations(&invalid_brc20_documents);
+    summary.invalid = invalid_brc20_documents.len() as u32;
+    let write_timer = metrics().block_write_seconds.start_timer();
+    let write_start_time = Instant::now();
+
+    // write the updated and new user balance documents back to MongoDB
+    if !user_balance_docs_to_update.is_empty() || !user_balance_docs_to_insert.is_empty() {
+        let start = Instant::now();
+        let start_len = user_balance_docs_to_update.len();
+        // This removes all UserBalance with 0 in all the balance fields.
+        user_balance_docs_to_update.retain(|_, user_balance_doc| {
+            let overall_balance = user_balance_doc
+                .get_f64("overall_balance")
+                .unwrap_or_default();
+            let available_balance = user_balance_doc
+                .get_f64("available_balance")
+                .unwrap_or_default();
+            let transferable_balance = user_balance_doc
+                .get_f64("transferable_balance")
+                .unwrap_or_default();
+
+            overall_balance != 0.0 || available_balance != 0.0 || transferable_balance != 0.0
+        });
 
-                            let start = Instant::now();
-                            for ticker in &tickers {
-                                let filter_doc = doc! {
-                                    "tick": ticker.get_str("tick").unwrap_or_default(),
-                                };
+        let len = user_balance_docs_to_update.len();
 
-                                let update_doc = doc! {
-                                    "$set": ticker,
-                                };
+        debug!(
+            "Zeroed User Balances removed: {} in {:?}",
+            start_len - len,
+            start.elapsed()
+        );
 
-                                mongo_client
-                                    .update_one_with_retries(
-                                        consts::COLLECTION_TICKERS,
-                                        filter_doc,
-                                        update_doc,
-                                        None,
-                                    )
-                                    .await?;
-                            }
+        info!("Inserting User Balances...");
+        // write user balance documents to mongodb
+        mongo_client
+            .update_user_balances(user_balance_docs_to_update, user_balance_docs_to_insert)
+            .await?;
+    }
 
-                            warn!(
-                                "Tickers updated after block: {} in {:?}",
-                                tickers.len(),
-                                start.elapsed()
-                            );
-                        }
+    insert_documents_to_mongo_after_each_block(
+        mongo_client,
+        &block_time,
+        config.storage_profile,
+        mint_documents,
+        transfer_documents,
+        deploy_documents,
+        invalid_brc20_documents,
+        user_balance_entry_documents,
+    )
+    .await?;
+
+    // Bulk update tickers in mongodb
+    if !tickers.is_empty() {
+        // convert tickers hashmap to vec<Document>
+        let tickers: Vec<Document> = tickers.into_values().collect();
+
+        debug!("tickers main loop: {:?}", tickers);
 
-                        // drop mongodb collection right before inserting active transfers
-                        mongo_client
-                            .drop_collection(consts::COLLECTION_BRC20_ACTIVE_TRANSFERS)
-                            .await?;
-
-                        // store active transfer collection, if any
-                        if let Some(active_transfers) = active_transfers_opt {
-                            let length = active_transfers.len();
-                            if !active_transfers.is_empty() {
-                                let start = Instant::now();
-                                mongo_client
-                                    .insert_active_transfers_to_mongodb(active_transfers)
-                                    .await?;
-
-                                info!(
-                                    "Active Transfers inserted to MongoDB after block: {} in {:?}",
-                                    length,
-                                    start.elapsed()
-                                );
-                            }
-                        }
+        let start = Instant::now();
+        for ticker in &tickers {
+            let filter_doc = doc! {
+                "tick": ticker.get_str("tick").unwrap_or_default(),
+            };
+
+            let update_doc = doc! {
+                "$set": ticker,
+            };
+
+            mongo_client
+                .update_one_with_retries(consts::COLLECTION_TICKERS, filter_doc, update_doc, None)
+                .await?;
+        }
 
-                        // After successfully processing the block, store the current_block_height
-                        match mongo_client
-                            .store_completed_block(current_block_height.into())
-                            .await
-                        {
-                            Ok(_) => (),
-                            Err(e) => {
-                                error!("Failed to store last processed block height: {:?}", e);
-                            }
-                        }
+        debug!(
+            "Tickers updated after block: {} in {:?}",
+            tickers.len(),
+            start.elapsed()
+        );
+    }
 
-                        // Increment the block height
-                        current_block_height += 1;
-                    }
-                    Err(e) => {
-                        error!("Failed to fetch block: {:?}, retrying...", e);
-                        sleep(Duration::from_secs(60));
-                    }
-                }
-            }
-            Err(e) => {
-                error!("Failed to fetch block hash for height: {:?}, retrying", e);
-                sleep(Duration::from_secs(60));
-            }
-        }
+    // store the sent and new active transfers, if any
+    if let Some(active_transfers) = active_transfers_opt {
+        metrics()
+            .active_transfers
+            .set(active_transfers.len() as i64);
+        let start = Instant::now();
+        let (deleted, inserted) = mongo_client
+            .write_active_transfers(&stored_active_transfers, &active_transfers)
+            .await?;
+
+        info!(
+            "Active Transfers written to MongoDB after block: {} deleted, {} inserted in {:?}",
+            deleted,
+            inserted,
+            start.elapsed()
+        );
+    }
+
+    // After successfully processing the block, store the current_block_height
+    summary.write = write_start_time.elapsed();
+    mongo_client
+        .store_completed_block(
+            current_block_height.into(),
+            &block_hash,
+            &block_time,
+            &summary,
+        )
+        .await?;
+    // The block has one confirmation while it's the tip
+    mongo_client
+        .mark_final_blocks(i64::from(current_block_height) + 1 - i64::from(config.confirmations))
+        .await?;
+
+    write_timer.observe_duration();
+    block_timer.observe_duration();
+    metrics().set_indexed_height(current_block_height.into());
+
+    Ok(summary)
+}
+
+// Counts an operation that was handled, invalid ones are counted by reason after the block
+fn count_valid_operation(op: &str, is_valid: bool) {
+    if is_valid {
+        metrics().operations.with_label_values(&[op, "valid"]).inc();
+    }
+}
+
+fn count_invalid_operations(invalid_brc20_documents: &[Document]) {
+    for invalid in invalid_brc20_documents {
+        let op = invalid
+            .get_document("inscription")
+            .and_then(|inscription| inscription.get_str("op"))
+            .unwrap_or("unknown");
+        let reason = invalid.get_str("code").unwrap_or("unknown");
+
+        metrics()
+            .operations
+            .with_label_values(&[op, "invalid"])
+            .inc();
+        metrics()
+            .invalid_operations
+            .with_label_values(&[op, reason])
+            .inc();
     }
 }
 
+/// Deletes everything indexed at or above `start_block_height` and restores the
+/// ticker totals and user balances to their state at the end of the previous block.
+///
+/// This is used at startup to clean up after a block that wasn't completed, and
+/// before a failed block is indexed again.
+pub async fn rollback_to_block_height(
+    mongo_client: &MongoClient,
+    start_block_height: i64,
+) -> Result<(), IndexerError> {
+    // delete deploys, mints, transfers, inscriptions, tickers, invalids, entries
+    info!("Deleting incomplete records...");
+    let start = Instant::now();
+
+    let collections = vec![
+        consts::COLLECTION_DEPLOYS,
+        consts::COLLECTION_MINTS,
+        consts::COLLECTION_TRANSFERS,
+        consts::COLLECTION_INVALIDS,
+        consts::COLLECTION_TICKERS,
+        consts::COLLECTION_USER_BALANCE_ENTRY,
+        consts::COLLECTION_BRC20_ACTIVE_TRANSFERS,
+        consts::COLLECTION_TOTAL_MINTED_AT_BLOCK_HEIGHT,
+        consts::COLLECTION_BLOCKS_COMPLETED,
+    ];
+
+    // A rollback can take a while, it counts as progress for the liveness check
+    for collection in collections {
+        mongo_client
+            .delete_from_collection(collection, start_block_height)
+            .await?;
+        health().progress();
+    }
+
+    debug!("Incomplete Block Records deleted: {:?}", start.elapsed());
+
+    info!("Restoring Active Transfers...");
+    let start = Instant::now();
+    let restored = mongo_client
+        .restore_active_transfers(start_block_height)
+        .await?;
+    debug!(
+        "Active Transfers restored: {} in {:?}",
+        restored,
+        start.elapsed()
+    );
+
+    info!("Resetting total_minted for selected tickers...");
+    let start = Instant::now();
+    let updated_tickers = mongo_client
+        .reset_tickers_total_minted(start_block_height)
+        .await?;
+    info!("Reset total_minted for the following tickers:");
+    for ticker in &updated_tickers {
+        info!("{}", ticker);
+    }
+    debug!("Reset total_minted for tickers in: {:?}", start.elapsed());
+    health().progress();
+
+    info!("Deleting User Balances...");
+    let start = Instant::now();
+    //delete user balance collection
+    let deleted_user_balances = mongo_client
+        .delete_user_balances_by_block_height(start_block_height)
+        .await?;
+    info!("Deleted User Balances: {:?}", deleted_user_balances);
+
+    debug!("User Balances Deleted: {:?}", start.elapsed());
+    health().progress();
+
+    // rebuild userbalances
+    info!("Rebuilding User Balances...");
+    let start = Instant::now();
+    mongo_client
+        .rebuild_deleted_user_balances(start_block_height, deleted_user_balances)
+        .await?;
+    debug!("User Balances Rebuilt: {:?}", start.elapsed());
+
+    Ok(())
+}
+
+// The position of a transfer inscription among the transfers not stored yet
+fn find_transfer_document(transfer_documents: &[Document], inscription_id: &str) -> Option<usize> {
+    transfer_documents
+        .iter()
+        .position(|doc| doc.get_str("inscription_id") == Ok(inscription_id))
+}
+
 /// Checks for transfer send events in a transaction and performs the necessary updates in MongoDB.
 ///
 /// This function checks if there are transfer send events in the given transaction and performs the following actions:
@@ -425,9 +907,11 @@ pub async fn index_brc20(
 ///
 /// * `mongo_client` - The MongoDB client for performing database operations.
 /// * `rpc` - The RPC client for interacting with the blockchain.
+/// * `config` - The indexer configuration, for the network and the retry policy.
 /// * `raw_tx_info` - The raw transaction information.
 /// * `block_height` - The block height of the transaction.
 /// * `tx_height` - The transaction height.
+/// * `block_time` - The time of the block, stamped on the sent transfer.
 /// * `active_transfers` - A hashmap containing active transfers.
 /// * `transfer_documents` - A vector of transfer documents.
 /// * `user_balance_entry_documents` - A vector of user balance entry documents.
@@ -435,120 +919,71 @@ pub async fn index_brc20(
 ///
 /// # Returns
 ///
-/// This function returns `Ok(())` if the operation is successful, or an error if any error occurs during the process.
+/// This function returns the number of transfers sent by the transaction if the operation is successful, or an error if any error occurs during the process.
+#[allow(clippy::too_many_arguments)]
 pub async fn check_for_transfer_send(
     mongo_client: &MongoClient,
     rpc: &Client,
+    config: &Config,
     raw_tx_info: &GetRawTransactionResult,
     block_height: u64,
     tx_height: i64,
-    active_transfers: &mut HashMap<(String, i64), Brc20ActiveTransfer>,
+    block_time: &BlockTime,
+    active_transfers: &mut ActiveTransfers,
     transfer_documents: &mut Vec<Document>,
     user_balance_entry_documents: &mut Vec<Document>,
     user_balance_docs_to_update: &mut HashMap<(String, String), Document>,
     user_balances_to_insert: &mut HashMap<(String, String), Document>,
-) -> Result<(), anyhow::Error> {
+) -> Result<u32, IndexerError> {
     let transaction = raw_tx_info.transaction()?;
-
-    for (input_index, input) in transaction.input.iter().enumerate() {
-        let txid = input.previous_output.txid.to_string();
-        let vout = input.previous_output.vout as i64;
-        let key = (txid.clone(), vout);
-
-        // Check if active transfer exists in the HashMap
-        if active_transfers.contains_key(&key) {
-            active_transfers.remove(&key);
-        } else {
-            continue;
-        }
-        info!("Transfer Send Found: {:?}", key);
-        // Check if transfer exists in the transfer_documents vector in memory
-        let index = transfer_documents.iter().position(|doc| {
-            if let Ok(tx) = doc.get_document("tx") {
-                if let Ok(txid) = tx.get_str("txid") {
-                    return txid == txid;
-                }
-            }
-            false
-        });
-
-        let transfer_doc = if let Some(index) = index {
-            // Document found in the vector, remove it from the vector
-            transfer_documents.remove(index)
-        } else {
-            info!("Checking in MongoDB: {:?}", key);
-            // Document not found in the vector, fetch it from MongoDB
-            let filter_doc = doc! {"tx.txid": txid.clone()};
-            match mongo_client
-                .get_document_by_filter(consts::COLLECTION_TRANSFERS, filter_doc)
-                .await?
-            {
-                Some(doc) => doc,
+    let sends = resolve_sends(rpc, config, raw_tx_info, &transaction, active_transfers).await?;
+
+    // Transfers inscribed in an earlier block are read from MongoDB before any send is
+    // applied, an error leaves the active transfers and the transfers of the block as they were
+    let mut stored_transfers = Vec::new();
+    for send in &sends {
+        let inscription_id = &send.active_transfer.inscription_id;
+        stored_transfers.push(
+            match find_transfer_document(transfer_documents, inscription_id) {
+                Some(_) => None,
                 None => {
-                    error!(
-                        "Transfer inscription not found for txid: {}, vout: {}",
-                        txid, vout
-                    );
-                    continue;
+                    mongo_client
+                        .get_document_by_filter(
+                            consts::COLLECTION_TRANSFERS,
+                            doc! { "inscription_id": inscription_id },
+                        )
+                        .await?
                 }
-            }
-        };
-
-        let mut tick = String::new();
-        if let Some(inscription) = transfer_doc.get_document("inscription").ok() {
-            if let Some(tck) = inscription.get_str("tick").ok() {
-                tick = tck.to_string();
-            } else {
-                error!("Failed to get 'tick' field from 'inscription'");
-            }
-        } else {
-            error!("Failed to get 'inscription' document");
-        }
-
-        let from = mongo_client.get_string(&transfer_doc, "from")?;
-        let amount = match mongo_client.get_f64(&transfer_doc, "amt") {
-            Some(amt) => amt,
-            None => 0.0,
-        };
-
-        let proper_vout = if input_index > 0 {
-            // if not in first input, get values of all inputs only up to this input
-            let input_values =
-                utils::transaction_inputs_to_values(rpc, &transaction.input[0..input_index])?;
-
-            // then get the sum these input values
-            let input_value_sum: u64 = input_values.iter().sum();
-            let total_output_value: u64 =
-                transaction.output.iter().map(|output| output.value).sum();
-
-            // If the sum of input values (up to the current input index) is greater than the total output value,
-            // assume that the sender is the receiver.
-            if input_value_sum >= total_output_value {
-                std::usize::MAX // use MAX as a sentinel value
-            } else {
-                // Calculate the index of the output (vout) which is the recipient of the
-                // inscribed satoshi by finding the first output whose value is greater than
-                // the sum of all preceding input values. This is based on the ordinal theory that satoshis are processed in order.
-                transaction
-                    .output
-                    .iter()
-                    .scan(0, |acc, output| {
-                        *acc += output.value;
-                        Some(*acc)
-                    })
-                    .position(|value| value > input_value_sum)
-                    .unwrap_or(transaction.output.len() - 1)
-            }
-        } else {
-            0
-        };
+            },
+        );
+    }
 
-        let receiver_address = if proper_vout == std::usize::MAX {
-            error!("Transfer sent as Miner Fee. Balance sent back to sender.");
-            from.clone() // If sentinel value is present, use sender's address as receiver's address
-        } else {
-            get_owner_of_vout(&raw_tx_info, proper_vout)?.to_string()
-        };
+    let count = sends.len() as u32;
+    for (send, stored_transfer) in sends.into_iter().zip(stored_transfers) {
+        active_transfers.remove(&send.active_transfer.key());
+        info!("Transfer Send Found: {}", send.active_transfer);
+        metrics()
+            .operations
+            .with_label_values(&["send", "valid"])
+            .inc();
+        let receiver_vout = send.receiver_vout;
+        let receiver_address = send.receiver;
+        let Brc20ActiveTransfer {
+            inscription_id,
+            address: from,
+            tick,
+            amt: amount,
+            ..
+        } = send.active_transfer;
+
+        // Both entries point at the sending transaction and the output the inscription lands in
+        let mut source = EntrySource::new(
+            "send",
+            &raw_tx_info.txid.to_string(),
+            tx_height,
+            inscription_id.clone(),
+        );
+        source.vout = receiver_vout.map(|vout| vout as i64);
 
         // Update user overall balance and available for the from address(sender)
         let user_entry_from = mongo_client
@@ -558,6 +993,10 @@ pub async fn check_for_transfer_send(
                 &tick,
                 block_height,
                 UserBalanceEntryType::Send,
+                EntrySource {
+                    counterparty: Some(receiver_address.clone()),
+                    ..source.clone()
+                },
             )
             .await?;
 
@@ -571,23 +1010,42 @@ pub async fn check_for_transfer_send(
                 &tick,
                 block_height,
                 UserBalanceEntryType::Receive,
+                EntrySource {
+                    counterparty: Some(from.clone()),
+                    ..source
+                },
             )
             .await?;
 
         user_balance_entry_documents.push(user_entry_to.to_document());
 
         //-------------MONGODB-------------------//
-        // Pass the transfer document to the update_transfer_document function
-        update_transfer_document(
-            mongo_client,
-            transfer_doc,
-            &txid,
-            &receiver_address,
-            block_height.try_into().unwrap(),
-            tx_height,
-            &raw_tx_info,
-        )
-        .await?;
+        // The transfer records where it went, a transfer inscribed in this block is taken
+        // out of the ones still to be inserted
+        let transfer_doc = match find_transfer_document(transfer_documents, &inscription_id) {
+            Some(index) => Some(transfer_documents.remove(index)),
+            None => stored_transfer,
+        };
+        match transfer_doc {
+            Some(transfer_doc) => {
+                update_transfer_document(
+                    mongo_client,
+                    transfer_doc,
+                    &inscription_id,
+                    &receiver_address,
+                    block_height as i64,
+                    tx_height,
+                    raw_tx_info,
+                    block_time,
+                    config.storage_profile,
+                )
+                .await?
+            }
+            None => error!(
+                "Transfer inscription {} not found, its send is only recorded in the balances",
+                inscription_id
+            ),
+        }
 
         // Update user available and transferable balance for the sender in MongoDB
         update_sender_user_balance_document(
@@ -607,14 +1065,146 @@ pub async fn check_for_transfer_send(
         )
         .await?;
 
-        info!(
-            "Transfer inscription found for txid: {}, vout: {}",
-            txid, vout
-        );
         info!("Amount transferred: {}, to: {}", amount, receiver_address);
     }
 
-    Ok(())
+    Ok(count)
+}
+
+// A transfer inscription sent by a transaction, and where it lands
+#[derive(Debug, Clone)]
+pub struct TransferSend {
+    // The input spending the outpoint the inscription is at
+    pub input_index: usize,
+    pub active_transfer: Brc20ActiveTransfer,
+    // The output receiving the inscription, none when it's spent as fee
+    pub receiver_vout: Option<usize>,
+    pub receiver: String,
+}
+
+impl TransferSend {
+    // The changes to the (available, transferable, overall) balances of the sender and the
+    // receiver, the same the balance entries of the send replay to
+    pub fn deltas(&self) -> ((f64, f64, f64), (f64, f64, f64)) {
+        let amount = self.active_transfer.amt;
+        let mut sent = (0.0, 0.0, 0.0);
+        let mut received = (0.0, 0.0, 0.0);
+        UserBalanceEntryType::Send.apply(&mut sent, amount);
+        UserBalanceEntryType::Receive.apply(&mut received, amount);
+        (sent, received)
+    }
+}
+
+// The active transfers spent by a transaction, with the input spending each, in input order.
+// Spending an outpoint sends every active transfer at it.
+fn spent_active_transfers(
+    transaction: &Transaction,
+    active_transfers: &ActiveTransfers,
+) -> Vec<(usize, Brc20ActiveTransfer)> {
+    transaction
+        .input
+        .iter()
+        .enumerate()
+        .flat_map(|(input_index, input)| {
+            let outpoint = input.previous_output;
+            active_transfers_at(
+                active_transfers,
+                &outpoint.txid.to_string(),
+                outpoint.vout.into(),
+            )
+            .into_iter()
+            .map(move |key| (input_index, active_transfers[&key].clone()))
+        })
+        .collect()
+}
+
+/// Finds the transfer inscriptions a transaction sends and where each lands.
+///
+/// The indexer and the simulator both resolve sends with it and then apply them, nothing is
+/// changed here, so an error leaves the active transfers as they were.
+pub async fn resolve_sends(
+    rpc: &Client,
+    config: &Config,
+    raw_tx_info: &GetRawTransactionResult,
+    transaction: &Transaction,
+    active_transfers: &ActiveTransfers,
+) -> Result<Vec<TransferSend>, IndexerError> {
+    let mut sends = Vec::new();
+    for (input_index, active_transfer) in spent_active_transfers(transaction, active_transfers) {
+        let (receiver_vout, receiver) = get_transfer_receiver(
+            rpc,
+            config,
+            raw_tx_info,
+            transaction,
+            input_index,
+            &active_transfer.address,
+        )
+        .await?;
+        sends.push(TransferSend {
+            input_index,
+            active_transfer,
+            receiver_vout,
+            receiver,
+        });
+    }
+
+    Ok(sends)
+}
+
+/// Finds the output and the address receiving a transfer inscription spent by input `input_index`.
+///
+/// The inscription is on the first satoshi of the input, it lands in the output that
+/// satoshi is assigned to. If it's spent as fee, there is no output and it returns to `from`.
+pub async fn get_transfer_receiver(
+    rpc: &Client,
+    config: &Config,
+    raw_tx_info: &GetRawTransactionResult,
+    transaction: &Transaction,
+    input_index: usize,
+    from: &str,
+) -> Result<(Option<usize>, String), IndexerError> {
+    let proper_vout = if input_index > 0 {
+        // if not in first input, get values of all inputs only up to this input
+        let input_values = utils::transaction_inputs_to_values(
+            rpc,
+            &config.retry_policy,
+            &transaction.input[0..input_index],
+        )
+        .await?;
+
+        // then get the sum these input values
+        let input_value_sum: u64 = input_values.iter().sum();
+        let total_output_value: u64 = transaction.output.iter().map(|output| output.value).sum();
+
+        // If the sum of input values (up to the current input index) is greater than the total output value,
+        // assume that the sender is the receiver.
+        if input_value_sum >= total_output_value {
+            usize::MAX // use MAX as a sentinel value
+        } else {
+            // Calculate the index of the output (vout) which is the recipient of the
+            // inscribed satoshi by finding the first output whose value is greater than
+            // the sum of all preceding input values. This is based on the ordinal theory that satoshis are processed in order.
+            transaction
+                .output
+                .iter()
+                .scan(0, |acc, output| {
+                    *acc += output.value;
+                    Some(*acc)
+                })
+                .position(|value| value > input_value_sum)
+                .unwrap_or(transaction.output.len() - 1)
+        }
+    } else {
+        0
+    };
+
+    if proper_vout == usize::MAX {
+        error!("Transfer sent as Miner Fee. Balance sent back to sender.");
+        Ok((None, from.to_string())) // If sentinel value is present, use sender's address as receiver's address
+    } else {
+        let receiver = get_owner_of_vout(raw_tx_info, proper_vout, config.network)?;
+        Ok((Some(proper_vout), receiver.to_string()))
+    }
 }
 
 /// Inserts different types of documents to MongoDB in their respective collections.
@@ -622,6 +1212,8 @@ pub async fn check_for_transfer_send(
 /// # Arguments
 ///
 /// * `mongo_client` - A reference to the MongoDB client used to interact with the database.
+/// * `block_time` - The time of the block, stamped on every document.
+/// * `storage_profile` - How much of the transactions is kept in the documents.
 /// * `mint_documents` - A vector of mint documents to be inserted into MongoDB.
 /// * `transfer_documents` - A vector of transfer documents to be inserted into MongoDB.
 /// * `deploy_documents` - A vector of deploy documents to be inserted into MongoDB.
@@ -631,21 +1223,39 @@ pub async fn check_for_transfer_send(
 /// # Errors
 ///
 /// This function will return an error if the insertion of any type of documents fails.
+#[allow(clippy::too_many_arguments)]
 pub async fn insert_documents_to_mongo_after_each_block(
     mongo_client: &MongoClient,
-    mint_documents: Vec<Document>,
-    transfer_documents: Vec<Document>,
-    deploy_documents: Vec<Document>,
-    invalid_brc20_documents: Vec<Document>,
-    user_balance_entry_documents: Vec<Document>,
-) -> Result<(), Box<dyn std::error::Error>> {
+    block_time: &BlockTime,
+    storage_profile: StorageProfile,
+    mut mint_documents: Vec<Document>,
+    mut transfer_documents: Vec<Document>,
+    mut deploy_documents: Vec<Document>,
+    mut invalid_brc20_documents: Vec<Document>,
+    mut user_balance_entry_documents: Vec<Document>,
+) -> Result<(), IndexerError> {
+    for document in mint_documents
+        .iter_mut()
+        .chain(transfer_documents.iter_mut())
+        .chain(deploy_documents.iter_mut())
+        .chain(invalid_brc20_documents.iter_mut())
+        .chain(user_balance_entry_documents.iter_mut())
+    {
+        block_time.stamp(document);
+        storage_profile.shape(document);
+    }
+    // Balance entries have no key of their own, they are keyed by their order in the block
+    for (seq, document) in user_balance_entry_documents.iter_mut().enumerate() {
+        document.insert("seq", seq as i64);
+    }
+
     // If there are mint documents, insert them into the mints collection
     if !mint_documents.is_empty() {
         let start = Instant::now();
         mongo_client
             .insert_many_with_retries(consts::COLLECTION_MINTS, &mint_documents)
             .await?;
-        warn!(
+        debug!(
             "Mints inserted after block: {} in {:?}",
             mint_documents.len(),
             start.elapsed()
@@ -658,7 +1268,7 @@ pub async fn insert_documents_to_mongo_after_each_block(
         mongo_client
             .insert_many_with_retries(consts::COLLECTION_TRANSFERS, &transfer_documents)
             .await?;
-        warn!(
+        debug!(
             "Transfers inserted after block: {} in {:?}",
             transfer_documents.len(),
             start.elapsed()
@@ -671,7 +1281,7 @@ pub async fn insert_documents_to_mongo_after_each_block(
         mongo_client
             .insert_many_with_retries(consts::COLLECTION_DEPLOYS, &deploy_documents)
             .await?;
-        warn!(
+        debug!(
             "Deploys inserted after block: {} in {:?}",
             deploy_documents.len(),
             start.elapsed()
@@ -684,7 +1294,7 @@ pub async fn insert_documents_to_mongo_after_each_block(
         mongo_client
             .insert_many_with_retries(consts::COLLECTION_INVALIDS, &invalid_brc20_documents)
             .await?;
-        warn!(
+        debug!(
             "Invalids inserted after block: {} in {:?}",
             invalid_brc20_documents.len(),
             start.elapsed()
@@ -700,7 +1310,7 @@ pub async fn insert_documents_to_mongo_after_each_block(
                 &user_balance_entry_documents,
             )
             .await?;
-        warn!(
+        debug!(
             "User Balance Entries inserted after block: {} in {:?}",
             user_balance_entry_documents.len(),
             start.elapsed()
@@ -774,7 +1384,7 @@ impl ToDocument for GetRawTransactionResultVin {
     fn to_document(&self) -> Document {
         doc! {
             "sequence": self.sequence as i64,
-            "coinbase": self.coinbase.as_ref().map(|c| hex::encode(c)),
+            "coinbase": self.coinbase.as_ref().map(hex::encode),
             "txid": self.txid.map(|txid| txid.to_string()),
             "vout": self.vout.map(|vout| vout as i64),
             "script_sig": self.script_sig.as_ref().map(|script_sig| {
@@ -784,7 +1394,7 @@ impl ToDocument for GetRawTransactionResultVin {
                 }
             }),
             "txinwitness": self.txinwitness.as_ref().map(|witness| {
-                witness.iter().map(|w| hex::encode(w)).collect::<Vec<_>>()
+                witness.iter().map(hex::encode).collect::<Vec<_>>()
             }),
         }
     }
@@ -813,22 +1423,32 @@ impl ToDocument for GetRawTransactionResultVout {
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn update_transfer_document(
     mongo_client: &MongoClient,
     transfer_doc: Document,
-    tx_id: &str,
+    inscription_id: &str,
     receiver_address: &str,
     send_block_height: i64,
     send_tx_height: i64,
     send_tx: &GetRawTransactionResult,
-) -> Result<(), anyhow::Error> {
+    send_block_time: &BlockTime,
+    storage_profile: StorageProfile,
+) -> Result<(), IndexerError> {
     // Update the fields of the document
     let updated_doc = {
         let mut updated_doc = transfer_doc;
+        // A transfer inscribed in the same block wasn't written, nor stamped, yet
+        if !updated_doc.contains_key(block_time::KEY_BLOCK_TIME) {
+            send_block_time.stamp(&mut updated_doc);
+        }
+        updated_doc.insert("send_block_time", send_block_time.time);
         updated_doc.insert("to", receiver_address);
         updated_doc.insert("send_tx", send_tx.to_document());
         updated_doc.insert("send_block_height", send_block_height);
         updated_doc.insert("send_tx_height", send_tx_height);
+        // A transfer inscribed in the same block still has its whole transaction
+        storage_profile.shape(&mut updated_doc);
         updated_doc
     };
 
@@ -836,7 +1456,7 @@ pub async fn update_transfer_document(
     // We can save to MongoDB without worrying about needing to
     // delete in case of restart, they will just be overwritten by the new ones
     // and will not affect any balances that need to be recalculated
-    let filter = doc! { "tx.txid": tx_id };
+    let filter = doc! { "inscription_id": inscription_id };
     let update_doc = doc! { "$set": updated_doc };
     let options = UpdateOptions::builder().upsert(true).build();
     mongo_client
@@ -850,3 +1470,119 @@ pub async fn update_transfer_document(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::hashes::Hash;
+
+    fn hash(byte: u8) -> BlockHash {
+        BlockHash::from_byte_array([byte; 32])
+    }
+
+    #[test]
+    fn test_find_transfer_document() {
+        let transfer = |txid: &str, inscription_id: &str| {
+            doc! { "tx": { "txid": txid }, "inscription_id": inscription_id, "amt": 1.0 }
+        };
+        let transfer_documents = vec![
+            doc! { "amt": 1.0 },
+            transfer("aa", "aai0"),
+            transfer("bb", "bbi0"),
+            transfer("bb", "bbi1"),
+        ];
+
+        // Comparing the txid with itself matched the first document with one, so sending bb
+        // updated the transfer document of aa
+        assert_eq!(find_transfer_document(&transfer_documents, "bbi0"), Some(2));
+        assert_eq!(find_transfer_document(&transfer_documents, "aai0"), Some(1));
+        // The transfers of one transaction share its txid, each send updates its own
+        assert_eq!(find_transfer_document(&transfer_documents, "bbi1"), Some(3));
+        // Sent transfers inscribed in an earlier block are looked up in MongoDB instead
+        assert_eq!(find_transfer_document(&transfer_documents, "cci0"), None);
+    }
+
+    #[test]
+    fn test_spent_active_transfers() {
+        use bitcoin::absolute::LockTime;
+        use bitcoin::{OutPoint, ScriptBuf, Sequence, TxIn, Txid, Witness};
+
+        let txid = |byte: u8| Txid::from_byte_array([byte; 32]);
+        let input = |txid: Txid, vout: u32| TxIn {
+            previous_output: OutPoint::new(txid, vout),
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::MAX,
+            witness: Witness::new(),
+        };
+        let mut active_transfers = ActiveTransfers::new();
+        for (tx_id, inscription_id, amt) in [
+            (txid(1), format!("{}i0", txid(1)), 1.0),
+            (txid(1), format!("{}i1", txid(1)), 2.0),
+            (txid(2), format!("{}i0", txid(2)), 3.0),
+        ] {
+            let active_transfer = Brc20ActiveTransfer::new(
+                tx_id.to_string(),
+                0,
+                100,
+                inscription_id,
+                "bc1qsender".to_string(),
+                "ordi".to_string(),
+                amt,
+            );
+            active_transfers.insert(active_transfer.key(), active_transfer);
+        }
+        let transaction = Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![input(txid(3), 0), input(txid(1), 0), input(txid(2), 1)],
+            output: Vec::new(),
+        };
+
+        // Both inscriptions at the outpoint are sent by the input spending it, the one at
+        // another output of the transaction isn't
+        let spent = spent_active_transfers(&transaction, &active_transfers);
+        let spent: Vec<(usize, f64)> = spent
+            .iter()
+            .map(|(input_index, active_transfer)| (*input_index, active_transfer.amt))
+            .collect();
+        assert_eq!(spent, vec![(1, 1.0), (1, 2.0)]);
+
+        let send = TransferSend {
+            input_index: 1,
+            active_transfer: active_transfers.values().next().unwrap().clone(),
+            receiver_vout: Some(0),
+            receiver: "bc1qreceiver".to_string(),
+        };
+        assert_eq!(send.deltas(), ((0.0, -1.0, -1.0), (1.0, 0.0, 1.0)));
+    }
+
+    // Indexed blocks 100 to 105, the node replaced the ones from `fork` on
+    async fn walk(fork: i64, final_height: Option<i64>) -> Result<Option<i64>, IndexerError> {
+        walk_back(
+            105,
+            final_height,
+            |height| async move { Ok((100..=105).contains(&height).then(|| hash(height as u8))) },
+            |height| async move {
+                let byte = if height >= fork { 0xff } else { height as u8 };
+                Ok(Some(hash(byte)))
+            },
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_walk_back() {
+        assert_eq!(walk(106, Some(99)).await.unwrap(), None);
+        assert_eq!(walk(103, Some(102)).await.unwrap(), Some(103));
+        // Blocks without a recorded hash are assumed to be on the chain
+        assert_eq!(walk(90, None).await.unwrap(), Some(100));
+
+        // Block 102 is final, it's never rolled back automatically
+        match walk(101, Some(102)).await {
+            Err(IndexerError::Protocol(message)) => {
+                assert!(message.starts_with("final block 102 isn't on the node's chain"))
+            }
+            result => panic!("expected a protocol violation, got {:?}", result),
+        }
+    }
+}
@@ -0,0 +1,50 @@
+use mongodb::bson::{Bson, DateTime, Document};
+
+pub const KEY_BLOCK_TIME: &str = "block_time";
+pub const KEY_MEDIAN_TIME: &str = "median_time";
+
+// When a block happened: the time in its header and the median time past of the 11 blocks
+// up to it. Unlike the header time, the median time past never goes backwards.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BlockTime {
+    pub time: DateTime,
+    pub median_time: DateTime,
+}
+
+impl BlockTime {
+    // From seconds since the epoch
+    pub fn from_secs(time: u64, median_time: u64) -> Self {
+        BlockTime {
+            time: DateTime::from_millis(time as i64 * 1000),
+            median_time: DateTime::from_millis(median_time as i64 * 1000),
+        }
+    }
+
+    // Stamps a record with the time of the block it was indexed in
+    pub fn stamp(&self, document: &mut Document) {
+        document.insert(KEY_BLOCK_TIME, Bson::DateTime(self.time));
+        document.insert(KEY_MEDIAN_TIME, Bson::DateTime(self.median_time));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mongodb::bson::doc;
+
+    #[test]
+    fn test_stamp() {
+        let block_time = BlockTime::from_secs(1_683_158_987, 1_683_156_218);
+        let mut document = doc! { "block_height": 788_000i64 };
+        block_time.stamp(&mut document);
+
+        assert_eq!(
+            document.get_datetime(KEY_BLOCK_TIME).unwrap().to_string(),
+            "2023-05-04 0:09:47.0 +00:00:00"
+        );
+        assert_eq!(
+            document.get_datetime(KEY_MEDIAN_TIME).unwrap(),
+            &DateTime::from_millis(1_683_156_218_000)
+        );
+    }
+}
@@ -1,3 +1,5 @@
+use bitcoin::Network;
+
 pub const COLLECTION_TICKERS: &str = "brc20_tickers";
 pub const COLLECTION_DEPLOYS: &str = "brc20_deploys";
 pub const COLLECTION_MINTS: &str = "brc20_mints";
@@ -8,11 +10,44 @@ pub const COLLECTION_USER_BALANCE_ENTRY: &str = "brc20_user_balance_entry";
 pub const COLLECTION_BLOCKS_COMPLETED: &str = "blocks_completed";
 pub const COLLECTION_BRC20_ACTIVE_TRANSFERS: &str = "brc20_active_transfers";
 pub const COLLECTION_TOTAL_MINTED_AT_BLOCK_HEIGHT: &str = "total_minted_at_block_height";
-pub const MONGO_RETRIES: u32 = 10000000;
+// Pending effects of the transactions in the mempool, replaced on every sync
+pub const COLLECTION_MEMPOOL: &str = "brc20_mempool";
+
+pub const COLLECTION_INDEXER_METADATA: &str = "indexer_metadata";
 
+// Every collection holding indexed data
+pub const COLLECTIONS: [&str; 11] = [
+    COLLECTION_TICKERS,
+    COLLECTION_DEPLOYS,
+    COLLECTION_MINTS,
+    COLLECTION_TRANSFERS,
+    COLLECTION_INVALIDS,
+    COLLECTION_USER_BALANCES,
+    COLLECTION_USER_BALANCE_ENTRY,
+    COLLECTION_BLOCKS_COMPLETED,
+    COLLECTION_BRC20_ACTIVE_TRANSFERS,
+    COLLECTION_TOTAL_MINTED_AT_BLOCK_HEIGHT,
+    COLLECTION_MEMPOOL,
+];
+
+// First block with BRC20 operations on mainnet, nothing below it is indexed
 pub const BRC20_STARTING_BLOCK_HEIGHT: i64 = 779832;
+// There is no BRC20 deploy to anchor the other networks, they start at their first inscription
+pub const BRC20_TESTNET_STARTING_BLOCK_HEIGHT: i64 = 2413343;
+pub const BRC20_SIGNET_STARTING_BLOCK_HEIGHT: i64 = 112402;
+pub const BRC20_REGTEST_STARTING_BLOCK_HEIGHT: i64 = 0;
+
+pub fn brc20_starting_block_height(network: Network) -> i64 {
+    match network {
+        Network::Testnet => BRC20_TESTNET_STARTING_BLOCK_HEIGHT,
+        Network::Signet => BRC20_SIGNET_STARTING_BLOCK_HEIGHT,
+        Network::Regtest => BRC20_REGTEST_STARTING_BLOCK_HEIGHT,
+        _ => BRC20_STARTING_BLOCK_HEIGHT,
+    }
+}
 
 pub const KEY_BLOCK_HEIGHT: &str = "block_height";
+pub const KEY_FINAL: &str = "final";
 pub const OVERALL_BALANCE: &str = "overall_balance";
 pub const TRANSFERABLE_BALANCE: &str = "transferable_balance";
 pub const AVAILABLE_BALANCE: &str = "available_balance";
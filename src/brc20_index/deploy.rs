@@ -1,4 +1,5 @@
-use super::invalid_brc20::InvalidBrc20Tx;
+use super::error::IndexerError;
+use super::invalid_brc20::{InvalidBrc20Tx, InvalidReason};
 use super::mongo::MongoClient;
 use super::ToDocument;
 use super::{brc20_ticker::Brc20Ticker, utils::convert_to_float, Brc20Inscription};
@@ -6,7 +7,7 @@ use crate::brc20_index::consts;
 use bitcoin::Address;
 use bitcoincore_rpc::bitcoincore_rpc_json::GetRawTransactionResult;
 use log::{error, info};
-use mongodb::bson::{doc, Bson, DateTime, Document};
+use mongodb::bson::{doc, Document};
 use serde::Serialize;
 
 #[derive(Debug, Clone, Serialize)]
@@ -71,16 +72,12 @@ impl Brc20Deploy {
 
     pub async fn validate_deploy_script(
         mut self,
-        mongo_client: &MongoClient,
+        ticker_exists: bool,
         invalid_brc20_docs: &mut Vec<Document>,
-    ) -> Result<Self, Box<dyn std::error::Error>> {
-        let ticker_symbol = self.inscription.tick.to_lowercase();
+    ) -> Result<Self, IndexerError> {
         let mut reasons = vec![];
 
-        match self
-            .validate_ticker_symbol(&ticker_symbol, mongo_client)
-            .await
-        {
+        match self.validate_ticker_symbol(ticker_exists) {
             Ok(_) => {}
             Err(reason) => {
                 error!("INVALID: {}", reason);
@@ -119,11 +116,10 @@ impl Brc20Deploy {
         let valid_deploy_tx = self.set_valid(reasons.is_empty());
 
         if !valid_deploy_tx.is_valid() {
-            let reason = reasons.join("; ");
             let invalid_tx = InvalidBrc20Tx::new(
                 valid_deploy_tx.tx.txid,
                 valid_deploy_tx.inscription.clone(),
-                reason,
+                reasons,
                 valid_deploy_tx.block_height,
             );
 
@@ -133,37 +129,33 @@ impl Brc20Deploy {
         Ok(valid_deploy_tx)
     }
 
-    async fn validate_ticker_symbol(
-        &self,
-        ticker_symbol: &String,
-        mongo_client: &MongoClient,
-    ) -> Result<(), String> {
-        //check if ticker symbol already exists in MongoDB
-        let ticker_exists = mongo_client
-            .ticker_exists(consts::COLLECTION_TICKERS, doc! { "tick": ticker_symbol })
-            .await
-            .map_err(|e| e.to_string())?; // Convert mongodb::error::Error to String
+    fn validate_ticker_symbol(&self, ticker_exists: bool) -> Result<(), InvalidReason> {
+        let length = self.inscription.tick.chars().count();
 
         if ticker_exists {
-            Err("Ticker symbol already exists".to_string())
-        } else if self.inscription.tick.chars().count() != 4 {
-            Err("Ticker symbol must be 4 characters long".to_string())
+            Err(InvalidReason::TickerAlreadyExists)
+        } else if length != 4 {
+            Err(InvalidReason::InvalidTickerLength { length })
         } else {
             Ok(())
         }
     }
 
-    fn validate_decimals_field(&mut self) -> Result<(), String> {
+    fn validate_decimals_field(&mut self) -> Result<(), InvalidReason> {
         if let Some(decimals) = &self.inscription.dec {
             let parsed_decimals = match decimals.parse::<u8>() {
                 Ok(value) => value,
                 Err(_) => {
-                    return Err("Decimals field must be a valid unsigned integer".to_string());
+                    return Err(InvalidReason::InvalidDecimals {
+                        dec: decimals.clone(),
+                    });
                 }
             };
 
             if parsed_decimals > 18 {
-                return Err("Decimals must be 18 or less".to_string());
+                return Err(InvalidReason::DecimalsTooLarge {
+                    dec: parsed_decimals,
+                });
             }
 
             self.dec = parsed_decimals;
@@ -172,33 +164,41 @@ impl Brc20Deploy {
         Ok(())
     }
 
-    fn validate_max_field(&self) -> Result<f64, String> {
+    fn validate_max_field(&self) -> Result<f64, InvalidReason> {
         match &self.inscription.max {
             Some(max_str) => match convert_to_float(max_str, self.dec) {
                 Ok(max) => {
                     if max > 0.0 && decimal_places(max) <= self.dec.into() {
                         Ok(max)
                     } else {
-                        Err("Max supply must be greater than 0 and the number of decimal places must not exceed the decimal value.".to_string())
+                        Err(InvalidReason::MaxOutOfRange { max, dec: self.dec })
                     }
                 }
-                Err(_) => Err("Max field must be a valid number.".to_string()),
+                Err(_) => Err(InvalidReason::InvalidMax {
+                    max: max_str.clone(),
+                }),
             },
-            None => Err("Max field is missing.".to_string()),
+            None => Err(InvalidReason::MissingMax),
         }
     }
 
-    fn validate_limit_field(&self, max: f64) -> Result<f64, String> {
+    fn validate_limit_field(&self, max: f64) -> Result<f64, InvalidReason> {
         match &self.inscription.lim {
             Some(lim_str) => match convert_to_float(lim_str, self.dec) {
                 Ok(limit) => {
                     if limit <= max && decimal_places(limit) <= self.dec.into() {
                         Ok(limit)
                     } else {
-                        Err("Limit must be less than or equal to max supply and the number of decimal places must not exceed the decimal value.".to_string())
+                        Err(InvalidReason::LimitOutOfRange {
+                            lim: limit,
+                            max,
+                            dec: self.dec,
+                        })
                     }
                 }
-                Err(_) => Err("Limit field must be a valid number.".to_string()),
+                Err(_) => Err(InvalidReason::InvalidLimit {
+                    lim: lim_str.clone(),
+                }),
             },
             None => Ok(max),
         }
@@ -217,7 +217,6 @@ impl ToDocument for Brc20Deploy {
             "tx": &self.tx.to_document(),
             "inscription": &self.inscription.to_document(),
             "is_valid": &self.is_valid,
-            "created_at": Bson::DateTime(DateTime::now())
         }
     }
 }
@@ -230,10 +229,18 @@ pub async fn handle_deploy_operation(
     block_height: u32,
     tx_height: u32,
     invalid_brc20_docs: &mut Vec<Document>,
-) -> Result<Brc20Deploy, Box<dyn std::error::Error>> {
+) -> Result<Brc20Deploy, IndexerError> {
+    //check if ticker symbol already exists in MongoDB
+    let ticker_exists = mongo_client
+        .ticker_exists(
+            consts::COLLECTION_TICKERS,
+            doc! { "tick": inscription.tick.to_lowercase() },
+        )
+        .await?;
+
     // if invalid vaiidate_deploy_script handles and adds invalid to mongodb
     let validated_deploy_tx = Brc20Deploy::new(raw_tx, inscription, block_height, tx_height, owner)
-        .validate_deploy_script(&mongo_client, invalid_brc20_docs)
+        .validate_deploy_script(ticker_exists, invalid_brc20_docs)
         .await?;
 
     if validated_deploy_tx.is_valid() {
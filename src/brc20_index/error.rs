@@ -0,0 +1,210 @@
+use super::invalid_brc20::InvalidReason;
+use bitcoincore_rpc::jsonrpc;
+use mongodb::bson;
+use mongodb::error::{ErrorKind, WriteFailure};
+use std::fmt;
+
+// Bitcoin Core is still starting up and not answering RPC calls yet
+const RPC_IN_WARMUP: i32 = -28;
+
+// MongoDB server error codes that are expected to go away on their own, like elections
+// or shutdowns of a replica set member
+const TRANSIENT_MONGO_CODES: [i32; 13] = [
+    6, 7, 89, 91, 134, 189, 262, 9001, 10107, 11600, 11602, 13435, 13436,
+];
+
+// A write was rejected by a unique index, the document is already stored
+const MONGO_DUPLICATE_KEY: i32 = 11000;
+
+// IndexerError separates failures of the indexer itself from invalid inscriptions,
+// so the main loop can decide whether to retry, skip or abort.
+#[derive(Debug)]
+pub enum IndexerError {
+    Rpc(bitcoincore_rpc::Error),    // A Bitcoin Core RPC call failed
+    Storage(mongodb::error::Error), // A MongoDB read or write failed
+    Decode(String),                 // A transaction, script or stored document couldn't be decoded
+    Config(String),                 // Configuration is missing or malformed
+    Protocol(String),               // An operation or the indexed state violates the BRC20 rules
+}
+
+// ErrorAction is what the caller should do with the work that failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorAction {
+    Retry, // The failure is transient, try the same work again
+    Skip,  // The failure is specific to this item, continue with the next one
+    Abort, // The indexer can't make progress, stop
+}
+
+impl IndexerError {
+    pub fn action(&self) -> ErrorAction {
+        match self {
+            IndexerError::Rpc(_) | IndexerError::Storage(_) if self.is_transient() => {
+                ErrorAction::Retry
+            }
+            IndexerError::Decode(_) | IndexerError::Protocol(_) => ErrorAction::Skip,
+            IndexerError::Rpc(_) | IndexerError::Storage(_) | IndexerError::Config(_) => {
+                ErrorAction::Abort
+            }
+        }
+    }
+
+    // Whether the same call may succeed when it's made again, like after a dropped
+    // connection. Errors caused by the request itself are never transient.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            IndexerError::Rpc(e) => match e {
+                bitcoincore_rpc::Error::Io(_) => true,
+                bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Transport(_)) => true,
+                bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(e)) => e.code == RPC_IN_WARMUP,
+                _ => false,
+            },
+            IndexerError::Storage(e) => {
+                if e.contains_label("RetryableWriteError")
+                    || e.contains_label("TransientTransactionError")
+                {
+                    return true;
+                }
+                match e.kind.as_ref() {
+                    ErrorKind::Io(_)
+                    | ErrorKind::ConnectionPoolCleared { .. }
+                    | ErrorKind::ServerSelection { .. }
+                    | ErrorKind::DnsResolve { .. } => true,
+                    ErrorKind::Command(e) => TRANSIENT_MONGO_CODES.contains(&e.code),
+                    _ => false,
+                }
+            }
+            IndexerError::Decode(_) | IndexerError::Config(_) | IndexerError::Protocol(_) => false,
+        }
+    }
+
+    // Whether every write that failed hit a unique key that is already stored, like the
+    // records of a block that is indexed again after a crash. Replaying them is a no-op.
+    pub fn is_duplicate_key(&self) -> bool {
+        let e = match self {
+            IndexerError::Storage(e) => e,
+            _ => return false,
+        };
+        match e.kind.as_ref() {
+            ErrorKind::BulkWrite(failure) => {
+                failure.write_concern_error.is_none()
+                    && failure
+                        .write_errors
+                        .as_ref()
+                        .is_some_and(|errors| errors.iter().all(|e| e.code == MONGO_DUPLICATE_KEY))
+            }
+            ErrorKind::Write(WriteFailure::WriteError(e)) => e.code == MONGO_DUPLICATE_KEY,
+            ErrorKind::Command(e) => e.code == MONGO_DUPLICATE_KEY,
+            _ => false,
+        }
+    }
+
+    // The server error code of a failed command, like creating an index
+    pub fn command_code(&self) -> Option<i32> {
+        match self {
+            IndexerError::Storage(e) => match e.kind.as_ref() {
+                ErrorKind::Command(e) => Some(e.code),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for IndexerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IndexerError::Rpc(e) => write!(f, "rpc error: {}", e),
+            IndexerError::Storage(e) => write!(f, "storage error: {}", e),
+            IndexerError::Decode(e) => write!(f, "decode error: {}", e),
+            IndexerError::Config(e) => write!(f, "config error: {}", e),
+            IndexerError::Protocol(e) => write!(f, "protocol violation: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for IndexerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            IndexerError::Rpc(e) => Some(e),
+            IndexerError::Storage(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<bitcoincore_rpc::Error> for IndexerError {
+    fn from(e: bitcoincore_rpc::Error) -> Self {
+        IndexerError::Rpc(e)
+    }
+}
+
+impl From<mongodb::error::Error> for IndexerError {
+    fn from(e: mongodb::error::Error) -> Self {
+        IndexerError::Storage(e)
+    }
+}
+
+impl From<bson::document::ValueAccessError> for IndexerError {
+    fn from(e: bson::document::ValueAccessError) -> Self {
+        IndexerError::Decode(e.to_string())
+    }
+}
+
+impl From<bson::ser::Error> for IndexerError {
+    fn from(e: bson::ser::Error) -> Self {
+        IndexerError::Decode(e.to_string())
+    }
+}
+
+impl From<bitcoin::consensus::encode::Error> for IndexerError {
+    fn from(e: bitcoin::consensus::encode::Error) -> Self {
+        IndexerError::Decode(e.to_string())
+    }
+}
+
+impl From<InvalidReason> for IndexerError {
+    fn from(reason: InvalidReason) -> Self {
+        IndexerError::Protocol(reason.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_actions() {
+        let io_error = std::io::Error::new(std::io::ErrorKind::TimedOut, "timeout");
+        assert_eq!(
+            IndexerError::from(mongodb::error::Error::from(io_error)).action(),
+            ErrorAction::Retry
+        );
+        let rpc_error = bitcoincore_rpc::Error::ReturnedError("bad request".to_string());
+        assert_eq!(IndexerError::from(rpc_error).action(), ErrorAction::Abort);
+        assert_eq!(
+            IndexerError::from(InvalidReason::TickerNotFound).action(),
+            ErrorAction::Skip
+        );
+        assert_eq!(
+            IndexerError::Config("RPC_URL is not set".to_string()).action(),
+            ErrorAction::Abort
+        );
+    }
+
+    #[test]
+    fn test_is_duplicate_key() {
+        let bulk_write_error = |codes: &[i32]| {
+            let write_errors: Vec<bson::Document> = codes
+                .iter()
+                .enumerate()
+                .map(|(index, code)| bson::doc! { "index": index as i64, "code": code })
+                .collect();
+            let failure = bson::from_document(bson::doc! { "writeErrors": write_errors }).unwrap();
+            IndexerError::from(mongodb::error::Error::from(ErrorKind::BulkWrite(failure)))
+        };
+
+        assert!(bulk_write_error(&[11000, 11000]).is_duplicate_key());
+        assert!(!bulk_write_error(&[11000, 121]).is_duplicate_key());
+        assert!(!IndexerError::Decode("E11000".to_string()).is_duplicate_key());
+    }
+}
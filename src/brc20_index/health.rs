@@ -0,0 +1,135 @@
+use super::error::IndexerError;
+use super::metrics::metrics;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+static HEALTH: OnceLock<Health> = OnceLock::new();
+
+// Health is the state the liveness and readiness checks are answered from,
+// it's updated by the main loop
+pub struct Health {
+    // Unix time of the last iteration of the main loop
+    last_progress: AtomicU64,
+    node_reachable: AtomicBool,
+    storage_reachable: AtomicBool,
+}
+
+pub fn health() -> &'static Health {
+    HEALTH.get_or_init(|| Health {
+        last_progress: AtomicU64::new(now()),
+        node_reachable: AtomicBool::new(false),
+        storage_reachable: AtomicBool::new(false),
+    })
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+// Thresholds of the checks, from the configuration
+#[derive(Debug, Clone, Copy)]
+pub struct HealthThresholds {
+    // The main loop must have made progress within this time to be alive
+    pub liveness_timeout: Duration,
+    // Ready only within this many blocks of the node's tip
+    pub readiness_max_lag_blocks: i64,
+}
+
+// The outcome of a check and why
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Check {
+    pub ok: bool,
+    pub problems: Vec<String>,
+}
+
+impl Health {
+    pub fn progress(&self) {
+        self.last_progress.store(now(), Ordering::Relaxed);
+    }
+
+    pub fn node_reachable(&self, reachable: bool) {
+        self.node_reachable.store(reachable, Ordering::Relaxed);
+    }
+
+    pub fn storage_reachable(&self, reachable: bool) {
+        self.storage_reachable.store(reachable, Ordering::Relaxed);
+    }
+
+    // Marks the dependency an error came from as unreachable
+    pub fn record_error(&self, error: &IndexerError) {
+        match error {
+            IndexerError::Rpc(_) => self.node_reachable(false),
+            IndexerError::Storage(_) => self.storage_reachable(false),
+            _ => {}
+        }
+    }
+
+    pub fn liveness(&self, thresholds: &HealthThresholds) -> Check {
+        let mut problems = Vec::new();
+
+        let stalled_secs = now().saturating_sub(self.last_progress.load(Ordering::Relaxed));
+        if stalled_secs > thresholds.liveness_timeout.as_secs() {
+            problems.push(format!("no progress for {}s", stalled_secs));
+        }
+
+        Check {
+            ok: problems.is_empty(),
+            problems,
+        }
+    }
+
+    pub fn readiness(&self, thresholds: &HealthThresholds) -> Check {
+        let mut problems = self.liveness(thresholds).problems;
+
+        if !self.node_reachable.load(Ordering::Relaxed) {
+            problems.push("node unreachable".to_string());
+        }
+        if !self.storage_reachable.load(Ordering::Relaxed) {
+            problems.push("database unreachable".to_string());
+        }
+        let lag = metrics().lag_blocks.get();
+        if lag > thresholds.readiness_max_lag_blocks {
+            problems.push(format!("{} blocks behind the node", lag));
+        }
+
+        Check {
+            ok: problems.is_empty(),
+            problems,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checks() {
+        let thresholds = HealthThresholds {
+            liveness_timeout: Duration::from_secs(60),
+            readiness_max_lag_blocks: 2,
+        };
+        let health = health();
+
+        health.progress();
+        assert!(health.liveness(&thresholds).ok);
+        health.last_progress.store(now() - 120, Ordering::Relaxed);
+        assert_eq!(
+            health.liveness(&thresholds).problems,
+            vec!["no progress for 120s".to_string()]
+        );
+
+        health.progress();
+        health.node_reachable(true);
+        health.storage_reachable(false);
+        let readiness = health.readiness(&thresholds);
+        assert!(!readiness.ok);
+        assert!(readiness
+            .problems
+            .contains(&"database unreachable".to_string()));
+    }
+}
@@ -0,0 +1,412 @@
+use super::{
+    consts,
+    deploy::Brc20Deploy,
+    error::{ErrorAction, IndexerError},
+    mint::Brc20Mint,
+    mongo::MongoClient,
+    transfer::Brc20Transfer,
+    Brc20Inscription,
+};
+use bitcoin::blockdata::opcodes::{all::OP_IF, all::OP_PUSHNUM_1, OP_FALSE};
+use bitcoin::blockdata::script::{Instruction, Script};
+use bitcoin::{Address, Transaction};
+use bitcoincore_rpc::bitcoincore_rpc_json::GetRawTransactionResult;
+use futures_util::StreamExt;
+use mongodb::bson::{doc, Bson, Document};
+
+// The ordinals envelope of an inscription: OP_FALSE OP_IF "ord" <fields> OP_0 <body> OP_ENDIF
+#[derive(Debug, Clone, PartialEq)]
+pub struct Envelope {
+    pub input: usize,
+    pub content_type: Option<String>,
+    pub body: Vec<u8>,
+}
+
+// Finds the inscription envelopes in the tapscripts of every input
+pub fn parse_envelopes(transaction: &Transaction) -> Vec<Envelope> {
+    let mut envelopes = Vec::new();
+
+    for (input_index, input) in transaction.input.iter().enumerate() {
+        for element in input.witness.iter() {
+            let mut instructions = Script::from_bytes(element)
+                .instructions()
+                .filter_map(Result::ok)
+                .peekable();
+
+            while let Some(instruction) = instructions.next() {
+                // OP_FALSE is an empty push
+                let is_false = match instruction {
+                    Instruction::PushBytes(bytes) => bytes.is_empty(),
+                    Instruction::Op(op) => op == OP_FALSE,
+                };
+                if !is_false {
+                    continue;
+                }
+                if instructions.peek() != Some(&Instruction::Op(OP_IF)) {
+                    continue;
+                }
+                instructions.next();
+                match instructions.peek() {
+                    Some(Instruction::PushBytes(tag)) if tag.as_bytes() == b"ord" => {
+                        instructions.next();
+                    }
+                    _ => continue,
+                }
+
+                let mut envelope = Envelope {
+                    input: input_index,
+                    content_type: None,
+                    body: Vec::new(),
+                };
+                let mut in_body = false;
+                for instruction in instructions.by_ref() {
+                    match instruction {
+                        Instruction::PushBytes(bytes) if in_body => {
+                            envelope.body.extend_from_slice(bytes.as_bytes())
+                        }
+                        // The content type tag is 1, pushed as a byte or as OP_PUSHNUM_1
+                        Instruction::PushBytes(bytes) if bytes.as_bytes() == [1] => {}
+                        Instruction::Op(OP_PUSHNUM_1) => {}
+                        Instruction::PushBytes(bytes) if bytes.is_empty() => in_body = true,
+                        Instruction::PushBytes(bytes) if envelope.content_type.is_none() => {
+                            envelope.content_type =
+                                Some(String::from_utf8_lossy(bytes.as_bytes()).into_owned())
+                        }
+                        Instruction::PushBytes(_) => {}
+                        // OP_ENDIF, or any other opcode, closes the envelope
+                        Instruction::Op(_) => break,
+                    }
+                }
+                envelopes.push(envelope);
+            }
+        }
+    }
+
+    envelopes
+}
+
+// Outcome of validating an inscription against the indexed state
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verdict {
+    Valid(String),
+    Invalid(Vec<String>),
+    Skipped(String),
+}
+
+// The state an inscription was checked against and the result, in order
+#[derive(Debug, Clone)]
+pub struct Trace {
+    pub steps: Vec<String>,
+    pub verdict: Verdict,
+}
+
+impl Trace {
+    fn new() -> Self {
+        Trace {
+            steps: Vec::new(),
+            verdict: Verdict::Skipped(String::new()),
+        }
+    }
+
+    fn step(&mut self, step: String) {
+        self.steps.push(step);
+    }
+}
+
+fn get_height(document: &Document, field: &str) -> Option<i64> {
+    match document.get(field) {
+        Some(Bson::Int32(height)) => Some(i64::from(*height)),
+        Some(Bson::Int64(height)) => Some(*height),
+        _ => None,
+    }
+}
+
+// The reasons the validators recorded for an invalid operation
+fn invalid_reasons(invalid_brc20_docs: &[Document]) -> Vec<String> {
+    invalid_brc20_docs
+        .iter()
+        .filter_map(|invalid| invalid.get_str("reason").ok())
+        .map(String::from)
+        .collect()
+}
+
+// Loads a ticker as it was at the start of block as_of, or as it is now
+async fn load_ticker(
+    mongo_client: &MongoClient,
+    tick: &str,
+    as_of: Option<i64>,
+) -> Result<Option<Document>, IndexerError> {
+    let ticker = mongo_client
+        .get_document_by_field(consts::COLLECTION_TICKERS, "tick", tick)
+        .await?;
+
+    let (mut ticker, as_of) = match (ticker, as_of) {
+        (Some(ticker), Some(as_of)) => (ticker, as_of),
+        (ticker, _) => return Ok(ticker),
+    };
+
+    // Not deployed yet
+    if get_height(&ticker, consts::KEY_BLOCK_HEIGHT).is_some_and(|height| height >= as_of) {
+        return Ok(None);
+    }
+
+    let filter = doc! {
+        "inscription.tick": tick,
+        "is_valid": true,
+        "block_height": { "$lt": as_of },
+    };
+    let mut cursor = mongo_client
+        .find_with_retries(consts::COLLECTION_MINTS, Some(filter), None)
+        .await?;
+    let mut total_minted = 0.0;
+    while let Some(result) = cursor.next().await {
+        total_minted += result?.get_f64("amt").unwrap_or_default();
+    }
+    ticker.insert("total_minted", total_minted);
+
+    Ok(Some(ticker))
+}
+
+// Loads a user balance as it was at the start of block as_of, or as it is now
+async fn load_user_balance(
+    mongo_client: &MongoClient,
+    address: &str,
+    tick: &str,
+    as_of: Option<i64>,
+) -> Result<Option<Document>, IndexerError> {
+    let as_of = match as_of {
+        Some(as_of) => as_of,
+        None => {
+            return mongo_client
+                .load_user_balance_with_retry(&(address.to_string(), tick.to_string()))
+                .await
+        }
+    };
+
+    let balance = mongo_client
+        .replay_user_balance(address, tick, as_of)
+        .await?
+        .map(|(available, transferable, overall)| {
+            doc! {
+                "address": address,
+                "tick": tick,
+                consts::AVAILABLE_BALANCE: available,
+                consts::TRANSFERABLE_BALANCE: transferable,
+                consts::OVERALL_BALANCE: overall,
+            }
+        });
+
+    Ok(balance)
+}
+
+/// Validates an inscription the way the indexer would, without writing anything.
+///
+/// The state is the one at the start of block `as_of`, or the current one if it's None.
+/// Transactions earlier in the same block aren't part of the state.
+pub async fn trace_inscription(
+    mongo_client: &MongoClient,
+    raw_tx: &GetRawTransactionResult,
+    inscription: Brc20Inscription,
+    inscription_id: String,
+    owner: Address,
+    as_of: Option<i64>,
+) -> Result<Trace, IndexerError> {
+    let mut trace = Trace::new();
+    let tick = inscription.tick.to_lowercase();
+    let block_height = as_of
+        .and_then(|height| u32::try_from(height).ok())
+        .unwrap_or_default();
+    let mut invalid_brc20_docs = Vec::new();
+
+    trace.step(match as_of {
+        Some(height) => format!("state: at the start of block {}", height),
+        None => "state: current".to_string(),
+    });
+    trace.step(format!("op: {}, tick: {}", inscription.op, tick));
+
+    let ticker = load_ticker(mongo_client, &tick, as_of).await?;
+    trace.step(match &ticker {
+        Some(ticker) => format!(
+            "ticker: deployed at block {}, max {}, limit {}, decimals {}, total minted {}",
+            get_height(ticker, consts::KEY_BLOCK_HEIGHT).unwrap_or_default(),
+            ticker.get("max_supply").unwrap_or(&Bson::Null),
+            ticker.get("limit").unwrap_or(&Bson::Null),
+            ticker.get("decimals").unwrap_or(&Bson::Null),
+            ticker.get("total_minted").unwrap_or(&Bson::Null),
+        ),
+        None => "ticker: not deployed".to_string(),
+    });
+
+    match &inscription.op[..] {
+        "deploy" => {
+            let deploy = Brc20Deploy::new(raw_tx, inscription, block_height, 0, owner)
+                .validate_deploy_script(ticker.is_some(), &mut invalid_brc20_docs)
+                .await?;
+            trace.verdict = if deploy.is_valid() {
+                Verdict::Valid(format!(
+                    "deploys {} with max {}, limit {} and {} decimals",
+                    tick,
+                    deploy.get_max_supply(),
+                    deploy.get_limit(),
+                    deploy.get_decimals()
+                ))
+            } else {
+                Verdict::Invalid(invalid_reasons(&invalid_brc20_docs))
+            };
+        }
+        "mint" => {
+            let mint = Brc20Mint::new(raw_tx, inscription, inscription_id, block_height, 0, owner)
+                .validate_mint(ticker.as_ref(), &mut invalid_brc20_docs)
+                .await?;
+            trace.verdict = if mint.is_valid() {
+                Verdict::Valid(format!("mints {} {} to {}", mint.amt, tick, mint.to))
+            } else {
+                Verdict::Invalid(invalid_reasons(&invalid_brc20_docs))
+            };
+        }
+        "transfer" => {
+            let mut balance = match ticker {
+                Some(_) => {
+                    load_user_balance(mongo_client, &owner.to_string(), &tick, as_of).await?
+                }
+                None => None,
+            };
+            trace.step(match &balance {
+                Some(balance) => format!(
+                    "balance of {}: available {}, transferable {}",
+                    owner,
+                    balance
+                        .get_f64(consts::AVAILABLE_BALANCE)
+                        .unwrap_or_default(),
+                    balance
+                        .get_f64(consts::TRANSFERABLE_BALANCE)
+                        .unwrap_or_default()
+                ),
+                None => format!("balance of {}: none", owner),
+            });
+
+            let mut transfer =
+                Brc20Transfer::new(raw_tx, inscription, inscription_id, block_height, 0, owner);
+            let result = transfer
+                .validate_inscribe_transfer(
+                    ticker.as_ref(),
+                    balance.as_mut(),
+                    &mut None,
+                    &mut invalid_brc20_docs,
+                )
+                .await;
+            trace.verdict = match result {
+                Ok(_) if transfer.is_valid() => Verdict::Valid(format!(
+                    "makes {} {} of {} transferable",
+                    transfer.amt, tick, transfer.from
+                )),
+                Ok(_) => Verdict::Invalid(invalid_reasons(&invalid_brc20_docs)),
+                Err(e) if e.action() == ErrorAction::Skip => {
+                    Verdict::Invalid(invalid_reasons(&invalid_brc20_docs))
+                }
+                Err(e) => return Err(e),
+            };
+        }
+        op => trace.verdict = Verdict::Skipped(format!("unknown op {}", op)),
+    }
+
+    Ok(trace)
+}
+
+// Describes what the indexer has stored for a transaction, if anything
+pub async fn find_indexed(
+    mongo_client: &MongoClient,
+    txid: &str,
+) -> Result<Vec<String>, IndexerError> {
+    let mut found = Vec::new();
+
+    for (collection, what) in [
+        (consts::COLLECTION_DEPLOYS, "deploy"),
+        (consts::COLLECTION_MINTS, "mint"),
+        (consts::COLLECTION_TRANSFERS, "transfer inscription"),
+    ] {
+        let filter = doc! { "tx.txid": txid };
+        if let Some(document) = mongo_client
+            .get_document_by_filter(collection, filter)
+            .await?
+        {
+            found.push(format!(
+                "indexed as a valid {} at block {}",
+                what,
+                get_height(&document, consts::KEY_BLOCK_HEIGHT).unwrap_or_default()
+            ));
+        }
+    }
+
+    let filter = doc! { "send_tx.txid": txid };
+    if let Some(document) = mongo_client
+        .get_document_by_filter(consts::COLLECTION_TRANSFERS, filter)
+        .await?
+    {
+        found.push(format!(
+            "indexed as the send of transfer inscription {} at block {}",
+            document.get_document("tx")?.get_str("txid")?,
+            get_height(&document, "send_block_height").unwrap_or_default()
+        ));
+    }
+
+    let filter = doc! { "tx_id": txid };
+    if let Some(document) = mongo_client
+        .get_document_by_filter(consts::COLLECTION_INVALIDS, filter)
+        .await?
+    {
+        found.push(format!(
+            "indexed as invalid at block {}: {}",
+            get_height(&document, consts::KEY_BLOCK_HEIGHT).unwrap_or_default(),
+            document.get_str("reason").unwrap_or_default()
+        ));
+    }
+
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::blockdata::opcodes::all::{OP_CHECKSIG, OP_ENDIF};
+    use bitcoin::blockdata::script::Builder;
+    use bitcoin::{OutPoint, Sequence, TxIn, Witness};
+
+    #[test]
+    fn test_parse_envelopes() {
+        let script = Builder::new()
+            .push_slice([0u8; 32])
+            .push_opcode(OP_CHECKSIG)
+            .push_opcode(OP_FALSE)
+            .push_opcode(OP_IF)
+            .push_slice(b"ord")
+            .push_slice([1])
+            .push_slice(b"text/plain;charset=utf-8")
+            .push_opcode(OP_FALSE)
+            .push_slice(br#"{"p":"brc-20","op":"mint","#)
+            .push_slice(br#""tick":"ordi","amt":"1000"}"#)
+            .push_opcode(OP_ENDIF)
+            .into_script();
+
+        let transaction = Transaction {
+            version: 2,
+            lock_time: bitcoin::absolute::LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::default(),
+                script_sig: Default::default(),
+                sequence: Sequence::MAX,
+                witness: Witness::from_slice(&[vec![0u8; 64], script.into_bytes()]),
+            }],
+            output: vec![],
+        };
+
+        assert_eq!(
+            parse_envelopes(&transaction),
+            vec![Envelope {
+                input: 0,
+                content_type: Some("text/plain;charset=utf-8".to_string()),
+                body: br#"{"p":"brc-20","op":"mint","tick":"ordi","amt":"1000"}"#.to_vec(),
+            }]
+        );
+    }
+}
@@ -1,15 +1,105 @@
 use super::{Brc20Inscription, ToDocument};
 use bitcoin::Txid;
-use mongodb::bson::{doc, Bson, DateTime, Document};
+use mongodb::bson::{self, doc, Document};
 use serde::Serialize;
+use std::fmt;
+
+// InvalidReason is the machine-readable reason why a BRC20 operation is invalid.
+// The code of each variant is stored with the invalid transaction and must never change,
+// the parameters carry the values the operation was checked against.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "code", content = "params", rename_all = "snake_case")]
+pub enum InvalidReason {
+    TickerNotFound,
+    TickerAlreadyExists,
+    InvalidTickerLength { length: usize },
+    InvalidDecimals { dec: String },
+    DecimalsTooLarge { dec: u8 },
+    MissingMax,
+    InvalidMax { max: String },
+    MaxOutOfRange { max: f64, dec: u8 },
+    InvalidLimit { lim: String },
+    LimitOutOfRange { lim: f64, max: f64, dec: u8 },
+    MalformedNumber { value: String },
+    TooManyDecimals { value: String, dec: u8 },
+    MintExceedsLimit { requested: f64, limit: f64 },
+    MaxSupplyReached { total_minted: f64, max_supply: f64 },
+    UserBalanceNotFound,
+    InsufficientBalance { requested: f64, available: f64 },
+}
+
+impl InvalidReason {
+    pub fn code(&self) -> &'static str {
+        match self {
+            InvalidReason::TickerNotFound => "ticker_not_found",
+            InvalidReason::TickerAlreadyExists => "ticker_already_exists",
+            InvalidReason::InvalidTickerLength { .. } => "invalid_ticker_length",
+            InvalidReason::InvalidDecimals { .. } => "invalid_decimals",
+            InvalidReason::DecimalsTooLarge { .. } => "decimals_too_large",
+            InvalidReason::MissingMax => "missing_max",
+            InvalidReason::InvalidMax { .. } => "invalid_max",
+            InvalidReason::MaxOutOfRange { .. } => "max_out_of_range",
+            InvalidReason::InvalidLimit { .. } => "invalid_limit",
+            InvalidReason::LimitOutOfRange { .. } => "limit_out_of_range",
+            InvalidReason::MalformedNumber { .. } => "malformed_number",
+            InvalidReason::TooManyDecimals { .. } => "too_many_decimals",
+            InvalidReason::MintExceedsLimit { .. } => "mint_exceeds_limit",
+            InvalidReason::MaxSupplyReached { .. } => "max_supply_reached",
+            InvalidReason::UserBalanceNotFound => "user_balance_not_found",
+            InvalidReason::InsufficientBalance { .. } => "insufficient_balance",
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            InvalidReason::TickerNotFound => "Ticker symbol does not exist",
+            InvalidReason::TickerAlreadyExists => "Ticker symbol already exists",
+            InvalidReason::InvalidTickerLength { .. } => "Ticker symbol must be 4 characters long",
+            InvalidReason::InvalidDecimals { .. } => {
+                "Decimals field must be a valid unsigned integer"
+            }
+            InvalidReason::DecimalsTooLarge { .. } => "Decimals must be 18 or less",
+            InvalidReason::MissingMax => "Max field is missing.",
+            InvalidReason::InvalidMax { .. } => "Max field must be a valid number.",
+            InvalidReason::MaxOutOfRange { .. } => "Max supply must be greater than 0 and the number of decimal places must not exceed the decimal value.",
+            InvalidReason::InvalidLimit { .. } => "Limit field must be a valid number.",
+            InvalidReason::LimitOutOfRange { .. } => "Limit must be less than or equal to max supply and the number of decimal places must not exceed the decimal value.",
+            InvalidReason::MalformedNumber { .. } => "Malformed inscription",
+            InvalidReason::TooManyDecimals { .. } => {
+                "There are too many digits to the right of the decimal"
+            }
+            InvalidReason::MintExceedsLimit { .. } => "Mint amount exceeds limit",
+            InvalidReason::MaxSupplyReached { .. } => "Total minted is already at max supply",
+            InvalidReason::UserBalanceNotFound => "User balance not found",
+            InvalidReason::InsufficientBalance { .. } => "Transfer amount exceeds available balance",
+        }
+    }
+}
+
+impl fmt::Display for InvalidReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message())
+    }
+}
+
+impl std::error::Error for InvalidReason {}
+
+impl ToDocument for InvalidReason {
+    fn to_document(&self) -> Document {
+        // Serializes to { code, params }, params are omitted for reasons without any
+        let mut document = bson::to_document(self).unwrap_or_default();
+        document.insert("message", self.message());
+        document
+    }
+}
 
 // InvalidBrc20Tx represents an invalid BRC20 transaction,
-// storing the id of the transaction, the faulty inscription and the reason why it's invalid.
+// storing the id of the transaction, the faulty inscription and the reasons why it's invalid.
 #[derive(Debug, Clone, Serialize)]
 pub struct InvalidBrc20Tx {
     tx_id: Txid,                   // The unique identifier of the invalid transaction
     inscription: Brc20Inscription, // The faulty inscription of the transaction
-    reason: String,                // The reason why the transaction is invalid
+    reasons: Vec<InvalidReason>,   // The reasons why the transaction is invalid
     block_height: u32,             // The block height of the transaction
 }
 
@@ -17,13 +107,13 @@ impl InvalidBrc20Tx {
     pub fn new(
         tx_id: Txid,
         inscription: Brc20Inscription,
-        reason: String,
+        reasons: Vec<InvalidReason>,
         block_height: u32,
     ) -> Self {
         InvalidBrc20Tx {
             tx_id,
             inscription,
-            reason,
+            reasons,
             block_height,
         }
     }
@@ -31,12 +121,47 @@ impl InvalidBrc20Tx {
 
 impl ToDocument for InvalidBrc20Tx {
     fn to_document(&self) -> Document {
+        let messages: Vec<&str> = self.reasons.iter().map(|r| r.message()).collect();
+
         doc! {
             "tx_id": self.tx_id.to_string(),
             "inscription": self.inscription.to_document(),
-            "reason": self.reason.clone(),
+            "code": self.reasons.first().map(|r| r.code()),
+            "reason": messages.join("; "),
+            "reasons": self.reasons.iter().map(|r| r.to_document()).collect::<Vec<Document>>(),
             "block_height": self.block_height,
-            "created_at": Bson::DateTime(DateTime::now())
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_invalid_reason_document_with_params() {
+        let reason = InvalidReason::MintExceedsLimit {
+            requested: 2000.0,
+            limit: 1000.0,
+        };
+        assert_eq!(
+            reason.to_document(),
+            doc! {
+                "code": "mint_exceeds_limit",
+                "params": { "requested": 2000.0, "limit": 1000.0 },
+                "message": "Mint amount exceeds limit",
+            }
+        );
+    }
+
+    #[test]
+    fn test_invalid_reason_document_without_params() {
+        assert_eq!(
+            InvalidReason::TickerNotFound.to_document(),
+            doc! {
+                "code": "ticker_not_found",
+                "message": "Ticker symbol does not exist",
+            }
+        );
+    }
+}
@@ -0,0 +1,250 @@
+use super::{
+    error::{ErrorAction, IndexerError},
+    mongo::MongoClient,
+    retry::RetryClass,
+    simulate::Simulator,
+    utils::{extract_and_process_witness_data, get_witness_data_from_raw_tx},
+    ToDocument,
+};
+use crate::config::Config;
+use bitcoin::{OutPoint, Txid};
+use bitcoincore_rpc::bitcoincore_rpc_json::GetRawTransactionResult;
+use bitcoincore_rpc::{Client, RpcApi};
+use log::{debug, error, info};
+use mongodb::bson::{Bson, DateTime};
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+// A transaction seen in the mempool. Only the ones that can have BRC20 effects are kept whole.
+struct SeenTx {
+    time: u64,
+    spent: Vec<OutPoint>,
+    raw_tx: Option<GetRawTransactionResult>,
+}
+
+/// MempoolWatcher keeps the pending BRC20 effects of unconfirmed transactions up to date.
+///
+/// On every sync the transactions in the mempool are simulated in the order they arrived,
+/// parents before their children, on top of the indexed state, and their effects replace the stored ones. Transactions that
+/// were confirmed, replaced or evicted are gone from the mempool, so are their effects.
+pub struct MempoolWatcher {
+    rpc: Client,
+    mongo_client: MongoClient,
+    config: Config,
+    seen: HashMap<Txid, SeenTx>,
+}
+
+impl MempoolWatcher {
+    pub fn new(rpc: Client, mongo_client: MongoClient, config: Config) -> Self {
+        MempoolWatcher {
+            rpc,
+            mongo_client,
+            config,
+            seen: HashMap::new(),
+        }
+    }
+
+    // Syncs forever, failures are logged and retried on the next sync
+    pub async fn run(mut self, poll_interval: Duration) {
+        info!("Watching the mempool every {:?}", poll_interval);
+        loop {
+            match self.sync().await {
+                Ok(count) => debug!("Pending BRC20 effects in the mempool: {}", count),
+                Err(e) => error!("Failed to sync the mempool: {}", e),
+            }
+            tokio::time::sleep(poll_interval).await;
+        }
+    }
+
+    async fn sync(&mut self) -> Result<usize, IndexerError> {
+        let rpc = &self.rpc;
+        let retry_policy = &self.config.retry_policy;
+        let mempool = retry_policy
+            .run(
+                RetryClass::Rpc,
+                "get_raw_mempool_verbose",
+                move || async move { Ok(rpc.get_raw_mempool_verbose()?) },
+            )
+            .await?;
+
+        evict(&mut self.seen, &mempool);
+        for (txid, entry) in &mempool {
+            if self.seen.contains_key(txid) {
+                continue;
+            }
+            // It can have left the mempool since it was listed
+            let raw_tx = match self.fetch(txid).await {
+                Ok(raw_tx) => raw_tx,
+                Err(e) if e.action() != ErrorAction::Retry => {
+                    debug!("Skipping mempool transaction {}: {}", txid, e);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            let spent = raw_tx
+                .transaction()?
+                .input
+                .iter()
+                .map(|input| input.previous_output)
+                .collect();
+            let has_inscription = get_witness_data_from_raw_tx(&raw_tx)
+                .unwrap_or_default()
+                .into_iter()
+                .any(|witness| extract_and_process_witness_data(witness).is_some());
+
+            self.seen.insert(
+                *txid,
+                SeenTx {
+                    time: entry.time,
+                    spent,
+                    raw_tx: has_inscription.then_some(raw_tx),
+                },
+            );
+        }
+
+        let order = simulation_order(&self.seen);
+
+        let mut simulator = Simulator::new(&self.rpc, &self.mongo_client, &self.config).await?;
+        let mut documents = Vec::new();
+        for txid in order {
+            let seen = match self.seen.get_mut(&txid) {
+                Some(seen) => seen,
+                None => continue,
+            };
+            let time = seen.time;
+            // Spends of transfer inscriptions are only known once the inscription is
+            if seen.raw_tx.is_none()
+                && seen
+                    .spent
+                    .iter()
+                    .any(|outpoint| simulator.is_active_transfer(outpoint))
+            {
+                let rpc = &self.rpc;
+                seen.raw_tx = Some(
+                    retry_policy
+                        .run(
+                            RetryClass::Rpc,
+                            "get_raw_transaction_info",
+                            move || async move { Ok(rpc.get_raw_transaction_info(&txid, None)?) },
+                        )
+                        .await?,
+                );
+            }
+            let raw_tx = match &seen.raw_tx {
+                Some(raw_tx) => raw_tx,
+                None => continue,
+            };
+
+            let effects = match simulator.simulate(raw_tx).await {
+                Ok(effects) => effects,
+                Err(e) if e.action() == ErrorAction::Skip => {
+                    debug!("Skipping mempool transaction {}: {}", txid, e);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            for effect in effects {
+                let mut document = effect.to_document();
+                document.insert(
+                    "seen_at",
+                    Bson::DateTime(DateTime::from_millis(time as i64 * 1000)),
+                );
+                documents.push(document);
+            }
+        }
+
+        let count = documents.len();
+        self.mongo_client.replace_mempool_effects(documents).await?;
+
+        Ok(count)
+    }
+
+    async fn fetch(&self, txid: &Txid) -> Result<GetRawTransactionResult, IndexerError> {
+        let rpc = &self.rpc;
+        self.config
+            .retry_policy
+            .run(
+                RetryClass::Rpc,
+                "get_raw_transaction_info",
+                move || async move { Ok(rpc.get_raw_transaction_info(txid, None)?) },
+            )
+            .await
+    }
+}
+
+// Forgets the transactions that left the mempool, they were confirmed, replaced or evicted
+fn evict<V>(seen: &mut HashMap<Txid, SeenTx>, mempool: &HashMap<Txid, V>) {
+    seen.retain(|txid, _| mempool.contains_key(txid));
+}
+
+// The order the transactions are simulated in: the order they entered the mempool, except that
+// a transaction always comes after the unconfirmed ones it spends from. The mempool only knows
+// the time to the second, a child is often seen in the same second as its parent.
+fn simulation_order(seen: &HashMap<Txid, SeenTx>) -> Vec<Txid> {
+    let mut by_time: Vec<(u64, Txid)> =
+        seen.iter().map(|(txid, seen)| (seen.time, *txid)).collect();
+    by_time.sort();
+
+    fn visit(
+        txid: Txid,
+        seen: &HashMap<Txid, SeenTx>,
+        visited: &mut HashSet<Txid>,
+        order: &mut Vec<Txid>,
+    ) {
+        if !visited.insert(txid) {
+            return;
+        }
+        if let Some(tx) = seen.get(&txid) {
+            for outpoint in &tx.spent {
+                if seen.contains_key(&outpoint.txid) {
+                    visit(outpoint.txid, seen, visited, order);
+                }
+            }
+        }
+        order.push(txid);
+    }
+
+    let mut visited = HashSet::new();
+    let mut order = Vec::with_capacity(by_time.len());
+    for (_, txid) in by_time {
+        visit(txid, seen, &mut visited, &mut order);
+    }
+
+    order
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::hashes::Hash;
+
+    fn txid(byte: u8) -> Txid {
+        Txid::from_byte_array([byte; 32])
+    }
+
+    fn seen_tx(time: u64, spent: &[Txid]) -> SeenTx {
+        SeenTx {
+            time,
+            spent: spent.iter().map(|txid| OutPoint::new(*txid, 0)).collect(),
+            raw_tx: None,
+        }
+    }
+
+    #[test]
+    fn test_simulation_order() {
+        let (confirmed, early, child, parent, late) = (txid(0), txid(5), txid(1), txid(2), txid(3));
+        let mut seen = HashMap::from([
+            (early, seen_tx(100, &[confirmed])),
+            // The child sorts before its parent by txid, both entered at 200
+            (child, seen_tx(200, &[parent, confirmed])),
+            (parent, seen_tx(200, &[early])),
+            (late, seen_tx(300, &[])),
+        ]);
+        assert_eq!(simulation_order(&seen), vec![early, parent, child, late]);
+
+        // The parent was confirmed, the late one replaced
+        let mempool = HashMap::from([(early, ()), (child, ())]);
+        evict(&mut seen, &mempool);
+        assert_eq!(simulation_order(&seen), vec![early, child]);
+    }
+}
@@ -0,0 +1,195 @@
+use super::retry::{retry_metrics, RetryClassMetrics};
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+use std::sync::OnceLock;
+
+// Metrics are created on first use and live as long as the process
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+// Block processing takes from milliseconds for empty blocks to minutes during inscription waves
+const BLOCK_SECONDS_BUCKETS: [f64; 12] = [
+    0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0,
+];
+
+pub struct Metrics {
+    registry: Registry,
+    pub indexed_height: IntGauge,
+    pub node_height: IntGauge,
+    pub lag_blocks: IntGauge,
+    pub block_processing_seconds: Histogram,
+    pub block_write_seconds: Histogram,
+    // op is deploy, mint, transfer or send, result is valid or invalid
+    pub operations: IntCounterVec,
+    // reason is the stable code of the InvalidReason
+    pub invalid_operations: IntCounterVec,
+    // cache is ticker or user_balance, result is hit or miss
+    pub cache_lookups: IntCounterVec,
+    pub active_transfers: IntGauge,
+    // Mirrors retry::retry_metrics(), brought up to date on every scrape
+    retry_events: IntCounterVec,
+}
+
+pub fn metrics() -> &'static Metrics {
+    METRICS.get_or_init(Metrics::new)
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry =
+            Registry::new_custom(Some("brc20".to_string()), None).expect("valid registry prefix");
+
+        let indexed_height =
+            IntGauge::new("indexed_block_height", "Last completed block").expect("valid metric");
+        let node_height = IntGauge::new("node_block_height", "Block height of the node's tip")
+            .expect("valid metric");
+        let lag_blocks = IntGauge::new(
+            "lag_blocks",
+            "Blocks between the node's tip and the last completed block",
+        )
+        .expect("valid metric");
+        let block_processing_seconds = Histogram::with_opts(
+            HistogramOpts::new(
+                "block_processing_seconds",
+                "Time to index a block, including writes",
+            )
+            .buckets(BLOCK_SECONDS_BUCKETS.to_vec()),
+        )
+        .expect("valid metric");
+        let block_write_seconds = Histogram::with_opts(
+            HistogramOpts::new(
+                "block_write_seconds",
+                "Time to write the results of a block to MongoDB",
+            )
+            .buckets(BLOCK_SECONDS_BUCKETS.to_vec()),
+        )
+        .expect("valid metric");
+        let operations = IntCounterVec::new(
+            Opts::new("operations_total", "BRC20 operations indexed"),
+            &["op", "result"],
+        )
+        .expect("valid metric");
+        let invalid_operations = IntCounterVec::new(
+            Opts::new(
+                "invalid_operations_total",
+                "Invalid BRC20 operations by reason",
+            ),
+            &["op", "reason"],
+        )
+        .expect("valid metric");
+        let cache_lookups = IntCounterVec::new(
+            Opts::new(
+                "cache_lookups_total",
+                "Lookups of the per block caches, misses are loaded from MongoDB",
+            ),
+            &["cache", "result"],
+        )
+        .expect("valid metric");
+        let active_transfers = IntGauge::new(
+            "active_transfers",
+            "Transfer inscriptions that haven't been sent yet",
+        )
+        .expect("valid metric");
+        let retry_events = IntCounterVec::new(
+            Opts::new("retry_events_total", "Retried RPC and MongoDB calls"),
+            &["class", "event"],
+        )
+        .expect("valid metric");
+
+        for collector in [
+            Box::new(indexed_height.clone()) as Box<dyn prometheus::core::Collector>,
+            Box::new(node_height.clone()),
+            Box::new(lag_blocks.clone()),
+            Box::new(block_processing_seconds.clone()),
+            Box::new(block_write_seconds.clone()),
+            Box::new(operations.clone()),
+            Box::new(invalid_operations.clone()),
+            Box::new(cache_lookups.clone()),
+            Box::new(active_transfers.clone()),
+            Box::new(retry_events.clone()),
+        ] {
+            registry.register(collector).expect("unique metric");
+        }
+
+        Metrics {
+            registry,
+            indexed_height,
+            node_height,
+            lag_blocks,
+            block_processing_seconds,
+            block_write_seconds,
+            operations,
+            invalid_operations,
+            cache_lookups,
+            active_transfers,
+            retry_events,
+        }
+    }
+
+    pub fn set_indexed_height(&self, height: i64) {
+        self.indexed_height.set(height);
+        self.lag_blocks.set(self.node_height.get() - height);
+    }
+
+    pub fn set_node_height(&self, height: i64) {
+        self.node_height.set(height);
+        self.lag_blocks.set(height - self.indexed_height.get());
+    }
+
+    pub fn cache_lookup(&self, cache: &str, hit: bool) {
+        let result = if hit { "hit" } else { "miss" };
+        self.cache_lookups.with_label_values(&[cache, result]).inc();
+    }
+
+    // The metrics in the Prometheus text format
+    pub fn render(&self) -> String {
+        let retries = retry_metrics();
+        for (class, class_metrics) in [("rpc", retries.rpc), ("storage", retries.storage)] {
+            self.update_retry_events(class, class_metrics);
+        }
+
+        let mut buffer = Vec::new();
+        if let Err(e) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            log::error!("Failed to encode metrics: {}", e);
+        }
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+
+    fn update_retry_events(&self, class: &str, class_metrics: RetryClassMetrics) {
+        for (event, total) in [
+            ("attempt", class_metrics.calls),
+            ("retry", class_metrics.retries),
+            ("recovered", class_metrics.recovered),
+            ("exhausted", class_metrics.exhausted),
+            ("failed", class_metrics.failed),
+        ] {
+            let counter: IntCounter = self.retry_events.with_label_values(&[class, event]);
+            // The totals only grow, catch the counter up with them
+            counter.inc_by(total.saturating_sub(counter.get()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = metrics();
+        metrics.set_node_height(800_010);
+        metrics.set_indexed_height(800_000);
+        metrics
+            .invalid_operations
+            .with_label_values(&["mint", "max_supply_reached"])
+            .inc();
+
+        let rendered = metrics.render();
+        assert!(rendered.contains("brc20_lag_blocks 10"));
+        assert!(rendered.contains(
+            r#"brc20_invalid_operations_total{op="mint",reason="max_supply_reached"} 1"#
+        ));
+        assert!(rendered.contains(r#"brc20_retry_events_total{class="rpc",event="attempt"}"#));
+    }
+}
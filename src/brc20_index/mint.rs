@@ -1,15 +1,21 @@
 use std::collections::HashMap;
 
-use crate::brc20_index::user_balance::UserBalanceEntryType;
+use crate::brc20_index::user_balance::{EntrySource, UserBalanceEntryType};
 
 use super::{
-    consts, invalid_brc20::InvalidBrc20Tx, mongo::MongoClient, user_balance::UserBalanceEntry,
-    utils::convert_to_float, Brc20Inscription, ToDocument,
+    consts,
+    error::IndexerError,
+    invalid_brc20::{InvalidBrc20Tx, InvalidReason},
+    metrics::metrics,
+    mongo::MongoClient,
+    user_balance::UserBalanceEntry,
+    utils::convert_to_float,
+    Brc20Inscription, ToDocument,
 };
 use bitcoin::Address;
 use bitcoincore_rpc::bitcoincore_rpc_json::GetRawTransactionResult;
 use log::{error, info};
-use mongodb::bson::{doc, Bson, DateTime, Document};
+use mongodb::bson::{doc, Bson, Document};
 use serde::Serialize;
 
 #[derive(Debug, Clone, Serialize)]
@@ -20,6 +26,7 @@ pub struct Brc20Mint {
     pub to: Address,
     pub tx: GetRawTransactionResult,
     pub inscription: Brc20Inscription,
+    pub inscription_id: String,
     pub is_valid: bool,
 }
 
@@ -32,8 +39,8 @@ impl ToDocument for Brc20Mint {
             "to": self.to.to_string(),
             "tx": self.tx.to_document(),
             "inscription": self.inscription.to_document(),
+            "inscription_id": &self.inscription_id,
             "is_valid": self.is_valid,
-            "created_at": Bson::DateTime(DateTime::now())
         }
     }
 }
@@ -42,6 +49,7 @@ impl Brc20Mint {
     pub fn new(
         tx: &GetRawTransactionResult,
         inscription: Brc20Inscription,
+        inscription_id: String,
         block_height: u32,
         tx_height: u32,
         to: Address,
@@ -53,6 +61,7 @@ impl Brc20Mint {
             to,
             tx: tx.clone(),
             inscription,
+            inscription_id,
             is_valid: false,
         }
     }
@@ -65,12 +74,12 @@ impl Brc20Mint {
         &self.inscription
     }
 
-    pub async fn validate_mint<'a>(
+    pub async fn validate_mint(
         mut self,
         ticker_doc_opt: Option<&Document>,
         invalid_brc20_docs: &mut Vec<Document>,
-    ) -> Result<Brc20Mint, Box<dyn std::error::Error>> {
-        let mut reason = String::new();
+    ) -> Result<Brc20Mint, IndexerError> {
+        let mut reason = InvalidReason::TickerNotFound;
 
         if let Some(ticker_doc) = ticker_doc_opt {
             // get values from ticker doc
@@ -86,14 +95,20 @@ impl Brc20Mint {
                 .get("total_minted")
                 .and_then(Bson::as_f64)
                 .unwrap_or_default();
-            let decimals = ticker_doc
-                .get("decimals")
-                .and_then(Bson::as_i32)
-                .unwrap_or_default();
+            // Tickers store their decimals as 64 bit integers, older ones as 32 bit
+            let decimals = match ticker_doc.get("decimals") {
+                Some(Bson::Int32(decimals)) => i64::from(*decimals),
+                Some(Bson::Int64(decimals)) => *decimals,
+                _ => 0,
+            };
+
+            let decimals = u8::try_from(decimals).map_err(|_| {
+                IndexerError::Decode(format!("Invalid ticker decimals: {}", decimals))
+            })?;
 
             // get amount from inscription
-            let amount = match self.inscription.amt.as_ref().map(String::as_str) {
-                Some(amt_str) => convert_to_float(amt_str, decimals.try_into().unwrap()),
+            let amount = match self.inscription.amt.as_deref() {
+                Some(amt_str) => convert_to_float(amt_str, decimals),
                 None => Ok(0.0),
             };
 
@@ -102,10 +117,16 @@ impl Brc20Mint {
                 Ok(amount) => {
                     // Check if the amount is greater than the limit
                     if amount > limit {
-                        reason = "Mint amount exceeds limit".to_string();
+                        reason = InvalidReason::MintExceedsLimit {
+                            requested: amount,
+                            limit,
+                        };
                     // Check if total minted is already greater than or equal to max supply
                     } else if total_minted >= max_supply {
-                        reason = "Total minted is already at max supply".to_string();
+                        reason = InvalidReason::MaxSupplyReached {
+                            total_minted,
+                            max_supply,
+                        };
                     // Check if the total minted amount + requested mint amount exceeds the max supply
                     } else if total_minted + amount > max_supply {
                         self.is_valid = true;
@@ -118,11 +139,9 @@ impl Brc20Mint {
                     }
                 }
                 Err(e) => {
-                    reason = e.to_string();
+                    reason = e;
                 }
             }
-        } else {
-            reason = "Ticker symbol does not exist".to_string();
         }
         // handle invalid mint transaction
         if !self.is_valid {
@@ -132,7 +151,7 @@ impl Brc20Mint {
             let invalid_tx = InvalidBrc20Tx::new(
                 self.tx.txid,
                 self.inscription.clone(),
-                reason,
+                vec![reason],
                 self.block_height,
             );
 
@@ -176,7 +195,7 @@ async fn update_ticker_total_minted(
     tickers: &mut HashMap<String, Document>,
     mongo_client: &MongoClient,
     block_height: u32,
-) -> Result<(), Box<dyn std::error::Error>> {
+) -> Result<(), IndexerError> {
     // Check if the hashmap contains the ticker
     if let Some(ticker_doc) = get_ticker(tickers, ticker_symbol, mongo_client).await {
         // Update the total minted amount in the hashmap
@@ -203,7 +222,7 @@ pub async fn update_balances_and_ticker(
     validated_mint_tx: &Brc20Mint,
     tickers: &mut HashMap<String, Document>,
     block_height: u32,
-) -> Result<UserBalanceEntry, Box<dyn std::error::Error>> {
+) -> Result<UserBalanceEntry, IndexerError> {
     // Update total minted tokens for this ticker in MongoDB and in-memory hashmap
     update_ticker_total_minted(
         &validated_mint_tx.inscription.tick.to_lowercase(),
@@ -215,32 +234,49 @@ pub async fn update_balances_and_ticker(
     .await?;
 
     // return user balance entry
-    Ok(mongo_client
+    mongo_client
         .insert_user_balance_entry(
             &validated_mint_tx.to.to_string(),
             validated_mint_tx.amt,
             &validated_mint_tx.inscription.tick.to_lowercase(),
             validated_mint_tx.block_height.into(),
             UserBalanceEntryType::Receive,
+            EntrySource::new(
+                "mint",
+                &validated_mint_tx.tx.txid.to_string(),
+                validated_mint_tx.tx_height.into(),
+                validated_mint_tx.inscription_id.clone(),
+            ),
         )
-        .await?)
+        .await
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn handle_mint_operation(
     mongo_client: &MongoClient,
     block_height: u32,
     tx_height: u32,
     owner: Address,
     inscription: Brc20Inscription,
+    inscription_id: String,
     raw_tx: &GetRawTransactionResult,
     tickers: &mut HashMap<String, Document>,
     invalid_brc20_docs: &mut Vec<Document>,
-) -> Result<(Brc20Mint, UserBalanceEntry), Box<dyn std::error::Error>> {
+) -> Result<(Brc20Mint, UserBalanceEntry), IndexerError> {
     // Try to get the ticker from the hashmap if not, then mongodb
-    let ticker_doc_opt = get_ticker(tickers, &inscription.tick.to_lowercase(), mongo_client).await;
+    let ticker_symbol = inscription.tick.to_lowercase();
+    metrics().cache_lookup("ticker", tickers.contains_key(&ticker_symbol));
+    let ticker_doc_opt = get_ticker(tickers, &ticker_symbol, mongo_client).await;
 
     // Create a new Brc20Mint instance
-    let new_mint = Brc20Mint::new(&raw_tx, inscription, block_height, tx_height, owner);
+    let new_mint = Brc20Mint::new(
+        raw_tx,
+        inscription,
+        inscription_id,
+        block_height,
+        tx_height,
+        owner,
+    );
     let validated_mint_tx = new_mint
         .validate_mint(ticker_doc_opt, invalid_brc20_docs)
         .await?;
@@ -1,72 +1,82 @@
-use std::collections::HashMap;
-use std::env;
-use std::time::Duration;
-
-use super::transfer::Brc20ActiveTransfer;
-use super::user_balance::{UserBalanceEntry, UserBalanceEntryType};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::str::FromStr;
+
+use super::block_time::BlockTime;
+use super::error::IndexerError;
+use super::progress::BlockSummary;
+use super::retry::{RetryClass, RetryPolicy};
+use super::transfer::{ActiveTransferKey, ActiveTransfers, Brc20ActiveTransfer};
+use super::user_balance::{BalanceView, EntrySource, UserBalanceEntry, UserBalanceEntryType};
 use crate::brc20_index::consts;
+use bitcoin::{BlockHash, Network};
 use futures_util::stream::TryStreamExt;
 use futures_util::StreamExt;
-use log::{error, info, warn};
+use log::{debug, error, info};
 use mongodb::bson::{doc, Bson, DateTime, Document};
-use mongodb::options::{FindOneOptions, FindOptions, IndexOptions, UpdateOptions};
+use mongodb::options::{
+    AggregateOptions, FindOneOptions, FindOptions, IndexOptions, InsertManyOptions, UpdateOptions,
+};
 use mongodb::{bson, options::ClientOptions, Client};
-use mongodb::{Cursor, IndexModel};
+use mongodb::{Collection, Cursor, IndexModel};
+
+// The collection or index to drop doesn't exist
+const MONGO_NAMESPACE_NOT_FOUND: i32 = 26;
+const MONGO_INDEX_NOT_FOUND: i32 = 27;
+// An index with the same name or keys but other options already exists
+const MONGO_INDEX_OPTIONS_CONFLICT: i32 = 85;
+const MONGO_INDEX_KEY_SPECS_CONFLICT: i32 = 86;
 
 pub struct MongoClient {
     client: Client,
     db_name: String,
+    retry_policy: RetryPolicy,
 }
 
 impl MongoClient {
     pub async fn new(
         connection_string: &str,
         db_name: &str,
-        mongo_direct_connection: bool,
+        direct_connection: Option<bool>,
+        retry_policy: RetryPolicy,
     ) -> Result<Self, mongodb::error::Error> {
         let mut client_options = ClientOptions::parse(connection_string).await?;
-        // Uncomment when using locally
-        // Get the mongo host from environment variable if on local workstation
-        let mongo_db_host = env::var("MONGO_DB_HOST");
-        match mongo_db_host {
-            Ok(_host) => client_options.direct_connection = Some(mongo_direct_connection),
-            Err(_) => (),
-        };
+        // Direct connections are used on local workstations, replica sets leave it unset
+        if direct_connection.is_some() {
+            client_options.direct_connection = direct_connection;
+        }
 
         let client = Client::with_options(client_options)?;
 
         Ok(Self {
             client,
             db_name: db_name.to_string(),
+            retry_policy,
         })
     }
 
+    fn collection(&self, collection_name: &str) -> Collection<Document> {
+        self.client
+            .database(&self.db_name)
+            .collection::<bson::Document>(collection_name)
+    }
+
+    // Inserts a document, one that is already stored under a unique key is left as it is
     pub async fn insert_document(
         &self,
         collection_name: &str,
         document: bson::Document,
-    ) -> anyhow::Result<()> {
-        let db = self.client.database(&self.db_name);
-        let collection = db.collection::<bson::Document>(collection_name);
-        let retries = consts::MONGO_RETRIES;
-
-        for attempt in 0..=retries {
-            match collection.insert_one(document.clone(), None).await {
-                Ok(_) => return Ok(()),
-                Err(e) => {
-                    error!(
-                        "Attempt {}/{} failed with error: {}. Retrying...",
-                        attempt + 1,
-                        retries,
-                        e,
-                    );
-                    tokio::time::sleep(Duration::from_secs(2)).await;
+    ) -> Result<(), IndexerError> {
+        let collection = &self.collection(collection_name);
+        let document = &document;
+
+        self.retry_policy
+            .run(RetryClass::Storage, "insert_document", move || async move {
+                match collection.insert_one(document.clone(), None).await {
+                    Ok(_) => Ok(()),
+                    Err(e) => skip_duplicates(collection_name, e.into()),
                 }
-            }
-        }
-        Err(anyhow::anyhow!(
-            "Failed to insert document after all retries"
-        ))
+            })
+            .await
     }
 
     pub async fn update_one_with_retries(
@@ -75,31 +85,57 @@ impl MongoClient {
         filter: Document,
         update: Document,
         update_options: Option<UpdateOptions>,
-    ) -> anyhow::Result<()> {
-        let db = self.client.database(&self.db_name);
-        let collection = db.collection::<bson::Document>(collection_name);
-        let retries = consts::MONGO_RETRIES;
-
-        for attempt in 0..=retries {
-            match collection
-                .update_one(filter.clone(), update.clone(), update_options.clone())
-                .await
-            {
-                Ok(_) => return Ok(()),
-                Err(e) => {
-                    error!(
-                        "Attempt {}/{} failed with error: {}. Retrying...",
-                        attempt + 1,
-                        retries,
-                        e,
-                    );
-                    tokio::time::sleep(Duration::from_secs(2)).await;
-                }
-            }
-        }
-        Err(anyhow::anyhow!(
-            "Failed to update document after all retries"
-        ))
+    ) -> Result<(), IndexerError> {
+        let collection = &self.collection(collection_name);
+        let (filter, update, update_options) = (&filter, &update, &update_options);
+
+        self.retry_policy
+            .run(RetryClass::Storage, "update_one", move || async move {
+                collection
+                    .update_one(filter.clone(), update.clone(), update_options.clone())
+                    .await?;
+                Ok(())
+            })
+            .await
+    }
+
+    pub async fn update_many_with_retries(
+        &self,
+        collection_name: &str,
+        filter: Document,
+        update: Document,
+    ) -> Result<(), IndexerError> {
+        let collection = &self.collection(collection_name);
+        let (filter, update) = (&filter, &update);
+
+        self.retry_policy
+            .run(RetryClass::Storage, "update_many", move || async move {
+                collection
+                    .update_many(filter.clone(), update.clone(), None)
+                    .await?;
+                Ok(())
+            })
+            .await
+    }
+
+    // Runs an update pipeline on every matching document, returns the number modified
+    pub async fn update_many_with_pipeline_with_retries(
+        &self,
+        collection_name: &str,
+        filter: Document,
+        pipeline: Vec<Document>,
+    ) -> Result<u64, IndexerError> {
+        let collection = &self.collection(collection_name);
+        let (filter, pipeline) = (&filter, &pipeline);
+
+        self.retry_policy
+            .run(RetryClass::Storage, "update_many", move || async move {
+                let result = collection
+                    .update_many(filter.clone(), pipeline.clone(), None)
+                    .await?;
+                Ok(result.modified_count)
+            })
+            .await
     }
 
     pub async fn find_one_with_retries(
@@ -107,26 +143,15 @@ impl MongoClient {
         collection_name: &str,
         filter: Document,
         options: Option<FindOneOptions>,
-    ) -> anyhow::Result<Option<Document>> {
-        let db = self.client.database(&self.db_name);
-        let collection = db.collection::<bson::Document>(collection_name);
-        let retries = consts::MONGO_RETRIES;
-
-        for attempt in 0..=retries {
-            match collection.find_one(filter.clone(), options.clone()).await {
-                Ok(result) => return Ok(result),
-                Err(e) => {
-                    error!(
-                        "Attempt {}/{} failed with error: {}. Retrying...",
-                        attempt + 1,
-                        retries,
-                        e,
-                    );
-                    tokio::time::sleep(Duration::from_secs(2)).await;
-                }
-            }
-        }
-        Err(anyhow::anyhow!("Failed to find document after all retries"))
+    ) -> Result<Option<Document>, IndexerError> {
+        let collection = &self.collection(collection_name);
+        let (filter, options) = (&filter, &options);
+
+        self.retry_policy
+            .run(RetryClass::Storage, "find_one", move || async move {
+                Ok(collection.find_one(filter.clone(), options.clone()).await?)
+            })
+            .await
     }
 
     pub async fn find_with_retries(
@@ -134,84 +159,176 @@ impl MongoClient {
         collection_name: &str,
         filter: Option<Document>,
         options: Option<FindOptions>,
-    ) -> anyhow::Result<Cursor<Document>> {
-        let db = self.client.database(&self.db_name);
-        let collection = db.collection::<bson::Document>(collection_name);
-        let retries = consts::MONGO_RETRIES;
-
-        for attempt in 0..=retries {
-            match collection.find(filter.clone(), options.clone()).await {
-                Ok(cursor) => return Ok(cursor),
-                Err(e) => {
-                    error!(
-                        "Attempt {}/{} failed with error: {}. Retrying...",
-                        attempt + 1,
-                        retries,
-                        e,
-                    );
-                    tokio::time::sleep(Duration::from_secs(2)).await;
-                }
-            }
-        }
-        Err(anyhow::anyhow!(
-            "Failed to find documents after all retries"
-        ))
+    ) -> Result<Cursor<Document>, IndexerError> {
+        let collection = &self.collection(collection_name);
+        let (filter, options) = (&filter, &options);
+
+        self.retry_policy
+            .run(RetryClass::Storage, "find", move || async move {
+                Ok(collection.find(filter.clone(), options.clone()).await?)
+            })
+            .await
+    }
+
+    // Stages that group documents may spill to disk, the collections are too large for memory
+    pub async fn aggregate_with_retries(
+        &self,
+        collection_name: &str,
+        pipeline: Vec<Document>,
+    ) -> Result<Cursor<Document>, IndexerError> {
+        let collection = &self.collection(collection_name);
+        let pipeline = &pipeline;
+        let options = &AggregateOptions::builder().allow_disk_use(true).build();
+
+        self.retry_policy
+            .run(RetryClass::Storage, "aggregate", move || async move {
+                Ok(collection
+                    .aggregate(pipeline.clone(), options.clone())
+                    .await?)
+            })
+            .await
     }
 
     pub async fn insert_many_with_retries(
         &self,
         collection_name: &str,
         documents: &[bson::Document],
-    ) -> Result<(), anyhow::Error> {
-        let db = self.client.database(&self.db_name);
-        let collection = db.collection::<bson::Document>(collection_name);
-        let retries = consts::MONGO_RETRIES;
-
-        let mut attempts = 0;
-        while attempts <= retries {
-            match collection.insert_many(documents, None).await {
-                Ok(_) => return Ok(()),
-                Err(e) => {
-                    error!(
-                        "Failed to insert documents: {}. Attempt {}/{}",
-                        e,
-                        attempts + 1,
-                        retries + 1
-                    );
-                    attempts += 1;
-                    tokio::time::sleep(std::time::Duration::from_secs(2)).await;
+    ) -> Result<(), IndexerError> {
+        let collection = &self.collection(collection_name);
+        // Unordered, so the documents after one that is already stored are still inserted
+        let options = &InsertManyOptions::builder().ordered(false).build();
+
+        self.retry_policy
+            .run(RetryClass::Storage, "insert_many", move || async move {
+                match collection.insert_many(documents, options.clone()).await {
+                    Ok(_) => Ok(()),
+                    Err(e) => skip_duplicates(collection_name, e.into()),
                 }
-            }
-        }
-        Err(anyhow::Error::msg("All retry attempts failed"))
+            })
+            .await
     }
 
     pub async fn delete_many_with_retries(
         &self,
         collection_name: &str,
         filter: Document,
-    ) -> Result<(), anyhow::Error> {
-        let db = self.client.database(&self.db_name);
-        let collection = db.collection::<bson::Document>(collection_name);
-        let retries = consts::MONGO_RETRIES;
-
-        let mut attempts = 0;
-        while attempts <= retries {
-            match collection.delete_many(filter.clone(), None).await {
-                Ok(_) => return Ok(()),
-                Err(e) => {
-                    error!(
-                        "Failed to delete documents: {}. Attempt {}/{}",
-                        e,
-                        attempts + 1,
-                        retries + 1
-                    );
-                    attempts += 1;
-                    tokio::time::sleep(std::time::Duration::from_secs(2)).await;
-                }
+    ) -> Result<(), IndexerError> {
+        let collection = &self.collection(collection_name);
+        let filter = &filter;
+
+        self.retry_policy
+            .run(RetryClass::Storage, "delete_many", move || async move {
+                collection.delete_many(filter.clone(), None).await?;
+                Ok(())
+            })
+            .await
+    }
+
+    pub async fn count_documents_with_retries(
+        &self,
+        collection_name: &str,
+        filter: Option<Document>,
+    ) -> Result<u64, IndexerError> {
+        let collection = &self.collection(collection_name);
+        let filter = &filter;
+
+        self.retry_policy
+            .run(RetryClass::Storage, "count_documents", move || async move {
+                Ok(collection.count_documents(filter.clone(), None).await?)
+            })
+            .await
+    }
+
+    async fn create_index_with_retries(
+        &self,
+        collection_name: &str,
+        index_model: IndexModel,
+    ) -> Result<(), IndexerError> {
+        let collection = &self.collection(collection_name);
+        let index_model = &index_model;
+
+        self.retry_policy
+            .run(RetryClass::Storage, "create_index", move || async move {
+                collection.create_index(index_model.clone(), None).await?;
+                Ok(())
+            })
+            .await
+    }
+
+    // Creates a unique index on the natural key of a collection, documents without the key
+    // can be left out with a partial filter. A plain index on the same keys, as created by
+    // earlier versions, is replaced. If the stored documents already repeat a key the index
+    // can't be built, and without it writes aren't idempotent, so the indexer refuses to run.
+    async fn create_unique_index(
+        &self,
+        collection_name: &str,
+        keys: Document,
+        partial_filter: Option<Document>,
+    ) -> Result<(), IndexerError> {
+        let index_name = index_name(&keys);
+        let index_model = IndexModel::builder()
+            .keys(keys)
+            .options(
+                IndexOptions::builder()
+                    .unique(true)
+                    .partial_filter_expression(partial_filter)
+                    .build(),
+            )
+            .build();
+
+        let mut result = self
+            .create_index_with_retries(collection_name, index_model.clone())
+            .await;
+        if let Some(MONGO_INDEX_OPTIONS_CONFLICT | MONGO_INDEX_KEY_SPECS_CONFLICT) =
+            result.as_ref().err().and_then(IndexerError::command_code)
+        {
+            info!(
+                "Replacing index {} of {} with a unique one",
+                index_name, collection_name
+            );
+            self.collection(collection_name)
+                .drop_index(&index_name, None)
+                .await?;
+            result = self
+                .create_index_with_retries(collection_name, index_model)
+                .await;
+        }
+
+        match result {
+            Err(e) if e.is_duplicate_key() => {
+                Err(duplicate_key_error(collection_name, &index_name, &e))
             }
+            result => result,
+        }
+    }
+
+    // Drops an index that a newer schema replaced, returns whether it existed
+    pub async fn drop_index_if_exists(
+        &self,
+        collection_name: &str,
+        keys: &Document,
+    ) -> Result<bool, IndexerError> {
+        let index_name = index_name(keys);
+        let result: Result<(), IndexerError> = self
+            .collection(collection_name)
+            .drop_index(&index_name, None)
+            .await
+            .map_err(Into::into);
+        match result {
+            Ok(()) => {
+                info!("Dropped index {} of {}", index_name, collection_name);
+                Ok(true)
+            }
+            Err(e)
+                if matches!(
+                    e.command_code(),
+                    Some(MONGO_NAMESPACE_NOT_FOUND | MONGO_INDEX_NOT_FOUND)
+                ) =>
+            {
+                Ok(false)
+            }
+            Err(e) => Err(e),
         }
-        Err(anyhow::Error::msg("All retry attempts failed"))
     }
 
     pub async fn get_document_by_field(
@@ -219,7 +336,7 @@ impl MongoClient {
         collection_name: &str,
         field_name: &str,
         field_value: &str,
-    ) -> Result<Option<Document>, anyhow::Error> {
+    ) -> Result<Option<Document>, IndexerError> {
         let filter = doc! { field_name: field_value };
         self.find_one_with_retries(collection_name, filter, None)
             .await
@@ -229,7 +346,7 @@ impl MongoClient {
         &self,
         collection_name: &str,
         filter: Document,
-    ) -> Result<Option<Document>, anyhow::Error> {
+    ) -> Result<Option<Document>, IndexerError> {
         self.find_one_with_retries(collection_name, filter, None)
             .await
     }
@@ -241,7 +358,8 @@ impl MongoClient {
         tick: &str,
         block_height: u64,
         entry_type: UserBalanceEntryType,
-    ) -> Result<UserBalanceEntry, anyhow::Error> {
+        source: EntrySource,
+    ) -> Result<UserBalanceEntry, IndexerError> {
         // instantiate a new user balance entry
         Ok(UserBalanceEntry::new(
             address.to_string(),
@@ -249,23 +367,129 @@ impl MongoClient {
             block_height,
             amount,
             entry_type,
+            source,
         ))
     }
 
-    pub async fn store_completed_block(&self, block_height: i64) -> anyhow::Result<()> {
-        let document = doc! {
+    // Refuses a database that was indexed for another network, returns whether the network
+    // of the database is recorded. Nothing is written.
+    pub async fn check_network(&self, network: Network) -> Result<bool, IndexerError> {
+        let filter = doc! { "_id": "network" };
+        let stored = self
+            .find_one_with_retries(consts::COLLECTION_INDEXER_METADATA, filter, None)
+            .await?;
+
+        match stored {
+            Some(document) => {
+                let stored_network = document.get_str("network")?;
+                if stored_network != network.to_string() {
+                    return Err(IndexerError::Config(format!(
+                        "database {} was indexed for {}, not {}",
+                        self.db_name, stored_network, network
+                    )));
+                }
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    // Records the network of the database on first use and refuses to use a database
+    // that was indexed for another network
+    pub async fn ensure_network(&self, network: Network) -> Result<(), IndexerError> {
+        if !self.check_network(network).await? {
+            let document = doc! {
+                "_id": "network",
+                "network": network.to_string(),
+                "created_at": Bson::DateTime(DateTime::now())
+            };
+            self.insert_document(consts::COLLECTION_INDEXER_METADATA, document)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn store_completed_block(
+        &self,
+        block_height: i64,
+        block_hash: &BlockHash,
+        block_time: &BlockTime,
+        summary: &BlockSummary,
+    ) -> Result<(), IndexerError> {
+        // The only wall clock time indexed, when the block was completed
+        let mut document = doc! {
             consts::KEY_BLOCK_HEIGHT: block_height,
-            "created_at": Bson::DateTime(DateTime::now())
+            "block_hash": block_hash.to_string(),
+            "summary": summary.to_document(),
+            "indexed_at": Bson::DateTime(DateTime::now())
         };
+        block_time.stamp(&mut document);
+
+        // A block that is indexed again replaces its record
+        self.update_one_with_retries(
+            consts::COLLECTION_BLOCKS_COMPLETED,
+            doc! { consts::KEY_BLOCK_HEIGHT: block_height },
+            doc! { "$set": document },
+            Some(UpdateOptions::builder().upsert(true).build()),
+        )
+        .await?;
+
+        Ok(())
+    }
 
-        // Insert into MongoDB collection
-        self.insert_document(consts::COLLECTION_BLOCKS_COMPLETED, document)
+    // The hash of a completed block, None for unknown blocks and blocks completed
+    // before hashes were recorded
+    pub async fn get_completed_block_hash(
+        &self,
+        block_height: i64,
+    ) -> Result<Option<BlockHash>, IndexerError> {
+        let filter = doc! { consts::KEY_BLOCK_HEIGHT: block_height };
+        let document = self
+            .find_one_with_retries(consts::COLLECTION_BLOCKS_COMPLETED, filter, None)
             .await?;
 
-        Ok(())
+        match document
+            .as_ref()
+            .and_then(|doc| doc.get_str("block_hash").ok())
+        {
+            Some(block_hash) => BlockHash::from_str(block_hash)
+                .map(Some)
+                .map_err(|e| IndexerError::Decode(format!("Invalid block hash: {}", e))),
+            None => Ok(None),
+        }
     }
 
-    pub async fn get_last_completed_block_height(&self) -> Result<Option<i64>, anyhow::Error> {
+    // Marks the completed blocks up to block_height as final. Final blocks are never
+    // rolled back on a reorganization, everything above them is provisional.
+    pub async fn mark_final_blocks(&self, block_height: i64) -> Result<(), IndexerError> {
+        let filter = doc! {
+            consts::KEY_BLOCK_HEIGHT: { "$lte": block_height },
+            consts::KEY_FINAL: { "$ne": true },
+        };
+        let update = doc! { "$set": { consts::KEY_FINAL: true } };
+
+        self.update_many_with_retries(consts::COLLECTION_BLOCKS_COMPLETED, filter, update)
+            .await
+    }
+
+    // The last final block, None if no block is final yet
+    pub async fn get_final_block_height(&self) -> Result<Option<i64>, IndexerError> {
+        let find_options = FindOneOptions::builder()
+            .sort(doc! { consts::KEY_BLOCK_HEIGHT: -1 })
+            .build();
+        let document = self
+            .find_one_with_retries(
+                consts::COLLECTION_BLOCKS_COMPLETED,
+                doc! { consts::KEY_FINAL: true },
+                Some(find_options),
+            )
+            .await?;
+
+        Ok(document.and_then(|doc| doc.get_i64(consts::KEY_BLOCK_HEIGHT).ok()))
+    }
+
+    pub async fn get_last_completed_block_height(&self) -> Result<Option<i64>, IndexerError> {
         // Sort in descending order to get the latest block height
         let sort_doc = doc! { consts::KEY_BLOCK_HEIGHT: -1 };
         let find_options = FindOneOptions::builder().sort(sort_doc).build();
@@ -292,7 +516,7 @@ impl MongoClient {
         &self,
         collection_name: &str,
         start_block_height: i64,
-    ) -> anyhow::Result<()> {
+    ) -> Result<(), IndexerError> {
         self.delete_many_with_retries(
             collection_name,
             doc! { "block_height": { "$gte": start_block_height } },
@@ -302,11 +526,86 @@ impl MongoClient {
         Ok(())
     }
 
-    pub async fn drop_collection(&self, collection_name: &str) -> anyhow::Result<()> {
-        self.delete_many_with_retries(collection_name, doc! {})
+    // Transfers inscribed below and sent at or above start_block_height become active again,
+    // returns the number of restored transfers
+    pub async fn restore_active_transfers(
+        &self,
+        start_block_height: i64,
+    ) -> Result<usize, IndexerError> {
+        let filter = doc! {
+            "block_height": { "$lt": start_block_height },
+            "send_block_height": { "$gte": start_block_height },
+        };
+        let mut cursor = self
+            .find_with_retries(consts::COLLECTION_TRANSFERS, Some(filter), None)
             .await?;
 
-        Ok(())
+        let mut restored = 0;
+        while let Some(result) = cursor.next().await {
+            let transfer_doc = result?;
+            let tx_id = transfer_doc
+                .get_document("tx")?
+                .get_str("txid")?
+                .to_string();
+            let inscription_id = transfer_doc.get_str("inscription_id")?.to_string();
+            let block_height = match transfer_doc.get("block_height") {
+                Some(Bson::Int32(height)) => i64::from(*height),
+                Some(Bson::Int64(height)) => *height,
+                _ => {
+                    return Err(IndexerError::Decode(format!(
+                        "Transfer {} has no block_height",
+                        tx_id
+                    )))
+                }
+            };
+
+            // Forget the send
+            let update = doc! {
+                "$set": {
+                    "to": Bson::Null,
+                    "send_tx": Bson::Null,
+                    "send_block_height": Bson::Null,
+                    "send_tx_height": Bson::Null,
+                }
+            };
+            self.update_one_with_retries(
+                consts::COLLECTION_TRANSFERS,
+                doc! { "inscription_id": &inscription_id },
+                update,
+                None,
+            )
+            .await?;
+
+            // The inscription is always on the first output of the inscribe transaction
+            let active_transfer = Brc20ActiveTransfer::new(
+                tx_id.clone(),
+                0,
+                block_height,
+                inscription_id,
+                transfer_doc.get_str("from")?.to_string(),
+                transfer_doc
+                    .get_document("inscription")?
+                    .get_str("tick")?
+                    .to_lowercase(),
+                transfer_doc.get_f64("amt").unwrap_or_default(),
+            );
+            let update = doc! { "$set": bson::to_document(&active_transfer)? };
+            self.update_one_with_retries(
+                consts::COLLECTION_BRC20_ACTIVE_TRANSFERS,
+                doc! {
+                    "tx_id": &active_transfer.tx_id,
+                    "vout": active_transfer.vout,
+                    "inscription_id": &active_transfer.inscription_id,
+                },
+                update,
+                Some(UpdateOptions::builder().upsert(true).build()),
+            )
+            .await?;
+
+            restored += 1;
+        }
+
+        Ok(restored)
     }
 
     // pub async fn rebuild_user_balances(&self, block_height: i64) -> anyhow::Result<()> {
@@ -367,7 +666,7 @@ impl MongoClient {
         &self,
         collection: &str,
         filter: Document,
-    ) -> Result<bool, anyhow::Error> {
+    ) -> Result<bool, IndexerError> {
         match self.find_one_with_retries(collection, filter, None).await {
             Ok(Some(_)) => Ok(true),
             Ok(None) => Ok(false),
@@ -375,13 +674,6 @@ impl MongoClient {
         }
     }
 
-    pub fn get_double(&self, doc: &Document, field: &str) -> Option<f64> {
-        match doc.get(field) {
-            Some(Bson::Double(value)) => Some(*value),
-            _ => None,
-        }
-    }
-
     pub fn get_f64(&self, doc: &Document, field: &str) -> Option<f64> {
         match doc.get(field) {
             Some(Bson::Double(value)) => Some(*value),
@@ -389,154 +681,208 @@ impl MongoClient {
         }
     }
 
-    pub fn get_string(
-        &self,
-        doc: &Document,
-        key: &str,
-    ) -> Result<String, mongodb::bson::document::ValueAccessError> {
-        match doc.get_str(key) {
-            Ok(value) => Ok(value.to_string()),
-            Err(e) => Err(e),
-        }
-    }
-
     pub async fn load_active_transfers_with_retry(
         &self,
-    ) -> Result<Option<HashMap<(String, i64), Brc20ActiveTransfer>>, String> {
-        let retries = consts::MONGO_RETRIES;
-        for attempt in 0..=retries {
-            match self.load_active_transfers().await {
-                Ok(result) => return Ok(result),
-                Err(e) => {
-                    // Error handling and backoff logic here
-                    error!(
-                        "Attempt {}/{} failed with error: {}. Retrying...",
-                        attempt + 1,
-                        retries,
-                        e,
-                    );
-                    tokio::time::sleep(Duration::from_secs(2)).await;
-                }
-            }
-        }
-        Err(format!(
-            "Failed to load active transfers after {} attempts",
-            retries
-        ))
+    ) -> Result<Option<ActiveTransfers>, IndexerError> {
+        self.retry_policy
+            .run(RetryClass::Storage, "load_active_transfers", || {
+                self.load_active_transfers()
+            })
+            .await
     }
 
-    pub async fn load_active_transfers(
-        &self,
-    ) -> Result<Option<HashMap<(String, i64), Brc20ActiveTransfer>>, String> {
-        let mut active_transfers = HashMap::new();
+    pub async fn load_active_transfers(&self) -> Result<Option<ActiveTransfers>, IndexerError> {
+        let mut active_transfers = ActiveTransfers::new();
 
-        let db = self.client.database(&self.db_name);
-        let collection = db.collection::<bson::Document>(consts::COLLECTION_BRC20_ACTIVE_TRANSFERS);
+        let collection = self.collection(consts::COLLECTION_BRC20_ACTIVE_TRANSFERS);
 
         // Check if the collection has any documents
-        let doc_count = collection
-            .estimated_document_count(None)
-            .await
-            .map_err(|e| e.to_string())?;
+        let doc_count = collection.estimated_document_count(None).await?;
 
         // If no documents, return None
         if doc_count == 0 {
             return Ok(None);
         }
 
-        let mut cursor = collection
-            .find(None, None)
-            .await
-            .map_err(|e| e.to_string())?;
+        let mut cursor = collection.find(None, None).await?;
 
         while let Some(result) = cursor.next().await {
             match result {
                 Ok(document) => {
                     let active_transfer = Brc20ActiveTransfer::from_document(document)?;
-                    let key = (active_transfer.tx_id.clone(), active_transfer.vout);
-                    active_transfers.insert(key, active_transfer);
+                    active_transfers.insert(active_transfer.key(), active_transfer);
                 }
-                Err(e) => return Err(e.to_string()),
+                Err(e) => return Err(e.into()),
             }
         }
 
         Ok(Some(active_transfers))
     }
 
-    pub async fn insert_active_transfers_to_mongodb(
+    // Fills in the inscription's details of the active transfers stored without them,
+    // returns how many were filled
+    pub async fn fill_active_transfers(&self) -> Result<u64, IndexerError> {
+        let filter = doc! { "address": { "$exists": false } };
+        let mut cursor = self
+            .find_with_retries(
+                consts::COLLECTION_BRC20_ACTIVE_TRANSFERS,
+                Some(filter),
+                None,
+            )
+            .await?;
+
+        let mut filled = 0;
+        while let Some(result) = cursor.next().await {
+            let mut active_transfer = Brc20ActiveTransfer::from_document(result?)?;
+            self.fill_active_transfer(&mut active_transfer).await?;
+            filled += 1;
+        }
+
+        Ok(filled)
+    }
+
+    // Fills in the inscription's details from its transfer and writes them back
+    async fn fill_active_transfer(
+        &self,
+        active_transfer: &mut Brc20ActiveTransfer,
+    ) -> Result<(), IndexerError> {
+        // Active transfers stored without details were keyed by outpoint alone, a later
+        // transfer of the same transaction replaced an earlier one
+        let find_options = FindOneOptions::builder().sort(doc! { "_id": -1 }).build();
+        let transfer_doc = self
+            .find_one_with_retries(
+                consts::COLLECTION_TRANSFERS,
+                doc! { "tx.txid": &active_transfer.tx_id },
+                Some(find_options),
+            )
+            .await?
+            .ok_or_else(|| {
+                IndexerError::Decode(format!(
+                    "Transfer of active transfer {} not found",
+                    active_transfer.tx_id
+                ))
+            })?;
+
+        active_transfer.inscription_id = transfer_doc.get_str("inscription_id")?.to_string();
+        active_transfer.address = transfer_doc.get_str("from")?.to_string();
+        active_transfer.tick = transfer_doc
+            .get_document("inscription")?
+            .get_str("tick")?
+            .to_lowercase();
+        active_transfer.amt = transfer_doc.get_f64("amt").unwrap_or_default();
+
+        let update = doc! { "$set": bson::to_document(active_transfer)? };
+        self.update_one_with_retries(
+            consts::COLLECTION_BRC20_ACTIVE_TRANSFERS,
+            doc! { "tx_id": &active_transfer.tx_id, "vout": active_transfer.vout },
+            update,
+            None,
+        )
+        .await
+    }
+
+    // The unsent transfer inscriptions of an address, of one tick or of all, oldest first.
+    // Their amounts add up to the transferable balance of the address in each tick.
+    pub async fn get_active_transfers(
         &self,
-        active_transfers: HashMap<(String, i64), Brc20ActiveTransfer>,
-    ) -> Result<(), anyhow::Error> {
-        // Convert the HashMap to a Vec<bson::Document>.
-        let documents: Result<Vec<bson::Document>, _> = active_transfers
-            .values()
-            .map(|value| bson::to_document(value))
-            .collect::<Result<Vec<_>, _>>();
+        address: &str,
+        tick: Option<&str>,
+    ) -> Result<Vec<Brc20ActiveTransfer>, IndexerError> {
+        let filter = Brc20ActiveTransfer::filter(address, tick);
+        let find_options = FindOptions::builder()
+            .sort(doc! { "tick": 1, "block_height": 1, "tx_id": 1 })
+            .build();
+        let mut cursor = self
+            .find_with_retries(
+                consts::COLLECTION_BRC20_ACTIVE_TRANSFERS,
+                Some(filter),
+                Some(find_options),
+            )
+            .await?;
+
+        let mut active_transfers = Vec::new();
+        while let Some(document) = cursor.next().await {
+            active_transfers.push(Brc20ActiveTransfer::from_document(document?)?);
+        }
+
+        Ok(active_transfers)
+    }
 
-        // Insert the documents into the collection with retries.
-        self.insert_many_with_retries(consts::COLLECTION_BRC20_ACTIVE_TRANSFERS, &documents?)
+    /// Brings the stored active transfers in line with the ones after a block, given the keys
+    /// that were stored before it. Sent transfers are deleted and new ones inserted, so a crash
+    /// halfway leaves a state the block can be indexed again from.
+    ///
+    /// Returns the number of deleted and inserted active transfers.
+    pub async fn write_active_transfers(
+        &self,
+        stored_keys: &HashSet<ActiveTransferKey>,
+        active_transfers: &ActiveTransfers,
+    ) -> Result<(usize, usize), IndexerError> {
+        let sent: Vec<Document> = stored_keys
+            .iter()
+            .filter(|key| !active_transfers.contains_key(*key))
+            .map(|(tx_id, vout, inscription_id)| {
+                doc! { "tx_id": tx_id, "vout": vout, "inscription_id": inscription_id }
+            })
+            .collect();
+        if !sent.is_empty() {
+            self.delete_many_with_retries(
+                consts::COLLECTION_BRC20_ACTIVE_TRANSFERS,
+                doc! { "$or": &sent },
+            )
             .await?;
+        }
 
-        Ok(())
+        let inscribed = active_transfers
+            .iter()
+            .filter(|(key, _)| !stored_keys.contains(*key))
+            .map(|(_, active_transfer)| bson::to_document(active_transfer))
+            .collect::<Result<Vec<_>, _>>()?;
+        if !inscribed.is_empty() {
+            self.insert_many_with_retries(consts::COLLECTION_BRC20_ACTIVE_TRANSFERS, &inscribed)
+                .await?;
+        }
+
+        Ok((sent.len(), inscribed.len()))
     }
 
     pub async fn load_user_balance_with_retry(
         &self,
         key: &(String, String),
-    ) -> Result<Option<Document>, anyhow::Error> {
-        let retries = consts::MONGO_RETRIES;
-        for attempt in 0..=retries {
-            match self.load_user_balance(key).await {
-                Ok(result) => return Ok(result),
-                Err(e) => {
-                    error!(
-                        "Attempt {}/{} failed with error: {}. Retrying...",
-                        attempt + 1,
-                        retries,
-                        e,
-                    );
-                    tokio::time::sleep(Duration::from_secs(2)).await;
-                }
-            }
-        }
-        Err(anyhow::anyhow!(
-            "Failed to load user balance after {} attempts",
-            retries
-        ))
+    ) -> Result<Option<Document>, IndexerError> {
+        self.retry_policy
+            .run(RetryClass::Storage, "load_user_balance", || {
+                self.load_user_balance(key)
+            })
+            .await
     }
 
     pub async fn load_user_balance(
         &self,
         key: &(String, String),
-    ) -> Result<Option<Document>, anyhow::Error> {
+    ) -> Result<Option<Document>, IndexerError> {
         let filter = doc! {
             "address": &key.0,
             "tick": &key.1,
         };
 
-        let options = None; // You can specify additional options here if needed
-
         let result = self
-            .find_one_with_retries(consts::COLLECTION_USER_BALANCES, filter, options)
+            .collection(consts::COLLECTION_USER_BALANCES)
+            .find_one(filter, None)
             .await?;
 
         Ok(result)
     }
 
-    pub async fn create_indexes(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let db = self.client.database(&self.db_name);
-
+    pub async fn create_indexes(&self) -> Result<(), IndexerError> {
         // Create an index on the 'address' and 'tick' fields for COLLECTION_USER_BALANCES
-        let user_balances_collection =
-            db.collection::<bson::Document>(consts::COLLECTION_USER_BALANCES);
         let user_balances_index_model = IndexModel::builder()
             .keys(doc! { "address": 1, "tick": 1 }) // 1 for ascending
             .options(IndexOptions::builder().unique(true).build())
             .build();
 
         // Create the index for COLLECTION_USER_BALANCES
-        user_balances_collection
-            .create_index(user_balances_index_model, None)
+        self.create_index_with_retries(consts::COLLECTION_USER_BALANCES, user_balances_index_model)
             .await?;
 
         // Create an index on the 'block_height' field for COLLECTION_USER_BALANCES
@@ -546,44 +892,124 @@ impl MongoClient {
             .build();
 
         // Create the index for COLLECTION_USER_BALANCES
-        user_balances_collection
-            .create_index(block_height_index_model, None)
+        self.create_index_with_retries(consts::COLLECTION_USER_BALANCES, block_height_index_model)
+            .await?;
+
+        // A block is completed once per height
+        self.create_unique_index(
+            consts::COLLECTION_BLOCKS_COMPLETED,
+            doc! { consts::KEY_BLOCK_HEIGHT: 1 },
+            None,
+        )
+        .await?;
+
+        // Operations are keyed by their inscription id, records written before the id was
+        // stored don't have one
+        for collection_name in [
+            consts::COLLECTION_DEPLOYS,
+            consts::COLLECTION_MINTS,
+            consts::COLLECTION_TRANSFERS,
+            consts::COLLECTION_INVALIDS,
+        ] {
+            self.create_unique_index(
+                collection_name,
+                doc! { "inscription_id": 1 },
+                Some(doc! { "inscription_id": { "$exists": true } }),
+            )
+            .await?;
+        }
+
+        // Balance entries are keyed by their position in the block
+        self.create_unique_index(
+            consts::COLLECTION_USER_BALANCE_ENTRY,
+            doc! { "block_height": 1, "seq": 1 },
+            Some(doc! { "seq": { "$exists": true } }),
+        )
+        .await?;
+
+        // Active transfers are keyed by the outpoint and the id of the inscription, the
+        // inscriptions of a transaction share its outpoint
+        self.create_unique_index(
+            consts::COLLECTION_BRC20_ACTIVE_TRANSFERS,
+            doc! { "tx_id": 1, "vout": 1, "inscription_id": 1 },
+            None,
+        )
+        .await?;
+
+        // Create an index for the activity feed of an address in COLLECTION_USER_BALANCE_ENTRY
+        let user_balance_entry_index_model = IndexModel::builder()
+            .keys(doc! { "address": 1, "block_height": -1, "tx_index": -1, "seq": 1 })
+            .options(IndexOptions::default())
+            .build();
+
+        // Create the index for COLLECTION_USER_BALANCE_ENTRY
+        self.create_index_with_retries(
+            consts::COLLECTION_USER_BALANCE_ENTRY,
+            user_balance_entry_index_model,
+        )
+        .await?;
+
+        // Create an index on the 'address' field for COLLECTION_MEMPOOL
+        let mempool_index_model = IndexModel::builder()
+            .keys(doc! { "address": 1 }) // 1 for ascending
+            .options(IndexOptions::default())
+            .build();
+
+        // Create the index for COLLECTION_MEMPOOL
+        self.create_index_with_retries(consts::COLLECTION_MEMPOOL, mempool_index_model)
             .await?;
 
+        // Create an index on the 'address' and 'tick' fields for COLLECTION_BRC20_ACTIVE_TRANSFERS
+        let active_transfers_index_model = IndexModel::builder()
+            .keys(doc! { "address": 1, "tick": 1 }) // 1 for ascending
+            .options(IndexOptions::default())
+            .build();
+
+        // Create the index for COLLECTION_BRC20_ACTIVE_TRANSFERS
+        self.create_index_with_retries(
+            consts::COLLECTION_BRC20_ACTIVE_TRANSFERS,
+            active_transfers_index_model,
+        )
+        .await?;
+
         // Create an index on the 'tick' field for COLLECTION_TICKERS
-        let tickers_collection = db.collection::<bson::Document>(consts::COLLECTION_TICKERS);
         let tickers_index_model = IndexModel::builder()
             .keys(doc! { "tick": 1 }) // 1 for ascending
             .options(IndexOptions::builder().unique(true).build())
             .build();
 
         // Create the index for COLLECTION_TICKERS
-        tickers_collection
-            .create_index(tickers_index_model, None)
+        self.create_index_with_retries(consts::COLLECTION_TICKERS, tickers_index_model)
             .await?;
 
         // Create an index on the 'tx.txid' field for COLLECTION_TRANSFERS
-        let transfers_collection = db.collection::<bson::Document>(consts::COLLECTION_TRANSFERS);
         let txid_index_model = IndexModel::builder()
             .keys(doc! { "tx.txid": 1 }) // 1 for ascending
             .options(IndexOptions::default())
             .build();
 
         // Create the index for COLLECTION_TRANSFERS
-        transfers_collection
-            .create_index(txid_index_model, None)
+        self.create_index_with_retries(consts::COLLECTION_TRANSFERS, txid_index_model)
             .await?;
 
         // Create an index on the 'inscription.tick' field for COLLECTION_MINTS
-        let mints_collection = db.collection::<bson::Document>(consts::COLLECTION_MINTS);
         let mints_index_model = IndexModel::builder()
             .keys(doc! { "inscription.tick": 1 }) // 1 for ascending
             .options(IndexOptions::default())
             .build();
 
         // Create the index for COLLECTION_MINTS
-        mints_collection
-            .create_index(mints_index_model, None)
+        self.create_index_with_retries(consts::COLLECTION_MINTS, mints_index_model)
+            .await?;
+
+        // Create an index on the 'code' field for COLLECTION_INVALIDS
+        let invalids_index_model = IndexModel::builder()
+            .keys(doc! { "code": 1 }) // 1 for ascending
+            .options(IndexOptions::default())
+            .build();
+
+        // Create the index for COLLECTION_INVALIDS
+        self.create_index_with_retries(consts::COLLECTION_INVALIDS, invalids_index_model)
             .await?;
 
         Ok(())
@@ -595,7 +1021,7 @@ impl MongoClient {
     pub async fn delete_user_balances_by_block_height(
         &self,
         start_block_height: i64,
-    ) -> Result<Vec<(String, String)>, anyhow::Error> {
+    ) -> Result<Vec<(String, String)>, IndexerError> {
         let filter = doc! { "block_height": { "$gte": start_block_height }};
 
         let mut deleted_user_balances = Vec::new();
@@ -625,7 +1051,7 @@ impl MongoClient {
         self.delete_many_with_retries(consts::COLLECTION_USER_BALANCES, filter.clone())
             .await?;
 
-        println!(
+        info!(
             "Deleted {} user balances with block_height >= {}",
             deleted_user_balances.len(),
             start_block_height
@@ -634,53 +1060,165 @@ impl MongoClient {
         Ok(deleted_user_balances)
     }
 
+    // Replays the balance entries of a user below block_height, returns
+    // (available_balance, transferable_balance, overall_balance) or None without entries
+    pub async fn replay_user_balance(
+        &self,
+        address: &str,
+        tick: &str,
+        block_height: i64,
+    ) -> Result<Option<(f64, f64, f64)>, IndexerError> {
+        let filter = doc! {
+            "address": address,
+            "tick": tick,
+            "block_height": { "$lt": block_height },
+        };
+
+        let mut cursor = self
+            .find_with_retries(consts::COLLECTION_USER_BALANCE_ENTRY, Some(filter), None)
+            .await?;
+
+        let mut balance = None;
+        while let Some(result) = cursor.next().await {
+            let document = result?;
+            let amount = document.get_f64("amt")?;
+            let entry_type = UserBalanceEntryType::try_from(document.get_str("entry_type")?)?;
+
+            entry_type.apply(balance.get_or_insert((0.0, 0.0, 0.0)), amount);
+        }
+
+        Ok(balance)
+    }
+
+    // The balances of an address by tick, as of the last final block and including the
+    // provisional blocks above it
+    pub async fn get_balance_views(&self, address: &str) -> Result<Vec<BalanceView>, IndexerError> {
+        let final_height = self.get_final_block_height().await?;
+
+        let mut balances = BTreeMap::new();
+        let mut cursor = self
+            .find_with_retries(
+                consts::COLLECTION_USER_BALANCES,
+                Some(doc! { "address": address }),
+                None,
+            )
+            .await?;
+        while let Some(result) = cursor.next().await {
+            let document = result?;
+            let balance = (
+                self.get_f64(&document, consts::AVAILABLE_BALANCE)
+                    .unwrap_or_default(),
+                self.get_f64(&document, consts::TRANSFERABLE_BALANCE)
+                    .unwrap_or_default(),
+                self.get_f64(&document, consts::OVERALL_BALANCE)
+                    .unwrap_or_default(),
+            );
+            balances.insert(document.get_str("tick")?.to_string(), balance);
+        }
+
+        // Only the ticks changed by provisional blocks differ, their balances can
+        // also have dropped to zero and be gone from the user balances
+        let filter = doc! {
+            "address": address,
+            consts::KEY_BLOCK_HEIGHT: { "$gt": final_height.unwrap_or(i64::MIN) },
+        };
+        let mut cursor = self
+            .find_with_retries(consts::COLLECTION_USER_BALANCE_ENTRY, Some(filter), None)
+            .await?;
+        let mut changed_ticks = HashSet::new();
+        while let Some(result) = cursor.next().await {
+            changed_ticks.insert(result?.get_str("tick")?.to_string());
+        }
+
+        let mut confirmed_changes = BTreeMap::new();
+        for tick in changed_ticks {
+            let confirmed = match final_height {
+                Some(final_height) => self
+                    .replay_user_balance(address, &tick, final_height + 1)
+                    .await?
+                    .unwrap_or_default(),
+                None => Default::default(),
+            };
+            confirmed_changes.insert(tick, confirmed);
+        }
+
+        Ok(BalanceView::combine(balances, confirmed_changes))
+    }
+
+    // Replaces the pending effects of the mempool transactions
+    pub async fn replace_mempool_effects(
+        &self,
+        documents: Vec<Document>,
+    ) -> Result<(), IndexerError> {
+        self.delete_many_with_retries(consts::COLLECTION_MEMPOOL, doc! {})
+            .await?;
+        if !documents.is_empty() {
+            self.insert_many_with_retries(consts::COLLECTION_MEMPOOL, &documents)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    // A page of the ledger entries of an address, newest first. Entries of one transaction keep
+    // the order they were written in.
+    pub async fn get_address_activity(
+        &self,
+        address: &str,
+        offset: u64,
+        limit: i64,
+    ) -> Result<Vec<UserBalanceEntry>, IndexerError> {
+        let find_options = FindOptions::builder()
+            .sort(doc! { "block_height": -1, "tx_index": -1, "seq": 1, "_id": 1 })
+            .skip(offset)
+            .limit(limit)
+            .build();
+        let mut cursor = self
+            .find_with_retries(
+                consts::COLLECTION_USER_BALANCE_ENTRY,
+                Some(doc! { "address": address }),
+                Some(find_options),
+            )
+            .await?;
+
+        let mut entries = Vec::new();
+        while let Some(document) = cursor.next().await {
+            entries.push(UserBalanceEntry::try_from(&document?)?);
+        }
+
+        Ok(entries)
+    }
+
+    // The pending effects of the mempool transactions on an address, oldest first
+    pub async fn get_mempool_effects(&self, address: &str) -> Result<Vec<Document>, IndexerError> {
+        let find_options = FindOptions::builder().sort(doc! { "seen_at": 1 }).build();
+        let cursor = self
+            .find_with_retries(
+                consts::COLLECTION_MEMPOOL,
+                Some(doc! { "address": address }),
+                Some(find_options),
+            )
+            .await?;
+
+        Ok(cursor.try_collect().await?)
+    }
+
     pub async fn rebuild_deleted_user_balances(
         &self,
         start_block_height: i64,
         deleted_user_balances: Vec<(String, String)>,
-    ) -> anyhow::Result<()> {
+    ) -> Result<(), IndexerError> {
         let mut user_balances: HashMap<String, HashMap<String, (f64, f64, f64)>> = HashMap::new();
 
         for (address, tick) in deleted_user_balances {
-            let filter = doc! {
-                "address": address.clone(),
-                "tick": tick.clone(),
-                "block_height": { "$lt": start_block_height },
-            };
-
-            let mut cursor = self
-                .find_with_retries(consts::COLLECTION_USER_BALANCE_ENTRY, Some(filter), None)
-                .await?;
-
-            while let Some(result) = cursor.next().await {
-                match result {
-                    Ok(document) => {
-                        let amount = document.get_f64("amt")?;
-                        let entry_type: UserBalanceEntryType =
-                            UserBalanceEntryType::from(document.get_str("entry_type")?);
-
-                        let user_balance = user_balances
-                            .entry(address.clone())
-                            .or_insert_with(HashMap::new);
-                        let balance = user_balance.entry(tick.clone()).or_insert((0.0, 0.0, 0.0)); // (available_balance, transferable_balance, overall balance)
-
-                        match entry_type {
-                            UserBalanceEntryType::Receive => {
-                                balance.0 += amount; // Increase the available balance
-                                balance.2 += amount; // Increase the overall balance
-                            }
-                            UserBalanceEntryType::Send => {
-                                balance.1 -= amount; // Decrease the transferable balance
-                                balance.2 -= amount; // Decrease the overall balance
-                            }
-                            UserBalanceEntryType::Inscription => {
-                                balance.0 -= amount; // Decrease the available balance
-                                balance.1 += amount; // Increase the transferable balance
-                            }
-                        }
-                    }
-                    Err(e) => return Err(e.into()),
-                }
+            if let Some(balance) = self
+                .replay_user_balance(&address, &tick, start_block_height)
+                .await?
+            {
+                user_balances
+                    .entry(address)
+                    .or_default()
+                    .insert(tick, balance);
             }
         }
 
@@ -705,17 +1243,92 @@ impl MongoClient {
         Ok(())
     }
 
-    pub async fn reset_tickers_total_minted(
+    /// Rebuilds every user balance from the balance entries below block_height.
+    ///
+    /// The balances are replayed for each address and tick the entries hold, so a balance
+    /// missing from the user balances is restored as well. Each one is upserted in place and
+    /// the ones without entries are deleted afterwards, an interrupted rebuild leaves every
+    /// balance either as it was or rebuilt and can simply be run again. Returns the number
+    /// of balances rebuilt and deleted.
+    pub async fn rebuild_user_balances(
         &self,
         block_height: i64,
-    ) -> Result<Vec<Document>, Box<dyn std::error::Error>> {
-        let db = self.client.database(&self.db_name);
-        let collection = db.collection::<bson::Document>(consts::COLLECTION_TICKERS);
+    ) -> Result<(usize, usize), IndexerError> {
+        let mut replayed: HashMap<(String, String), (f64, f64, f64)> = HashMap::new();
+        let mut cursor = self
+            .find_with_retries(
+                consts::COLLECTION_USER_BALANCE_ENTRY,
+                Some(doc! { "block_height": { "$lt": block_height } }),
+                None,
+            )
+            .await?;
+        while let Some(result) = cursor.next().await {
+            let document = result?;
+            let key = (
+                document.get_str("address")?.to_string(),
+                document.get_str("tick")?.to_string(),
+            );
+            let entry_type = UserBalanceEntryType::try_from(document.get_str("entry_type")?)?;
+            entry_type.apply(replayed.entry(key).or_default(), document.get_f64("amt")?);
+        }
+
+        for ((address, tick), (available_balance, transferable_balance, overall_balance)) in
+            &replayed
+        {
+            let update = doc! {
+                "$set": {
+                    "available_balance": available_balance,
+                    "transferable_balance": transferable_balance,
+                    "overall_balance": overall_balance,
+                    "block_height": block_height,
+                }
+            };
+            self.update_one_with_retries(
+                consts::COLLECTION_USER_BALANCES,
+                doc! { "address": address, "tick": tick },
+                update,
+                Some(UpdateOptions::builder().upsert(true).build()),
+            )
+            .await?;
+        }
+
+        let find_options = FindOptions::builder()
+            .projection(doc! { "address": 1, "tick": 1 })
+            .build();
+        let mut cursor = self
+            .find_with_retries(consts::COLLECTION_USER_BALANCES, None, Some(find_options))
+            .await?;
+        let mut unreplayed = Vec::new();
+        while let Some(result) = cursor.next().await {
+            let document = result?;
+            let key = (
+                document.get_str("address")?.to_string(),
+                document.get_str("tick")?.to_string(),
+            );
+            if !replayed.contains_key(&key) {
+                unreplayed.push(key);
+            }
+        }
+        for (address, tick) in &unreplayed {
+            self.delete_many_with_retries(
+                consts::COLLECTION_USER_BALANCES,
+                doc! { "address": address, "tick": tick },
+            )
+            .await?;
+        }
+
+        Ok((replayed.len(), unreplayed.len()))
+    }
 
+    pub async fn reset_tickers_total_minted(
+        &self,
+        block_height: i64,
+    ) -> Result<Vec<Document>, IndexerError> {
         let filter = doc! { "updated_at_block": { "$gte": block_height } };
 
-        let options = FindOptions::builder().build();
-        let mut cursor = collection.find(filter, options).await?;
+        let mut cursor = self
+            .find_with_retries(consts::COLLECTION_TICKERS, Some(filter), None)
+            .await?;
         let mut updated_tickers: Vec<Document> = Vec::new();
 
         // Iterate over the documents that match the filter
@@ -747,17 +1360,15 @@ impl MongoClient {
     pub async fn calculate_and_update_total_minted_for_ticker(
         &self,
         ticker_doc: &mut Document,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let db = self.client.database(&self.db_name);
-        let tickers_coll = db.collection::<bson::Document>(consts::COLLECTION_TICKERS);
-        let mints_coll = db.collection::<bson::Document>(consts::COLLECTION_MINTS);
-
+    ) -> Result<(), IndexerError> {
         let mut update_doc = ticker_doc.clone();
 
         let tick = ticker_doc.get_str("tick")?;
 
         let filter = doc! { "inscription.tick": tick };
-        let cursor = mints_coll.find(filter, None).await?;
+        let cursor = self
+            .find_with_retries(consts::COLLECTION_MINTS, Some(filter), None)
+            .await?;
         let mints: Vec<Document> = cursor.try_collect().await?;
 
         let total_minted: f64 = mints
@@ -768,7 +1379,8 @@ impl MongoClient {
         update_doc.insert("total_minted", total_minted);
         let update = doc! { "$set": update_doc};
         let filter = doc! { "tick": tick };
-        tickers_coll.update_one(filter, update, None).await?;
+        self.update_one_with_retries(consts::COLLECTION_TICKERS, filter, update, None)
+            .await?;
 
         Ok(())
     }
@@ -777,7 +1389,7 @@ impl MongoClient {
         &self,
         user_balance_docs_to_update: HashMap<(String, String), Document>,
         user_balance_docs_to_insert: HashMap<(String, String), Document>,
-    ) -> Result<(), anyhow::Error> {
+    ) -> Result<(), IndexerError> {
         let collection_name = consts::COLLECTION_USER_BALANCES;
 
         //time the process
@@ -797,7 +1409,7 @@ impl MongoClient {
             self.update_one_with_retries(collection_name, filter, update, None)
                 .await?;
         }
-        warn!("Updated {} user balances in: {:?}", len, start.elapsed());
+        debug!("Updated {} user balances in: {:?}", len, start.elapsed());
 
         // Insert new user balance documents using insert_many_with_retries
         let documents_to_insert: Vec<Document> =
@@ -914,3 +1526,68 @@ impl MongoClient {
     //     Ok(())
     // }
 }
+
+// Writes that hit a unique key were already made, like by an earlier attempt at the
+// same block, any other failure is returned
+fn skip_duplicates(collection_name: &str, e: IndexerError) -> Result<(), IndexerError> {
+    if !e.is_duplicate_key() {
+        return Err(e);
+    }
+    debug!("Skipped documents already stored in {}", collection_name);
+    Ok(())
+}
+
+// A unique index that can't be built because stored documents repeat a key, naming the key
+// the server reports, like { inscription_id: "<txid>i0" }
+fn duplicate_key_error(collection_name: &str, index_name: &str, e: &IndexerError) -> IndexerError {
+    let message = e.to_string();
+    let key = message
+        .split_once("dup key: ")
+        .and_then(|(_, rest)| rest.find('}').map(|end| &rest[..=end]))
+        .unwrap_or("unknown");
+
+    IndexerError::Config(format!(
+        "unique index {} of {} can't be built, stored documents repeat the key {}, \
+         remove the duplicates before indexing",
+        index_name, collection_name, key
+    ))
+}
+
+// The name MongoDB gives an index by default, like tx_id_1_vout_1
+fn index_name(keys: &Document) -> String {
+    keys.iter()
+        .map(|(key, direction)| format!("{}_{}", key, direction))
+        .collect::<Vec<_>>()
+        .join("_")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mongodb::error::ErrorKind;
+
+    #[test]
+    fn test_duplicate_key_error() {
+        let command_error = bson::from_document(doc! {
+            "code": 11000,
+            "codeName": "DuplicateKey",
+            "errmsg": "Index build failed: E11000 duplicate key error collection: \
+                       brc20.brc20_mints index: inscription_id_1 dup key: { inscription_id: \"abci0\" }",
+        })
+        .unwrap();
+        let e = IndexerError::from(mongodb::error::Error::from(ErrorKind::Command(
+            command_error,
+        )));
+        assert!(e.is_duplicate_key());
+
+        let keys = doc! { "inscription_id": 1 };
+        match duplicate_key_error(consts::COLLECTION_MINTS, &index_name(&keys), &e) {
+            IndexerError::Config(message) => assert_eq!(
+                message,
+                "unique index inscription_id_1 of brc20_mints can't be built, stored documents \
+                 repeat the key { inscription_id: \"abci0\" }, remove the duplicates before indexing"
+            ),
+            e => panic!("expected a config error, got {}", e),
+        }
+    }
+}
@@ -0,0 +1,196 @@
+use indicatif::{ProgressBar, ProgressStyle};
+use log::info;
+use mongodb::bson::{doc, Document};
+use std::collections::VecDeque;
+use std::io::{self, IsTerminal};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// The bar being drawn, log records are written around it
+static BAR: Mutex<Option<ProgressBar>> = Mutex::new(None);
+
+// Rates are averaged over this many blocks
+const RATE_WINDOW_BLOCKS: usize = 100;
+// How often the progress is logged when stderr isn't a terminal
+const LOG_INTERVAL: Duration = Duration::from_secs(60);
+
+// What was indexed in a block and how long it took, stored with the completed block
+#[derive(Debug, Default, Clone)]
+pub struct BlockSummary {
+    pub transactions: u32,
+    pub inscriptions: u32,
+    pub deploys: u32,
+    pub mints: u32,
+    pub transfers: u32,
+    pub sends: u32,
+    pub invalid: u32,
+    // Time spent on the transactions of the block
+    pub processing: Duration,
+    // Time spent writing the results to MongoDB
+    pub write: Duration,
+}
+
+impl BlockSummary {
+    pub fn to_document(&self) -> Document {
+        doc! {
+            "transactions": i64::from(self.transactions),
+            "inscriptions": i64::from(self.inscriptions),
+            "deploys": i64::from(self.deploys),
+            "mints": i64::from(self.mints),
+            "transfers": i64::from(self.transfers),
+            "sends": i64::from(self.sends),
+            "invalid": i64::from(self.invalid),
+            "processing_ms": self.processing.as_millis() as i64,
+            "write_ms": self.write.as_millis() as i64,
+        }
+    }
+}
+
+// Progress reports how far the indexer is behind the node's tip and how fast it catches up,
+// as a progress bar on a terminal or as a periodic log line otherwise
+pub struct Progress {
+    start_height: i64,
+    bar: Option<ProgressBar>,
+    // (time, blocks, inscriptions) with cumulative counts, oldest first
+    samples: VecDeque<(Instant, u64, u64)>,
+    blocks: u64,
+    inscriptions: u64,
+    last_log: Instant,
+}
+
+impl Progress {
+    pub fn new(start_height: i64) -> Self {
+        let bar = io::stderr().is_terminal().then(|| {
+            let bar = ProgressBar::new(0).with_style(
+                ProgressStyle::with_template("[{elapsed_precise}] [{wide_bar}] {msg}")
+                    .expect("valid template")
+                    .progress_chars("=> "),
+            );
+            *BAR.lock().unwrap_or_else(|e| e.into_inner()) = Some(bar.clone());
+            bar
+        });
+
+        Progress {
+            start_height,
+            bar,
+            samples: VecDeque::from([(Instant::now(), 0, 0)]),
+            blocks: 0,
+            inscriptions: 0,
+            last_log: Instant::now(),
+        }
+    }
+
+    pub fn block_completed(&mut self, height: i64, node_height: i64, summary: &BlockSummary) {
+        self.blocks += 1;
+        self.inscriptions += u64::from(summary.inscriptions);
+        self.samples
+            .push_back((Instant::now(), self.blocks, self.inscriptions));
+        if self.samples.len() > RATE_WINDOW_BLOCKS + 1 {
+            self.samples.pop_front();
+        }
+
+        let status = self.status(height, node_height);
+        match &self.bar {
+            Some(bar) => {
+                bar.set_length((node_height - self.start_height + 1).max(0) as u64);
+                bar.set_position((height - self.start_height + 1).max(0) as u64);
+                bar.set_message(status);
+            }
+            None if self.last_log.elapsed() >= LOG_INTERVAL => {
+                info!("Progress: {}", status);
+                self.last_log = Instant::now();
+            }
+            None => {}
+        }
+    }
+
+    // Blocks and inscriptions per second over the last blocks
+    fn rates(&self) -> Option<(f64, f64)> {
+        let (first, last) = (self.samples.front()?, self.samples.back()?);
+        let elapsed = last.0.duration_since(first.0).as_secs_f64();
+        if last.1 == first.1 || elapsed == 0.0 {
+            return None;
+        }
+
+        Some((
+            (last.1 - first.1) as f64 / elapsed,
+            (last.2 - first.2) as f64 / elapsed,
+        ))
+    }
+
+    fn status(&self, height: i64, node_height: i64) -> String {
+        let behind = (node_height - height).max(0);
+        let mut status = format!("block {} of {}", height, node_height);
+        if behind == 0 {
+            status.push_str(", following the tip");
+        } else {
+            status.push_str(&format!(" ({} behind)", behind));
+        }
+
+        if let Some((blocks_per_sec, inscriptions_per_sec)) = self.rates() {
+            status.push_str(&format!(
+                ", {:.2} blocks/s, {:.1} inscriptions/s",
+                blocks_per_sec, inscriptions_per_sec
+            ));
+            if behind > 0 {
+                let eta = Duration::from_secs_f64(behind as f64 / blocks_per_sec);
+                status.push_str(&format!(", ETA {}", format_duration(eta)));
+            }
+        }
+
+        status
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        if let Some(bar) = self.bar.take() {
+            bar.finish_and_clear();
+            *BAR.lock().unwrap_or_else(|e| e.into_inner()) = None;
+        }
+    }
+}
+
+// Runs `f` with the progress bar hidden, so that what it writes to the terminal isn't drawn over
+pub fn suspend<R>(f: impl FnOnce() -> R) -> R {
+    let bar = BAR.lock().unwrap_or_else(|e| e.into_inner()).clone();
+    match bar {
+        Some(bar) => bar.suspend(f),
+        None => f(),
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let (hours, minutes, secs) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if hours > 0 {
+        format!("{}h{:02}m", hours, minutes)
+    } else if minutes > 0 {
+        format!("{}m{:02}s", minutes, secs)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status() {
+        let mut progress = Progress::new(800_000);
+        let start = Instant::now() - Duration::from_secs(10);
+        progress.samples =
+            VecDeque::from([(start, 0, 0), (start + Duration::from_secs(10), 20, 1000)]);
+
+        assert_eq!(
+            progress.status(800_019, 807_219),
+            "block 800019 of 807219 (7200 behind), 2.00 blocks/s, 100.0 inscriptions/s, ETA 1h00m"
+        );
+        assert_eq!(
+            progress.status(807_219, 807_219),
+            "block 807219 of 807219, following the tip, 2.00 blocks/s, 100.0 inscriptions/s"
+        );
+        assert_eq!(format_duration(Duration::from_secs(75)), "1m15s");
+    }
+}
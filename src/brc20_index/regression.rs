@@ -0,0 +1,270 @@
+// Regression suite replaying historical BRC-20 operations through the validators.
+//
+// `invalid_txs/invalid_txs.json` holds known-invalid operations keyed by txid, each with the
+// reason the indexer recorded for it, which is compared by its stable code.
+// `invalid_txs/valid_txs.json` uses the same layout for known-valid operations, with an
+// optional expected `amt` instead of a reason (the minted or transferred amount, or the max
+// supply for deploys). Every case is replayed on its own against
+// `invalid_txs/fixture_state.json`, so a rule change that flips a historical result fails
+// here instead of silently changing the database. The valid mints and transfers are
+// synthetic cases built against the fixture state.
+use super::{deploy::Brc20Deploy, mint::Brc20Mint, transfer::Brc20Transfer, Brc20Inscription};
+use bitcoin::{Address, Network};
+use bitcoincore_rpc::bitcoincore_rpc_json::GetRawTransactionResult;
+use mongodb::bson::{doc, Document};
+use serde::Deserialize;
+use std::{collections::HashMap, fs, path::PathBuf, str::FromStr};
+
+// Every case is inscribed by this address, fixture balances should use it too
+const FIXTURE_OWNER: &str = "bc1qqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqq9e75rs";
+const FIXTURE_BLOCK_HEIGHT: u32 = 779832;
+
+#[derive(Deserialize)]
+struct FixtureTicker {
+    tick: String,
+    limit: f64,
+    max_supply: f64,
+    total_minted: f64,
+    decimals: u8,
+}
+
+#[derive(Deserialize)]
+struct FixtureUserBalance {
+    address: String,
+    tick: String,
+    overall_balance: f64,
+    available_balance: f64,
+    transferable_balance: f64,
+}
+
+#[derive(Deserialize)]
+struct FixtureState {
+    tickers: Vec<FixtureTicker>,
+    user_balances: Vec<FixtureUserBalance>,
+}
+
+impl FixtureState {
+    // Ticker documents in the same shape as Brc20Ticker::to_document
+    fn ticker_doc(&self, tick: &str) -> Option<Document> {
+        self.tickers
+            .iter()
+            .find(|ticker| ticker.tick == tick)
+            .map(|ticker| {
+                doc! {
+                    "tick": &ticker.tick,
+                    "limit": ticker.limit,
+                    "max_supply": ticker.max_supply,
+                    "decimals": ticker.decimals as i64,
+                    "total_minted": ticker.total_minted,
+                    "block_height": FIXTURE_BLOCK_HEIGHT,
+                }
+            })
+    }
+
+    // User balance documents in the same shape as UserBalance::to_document
+    fn user_balance_doc(&self, address: &str, tick: &str) -> Option<Document> {
+        self.user_balances
+            .iter()
+            .find(|balance| balance.address == address && balance.tick == tick)
+            .map(|balance| {
+                doc! {
+                    "address": &balance.address,
+                    "tick": &balance.tick,
+                    "overall_balance": balance.overall_balance,
+                    "available_balance": balance.available_balance,
+                    "transferable_balance": balance.transferable_balance,
+                    "block_height": FIXTURE_BLOCK_HEIGHT as i64,
+                }
+            })
+    }
+}
+
+#[derive(Deserialize)]
+struct InvalidCase {
+    tx_id: String,
+    inscription: Brc20Inscription,
+    reason: String,
+}
+
+#[derive(Deserialize)]
+struct ValidCase {
+    tx_id: String,
+    inscription: Brc20Inscription,
+    #[serde(default)]
+    amt: Option<f64>,
+}
+
+struct Outcome {
+    is_valid: bool,
+    amt: f64,
+    code: Option<String>,
+}
+
+// Maps the free text reasons recorded before InvalidReason existed to their codes
+fn legacy_code(reason: &str) -> &str {
+    match reason {
+        "Ticker not found" | "Ticker symbol does not exist" => "ticker_not_found",
+        "Ticker symbol already exists" => "ticker_already_exists",
+        "Ticker symbol must be 4 characters long" => "invalid_ticker_length",
+        "Mint amount exceeds limit" => "mint_exceeds_limit",
+        "Total minted is already at max supply" => "max_supply_reached",
+        "User balance not found" => "user_balance_not_found",
+        "Transfer amount exceeds available balance" => "insufficient_balance",
+        other => other,
+    }
+}
+
+fn fixture_path(file_name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("invalid_txs")
+        .join(file_name)
+}
+
+fn load_json<T: serde::de::DeserializeOwned>(file_name: &str) -> T {
+    let path = fixture_path(file_name);
+    let data = fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("failed to read {}: {}", path.display(), e));
+    serde_json::from_str(&data)
+        .unwrap_or_else(|e| panic!("failed to parse {}: {}", path.display(), e))
+}
+
+fn fixture_raw_tx(tx_id: &str) -> GetRawTransactionResult {
+    serde_json::from_value(serde_json::json!({
+        "hex": "",
+        "txid": tx_id,
+        "hash": tx_id,
+        "size": 0,
+        "vsize": 0,
+        "version": 1,
+        "locktime": 0,
+        "vin": [],
+        "vout": [],
+    }))
+    .unwrap_or_else(|e| panic!("invalid fixture txid {}: {}", tx_id, e))
+}
+
+fn fixture_owner() -> Address {
+    Address::from_str(FIXTURE_OWNER)
+        .unwrap()
+        .require_network(Network::Bitcoin)
+        .unwrap()
+}
+
+// Runs a single inscription through the validator for its operation
+async fn replay(state: &FixtureState, tx_id: &str, inscription: &Brc20Inscription) -> Outcome {
+    let raw_tx = fixture_raw_tx(tx_id);
+    let owner = fixture_owner();
+    let tick = inscription.tick.to_lowercase();
+    let ticker_doc = state.ticker_doc(&tick);
+    let mut invalid_brc20_docs = Vec::new();
+
+    let (is_valid, amt) = match &inscription.op[..] {
+        "deploy" => {
+            let deploy =
+                Brc20Deploy::new(&raw_tx, inscription.clone(), FIXTURE_BLOCK_HEIGHT, 0, owner)
+                    .validate_deploy_script(ticker_doc.is_some(), &mut invalid_brc20_docs)
+                    .await
+                    .unwrap();
+            (deploy.is_valid(), deploy.get_max_supply())
+        }
+        "mint" => {
+            let mint = Brc20Mint::new(
+                &raw_tx,
+                inscription.clone(),
+                format!("{}i0", tx_id),
+                FIXTURE_BLOCK_HEIGHT,
+                0,
+                owner,
+            )
+            .validate_mint(ticker_doc.as_ref(), &mut invalid_brc20_docs)
+            .await
+            .unwrap();
+            (mint.is_valid(), mint.amt)
+        }
+        "transfer" => {
+            let mut user_balance = state.user_balance_doc(FIXTURE_OWNER, &tick);
+            let mut transfer = Brc20Transfer::new(
+                &raw_tx,
+                inscription.clone(),
+                format!("{}i0", tx_id),
+                FIXTURE_BLOCK_HEIGHT,
+                0,
+                owner,
+            );
+            // Missing tickers and balances are reported as errors after recording the invalid
+            let _ = transfer
+                .validate_inscribe_transfer(
+                    ticker_doc.as_ref(),
+                    user_balance.as_mut(),
+                    &mut None,
+                    &mut invalid_brc20_docs,
+                )
+                .await;
+            (transfer.is_valid(), transfer.amt)
+        }
+        op => panic!("unexpected operation {} in {}", op, tx_id),
+    };
+
+    let code = invalid_brc20_docs
+        .last()
+        .and_then(|doc| doc.get_str("code").ok())
+        .map(str::to_string);
+
+    Outcome {
+        is_valid,
+        amt,
+        code,
+    }
+}
+
+#[tokio::test]
+async fn invalid_txs_keep_their_reason() {
+    let state: FixtureState = load_json("fixture_state.json");
+    let cases: HashMap<String, InvalidCase> = load_json("invalid_txs.json");
+
+    let mut mismatches = Vec::new();
+    for case in cases.values() {
+        let outcome = replay(&state, &case.tx_id, &case.inscription).await;
+        let expected = legacy_code(&case.reason);
+        if outcome.is_valid || outcome.code.as_deref() != Some(expected) {
+            mismatches.push(format!(
+                "{}: expected {}, got valid={} code={:?}",
+                case.tx_id, expected, outcome.is_valid, outcome.code
+            ));
+        }
+    }
+
+    assert!(
+        mismatches.is_empty(),
+        "{} of {} invalid txs changed result:\n{}",
+        mismatches.len(),
+        cases.len(),
+        mismatches.join("\n")
+    );
+}
+
+#[tokio::test]
+async fn valid_txs_stay_valid() {
+    let state: FixtureState = load_json("fixture_state.json");
+    let cases: HashMap<String, ValidCase> = load_json("valid_txs.json");
+
+    let mut mismatches = Vec::new();
+    for case in cases.values() {
+        let outcome = replay(&state, &case.tx_id, &case.inscription).await;
+        let amt_matches = case.amt.is_none_or(|amt| amt == outcome.amt);
+        if !outcome.is_valid || outcome.code.is_some() || !amt_matches {
+            mismatches.push(format!(
+                "{}: expected valid with amt {:?}, got valid={} amt={} code={:?}",
+                case.tx_id, case.amt, outcome.is_valid, outcome.amt, outcome.code
+            ));
+        }
+    }
+
+    assert!(
+        mismatches.is_empty(),
+        "{} of {} valid txs changed result:\n{}",
+        mismatches.len(),
+        cases.len(),
+        mismatches.join("\n")
+    );
+}
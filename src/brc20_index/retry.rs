@@ -0,0 +1,282 @@
+use super::error::IndexerError;
+use log::{error, warn};
+use rand::Rng;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+// Counters shared by every retried call, read by retry_metrics()
+static RPC_METRICS: ClassMetrics = ClassMetrics::new();
+static STORAGE_METRICS: ClassMetrics = ClassMetrics::new();
+
+// RetryClass groups the calls a RetryPolicy is applied to, each class has its own metrics
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetryClass {
+    Rpc,     // Bitcoin Core RPC calls
+    Storage, // MongoDB reads and writes
+}
+
+impl RetryClass {
+    fn metrics(&self) -> &'static ClassMetrics {
+        match self {
+            RetryClass::Rpc => &RPC_METRICS,
+            RetryClass::Storage => &STORAGE_METRICS,
+        }
+    }
+}
+
+// RetryPolicy decides how often and how long a failed call is retried.
+// Only transient errors are retried, see IndexerError::is_transient.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    // Attempts including the first one, 0 means unlimited
+    pub max_attempts: u32,
+    // Backoff before the first retry
+    pub initial_backoff: Duration,
+    // Upper bound of the backoff between two attempts
+    pub max_backoff: Duration,
+    // Growth of the backoff after each attempt
+    pub multiplier: f64,
+    // Total time after which no more attempts are made
+    pub deadline: Option<Duration>,
+    // Whether transient RPC and MongoDB errors are retried
+    pub retry_rpc: bool,
+    pub retry_storage: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 10,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(60),
+            multiplier: 2.0,
+            deadline: Some(Duration::from_secs(600)),
+            retry_rpc: true,
+            retry_storage: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    // Whether an error of this class should be retried at all
+    pub fn is_retryable(&self, class: RetryClass, error: &IndexerError) -> bool {
+        let class_enabled = match class {
+            RetryClass::Rpc => self.retry_rpc,
+            RetryClass::Storage => self.retry_storage,
+        };
+        class_enabled && error.is_transient()
+    }
+
+    // The exponential backoff before the given retry (starting at 1), without jitter
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let factor = self.multiplier.powi(retry.saturating_sub(1).min(64) as i32);
+        let backoff = self.initial_backoff.as_secs_f64() * factor;
+        Duration::from_secs_f64(backoff.min(self.max_backoff.as_secs_f64()))
+    }
+
+    // The backoff with "full jitter", a random duration between 0 and the exponential backoff,
+    // so that concurrent callers don't retry in lockstep
+    pub fn backoff_with_jitter(&self, retry: u32) -> Duration {
+        let backoff = self.backoff(retry);
+        if backoff.is_zero() {
+            return backoff;
+        }
+        rand::thread_rng().gen_range(Duration::ZERO..=backoff)
+    }
+
+    // Runs the operation until it succeeds, fails with an error that isn't retryable,
+    // runs out of attempts or would exceed the deadline. The last error is returned.
+    pub async fn run<T, F, Fut>(
+        &self,
+        class: RetryClass,
+        operation: &str,
+        mut f: F,
+    ) -> Result<T, IndexerError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, IndexerError>>,
+    {
+        let metrics = class.metrics();
+        let start = Instant::now();
+        let mut attempt = 1;
+
+        loop {
+            metrics.calls.fetch_add(1, Ordering::Relaxed);
+
+            let e = match f().await {
+                Ok(value) => {
+                    if attempt > 1 {
+                        metrics.recovered.fetch_add(1, Ordering::Relaxed);
+                    }
+                    return Ok(value);
+                }
+                Err(e) => e,
+            };
+
+            if !self.is_retryable(class, &e) {
+                metrics.failed.fetch_add(1, Ordering::Relaxed);
+                return Err(e);
+            }
+
+            let backoff = self.backoff_with_jitter(attempt);
+            let out_of_attempts = self.max_attempts != 0 && attempt >= self.max_attempts;
+            let past_deadline = self
+                .deadline
+                .is_some_and(|deadline| start.elapsed() + backoff > deadline);
+
+            if out_of_attempts || past_deadline {
+                metrics.exhausted.fetch_add(1, Ordering::Relaxed);
+                error!(
+                    "{} failed after {} attempts in {:?}: {}",
+                    operation,
+                    attempt,
+                    start.elapsed(),
+                    e
+                );
+                return Err(e);
+            }
+
+            metrics.retries.fetch_add(1, Ordering::Relaxed);
+            warn!(
+                "{} attempt {} failed: {}. Retrying in {:?}...",
+                operation, attempt, e, backoff
+            );
+            tokio::time::sleep(backoff).await;
+            attempt += 1;
+        }
+    }
+}
+
+struct ClassMetrics {
+    calls: AtomicU64,
+    retries: AtomicU64,
+    recovered: AtomicU64,
+    exhausted: AtomicU64,
+    failed: AtomicU64,
+}
+
+impl ClassMetrics {
+    const fn new() -> Self {
+        ClassMetrics {
+            calls: AtomicU64::new(0),
+            retries: AtomicU64::new(0),
+            recovered: AtomicU64::new(0),
+            exhausted: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+        }
+    }
+
+    fn snapshot(&self) -> RetryClassMetrics {
+        RetryClassMetrics {
+            calls: self.calls.load(Ordering::Relaxed),
+            retries: self.retries.load(Ordering::Relaxed),
+            recovered: self.recovered.load(Ordering::Relaxed),
+            exhausted: self.exhausted.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// Totals since startup for one RetryClass
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RetryClassMetrics {
+    pub calls: u64,     // Attempts made, including retries
+    pub retries: u64,   // Attempts that were retried after a transient error
+    pub recovered: u64, // Calls that succeeded after at least one retry
+    pub exhausted: u64, // Calls that gave up after running out of attempts or time
+    pub failed: u64,    // Calls that failed with an error that isn't retried
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RetryMetrics {
+    pub rpc: RetryClassMetrics,
+    pub storage: RetryClassMetrics,
+}
+
+pub fn retry_metrics() -> RetryMetrics {
+    RetryMetrics {
+        rpc: RPC_METRICS.snapshot(),
+        storage: STORAGE_METRICS.snapshot(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    fn fast_policy(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(4),
+            ..RetryPolicy::default()
+        }
+    }
+
+    fn transient_error() -> IndexerError {
+        IndexerError::Rpc(bitcoincore_rpc::Error::Io(std::io::Error::new(
+            std::io::ErrorKind::ConnectionRefused,
+            "connection refused",
+        )))
+    }
+
+    #[test]
+    fn test_backoff_grows_up_to_max() {
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(5),
+            ..RetryPolicy::default()
+        };
+        assert_eq!(policy.backoff(1), Duration::from_secs(1));
+        assert_eq!(policy.backoff(2), Duration::from_secs(2));
+        assert_eq!(policy.backoff(3), Duration::from_secs(4));
+        assert_eq!(policy.backoff(4), Duration::from_secs(5));
+        assert!(policy.backoff_with_jitter(4) <= Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn test_run_retries_transient_errors() {
+        let attempts = Cell::new(0);
+        let result = fast_policy(5)
+            .run(RetryClass::Rpc, "test", || {
+                attempts.set(attempts.get() + 1);
+                let attempt = attempts.get();
+                async move {
+                    if attempt < 3 {
+                        Err(transient_error())
+                    } else {
+                        Ok(attempt)
+                    }
+                }
+            })
+            .await;
+
+        assert_eq!(result.unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_run_gives_up() {
+        let attempts = Cell::new(0);
+        let result: Result<(), _> = fast_policy(3)
+            .run(RetryClass::Rpc, "test", || {
+                attempts.set(attempts.get() + 1);
+                async { Err(transient_error()) }
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.get(), 3);
+
+        // Errors that aren't transient are returned right away
+        attempts.set(0);
+        let result: Result<(), _> = fast_policy(3)
+            .run(RetryClass::Rpc, "test", || {
+                attempts.set(attempts.get() + 1);
+                async { Err(IndexerError::Decode("bad script".to_string())) }
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.get(), 1);
+    }
+}
@@ -0,0 +1,574 @@
+use super::{
+    consts,
+    error::IndexerError,
+    mongo::MongoClient,
+    utils::{extract_and_process_witness_data, get_witness_data_from_tx},
+    ToDocument,
+};
+use bitcoin::{consensus, Transaction};
+use futures_util::stream::TryStreamExt;
+use log::{info, warn};
+use mongodb::bson::{doc, oid::ObjectId, Bson, DateTime, Document};
+use mongodb::options::{FindOneOptions, UpdateOptions};
+use std::collections::{HashMap, HashSet};
+
+// The document in indexer_metadata recording the schema version of the database
+const SCHEMA_ID: &str = "schema";
+
+// The collections of operations read from inscriptions and the field holding their txid
+const OPERATION_COLLECTIONS: [(&str, &str); 4] = [
+    (consts::COLLECTION_DEPLOYS, "tx.txid"),
+    (consts::COLLECTION_MINTS, "tx.txid"),
+    (consts::COLLECTION_TRANSFERS, "tx.txid"),
+    (consts::COLLECTION_INVALIDS, "tx_id"),
+];
+
+// Inscription ids written per update
+const INSCRIPTION_ID_BATCH_SIZE: usize = 1_000;
+
+// A change to the layout of the stored documents. Migrations run in order and only touch
+// documents they didn't convert yet, so one that was interrupted is simply run again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Migration {
+    pub version: i64,
+    pub description: &'static str,
+}
+
+pub const MIGRATIONS: [Migration; 3] = [
+    Migration {
+        version: 1,
+        description: "key deploys, mints, transfers and invalids by inscription id",
+    },
+    Migration {
+        version: 2,
+        description: "store the address, tick and amount of active transfers",
+    },
+    Migration {
+        version: 3,
+        description: "key active transfers by inscription id as well as outpoint",
+    },
+];
+
+// The schema version this indexer reads and writes
+pub const SCHEMA_VERSION: i64 = MIGRATIONS.len() as i64;
+
+impl Migration {
+    async fn apply(&self, mongo_client: &MongoClient) -> Result<u64, IndexerError> {
+        match self.version {
+            1 => add_inscription_ids(mongo_client).await,
+            2 => mongo_client.fill_active_transfers().await,
+            // The unique index on the inscription id and outpoint was created before the
+            // migrations ran, the one on the outpoint alone refuses a second inscription
+            3 => {
+                mongo_client
+                    .drop_index_if_exists(
+                        consts::COLLECTION_BRC20_ACTIVE_TRANSFERS,
+                        &doc! { "tx_id": 1, "vout": 1 },
+                    )
+                    .await?;
+                Ok(0)
+            }
+            version => Err(IndexerError::Config(format!(
+                "no migration to schema version {}",
+                version
+            ))),
+        }
+    }
+}
+
+// The schema version of the database and the migration that was interrupted, if any
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SchemaState {
+    pub version: i64,
+    pub migrating: Option<i64>,
+}
+
+impl SchemaState {
+    pub fn pending(&self) -> &'static [Migration] {
+        let applied = self.version.clamp(0, SCHEMA_VERSION) as usize;
+        &MIGRATIONS[applied..]
+    }
+
+    // A database written by a newer indexer can't be read or migrated back
+    fn ensure_supported(&self, db_name: &str) -> Result<(), IndexerError> {
+        if self.version > SCHEMA_VERSION {
+            return Err(IndexerError::Config(format!(
+                "database {} has schema version {}, this indexer only supports up to {}",
+                db_name, self.version, SCHEMA_VERSION
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Reads the schema version of the database, None if it was never indexed.
+///
+/// A database indexed before versions were recorded is version 0. Nothing is written, only
+/// `migrate` records the version of a new database.
+pub async fn schema_state(mongo_client: &MongoClient) -> Result<Option<SchemaState>, IndexerError> {
+    let stored = mongo_client
+        .find_one_with_retries(
+            consts::COLLECTION_INDEXER_METADATA,
+            doc! { "_id": SCHEMA_ID },
+            None,
+        )
+        .await?;
+    if let Some(document) = stored {
+        return Ok(Some(SchemaState {
+            version: document.get_i64("version")?,
+            migrating: document.get_i64("migrating").ok(),
+        }));
+    }
+
+    let indexed = mongo_client
+        .count_documents_with_retries(consts::COLLECTION_BLOCKS_COMPLETED, None)
+        .await?
+        > 0;
+    Ok(indexed.then_some(SchemaState {
+        version: 0,
+        migrating: None,
+    }))
+}
+
+// Refuses a database that was never indexed or isn't at the schema version of this indexer
+pub async fn check_schema(mongo_client: &MongoClient, db_name: &str) -> Result<(), IndexerError> {
+    let state = match schema_state(mongo_client).await? {
+        Some(state) => state,
+        None => {
+            return Err(IndexerError::Config(format!(
+                "database {} isn't indexed yet, run `index` first",
+                db_name
+            )))
+        }
+    };
+    state.ensure_supported(db_name)?;
+    if !state.pending().is_empty() {
+        return Err(IndexerError::Config(format!(
+            "database {} has schema version {} and needs {} migration(s) to {}, run `migrate` or `index`",
+            db_name,
+            state.version,
+            state.pending().len(),
+            SCHEMA_VERSION
+        )));
+    }
+    Ok(())
+}
+
+/// Brings the database to the schema version of this indexer, creating the indexes first
+/// so the migrations can rely on them. A new database is recorded at the current version.
+///
+/// Each migration is recorded as in progress before it starts and as the schema version once
+/// it's done, an interrupted one is resumed on the next run. Returns the migrations applied.
+pub async fn migrate(
+    mongo_client: &MongoClient,
+    db_name: &str,
+) -> Result<Vec<Migration>, IndexerError> {
+    let stored = schema_state(mongo_client).await?;
+    if let Some(state) = &stored {
+        state.ensure_supported(db_name)?;
+    }
+    mongo_client.create_indexes().await?;
+    let state = match stored {
+        Some(state) => state,
+        None => {
+            store_state(mongo_client, SCHEMA_VERSION, None).await?;
+            return Ok(Vec::new());
+        }
+    };
+
+    let pending = state.pending();
+    for migration in pending {
+        if state.migrating == Some(migration.version) {
+            warn!(
+                "Resuming interrupted migration {}: {}",
+                migration.version, migration.description
+            );
+        } else {
+            info!(
+                "Migrating to schema version {}: {}",
+                migration.version, migration.description
+            );
+        }
+        store_state(mongo_client, migration.version - 1, Some(migration.version)).await?;
+        let converted = migration.apply(mongo_client).await?;
+        store_state(mongo_client, migration.version, None).await?;
+        info!(
+            "Schema version {} reached, {} documents converted",
+            migration.version, converted
+        );
+    }
+
+    Ok(pending.to_vec())
+}
+
+async fn store_state(
+    mongo_client: &MongoClient,
+    version: i64,
+    migrating: Option<i64>,
+) -> Result<(), IndexerError> {
+    let update = doc! {
+        "$set": {
+            "version": version,
+            "migrating": migrating.map_or(Bson::Null, Bson::Int64),
+            "updated_at": Bson::DateTime(DateTime::now()),
+        }
+    };
+    mongo_client
+        .update_one_with_retries(
+            consts::COLLECTION_INDEXER_METADATA,
+            doc! { "_id": SCHEMA_ID },
+            update,
+            Some(UpdateOptions::builder().upsert(true).build()),
+        )
+        .await
+}
+
+/// Gives the operations without an inscription id the id of the envelope they were read from.
+///
+/// The only operation of a transaction is its first envelope. The operations of a transaction
+/// with several, in any of the collections, are matched to its envelopes by their inscription.
+/// A transaction only stored as invalid has no hex, its operations were inserted in the order
+/// of its envelopes.
+async fn add_inscription_ids(mongo_client: &MongoClient) -> Result<u64, IndexerError> {
+    let mut cursor = mongo_client
+        .aggregate_with_retries(consts::COLLECTION_DEPLOYS, shared_transactions_pipeline())
+        .await?;
+
+    let mut keyed = 0;
+    let mut batches: HashMap<String, Vec<(ObjectId, String)>> = HashMap::new();
+    while let Some(transaction) = cursor.try_next().await? {
+        let txid = transaction.get_str("_id")?;
+        let operations = transaction
+            .get_array("operations")?
+            .iter()
+            .filter_map(Bson::as_document)
+            .map(StoredOperation::from_document)
+            .collect::<Result<Vec<_>, _>>()?;
+        let envelopes = match operations
+            .iter()
+            .find(|operation| operation.collection_name != consts::COLLECTION_INVALIDS)
+        {
+            Some(operation) => load_envelopes(mongo_client, operation).await?,
+            None => None,
+        };
+
+        for (index, inscription_id) in number_operations(txid, &operations, envelopes.as_deref()) {
+            let operation = &operations[index];
+            let batch = batches
+                .entry(operation.collection_name.clone())
+                .or_default();
+            batch.push((operation.id, inscription_id));
+            if batch.len() >= INSCRIPTION_ID_BATCH_SIZE {
+                keyed +=
+                    set_inscription_ids(mongo_client, &operation.collection_name, batch).await?;
+                batch.clear();
+            }
+        }
+    }
+    for (collection_name, batch) in &batches {
+        keyed += set_inscription_ids(mongo_client, collection_name, batch).await?;
+    }
+    info!(
+        "{} operations of transactions with several keyed by inscription id",
+        keyed
+    );
+
+    // Every operation left is the only one of its transaction
+    for (collection_name, txid_field) in OPERATION_COLLECTIONS {
+        let single = mongo_client
+            .update_many_with_pipeline_with_retries(
+                collection_name,
+                doc! { "inscription_id": { "$exists": false } },
+                vec![doc! {
+                    "$set": {
+                        "inscription_id": { "$concat": [format!("${}", txid_field), "i0"] },
+                    }
+                }],
+            )
+            .await?;
+        info!("{} {} keyed by inscription id", single, collection_name);
+        keyed += single;
+    }
+
+    Ok(keyed)
+}
+
+// The transactions with several operations that aren't all keyed yet, with every operation of
+// them, keyed or not, across the collections
+fn shared_transactions_pipeline() -> Vec<Document> {
+    let project = |collection_name: &str, txid_field: &str| {
+        doc! {
+            "$project": {
+                "txid": format!("${}", txid_field),
+                "inscription": 1,
+                "inscription_id": 1,
+                "collection": { "$literal": collection_name },
+            }
+        }
+    };
+
+    let mut pipeline = Vec::new();
+    for (collection_name, txid_field) in OPERATION_COLLECTIONS {
+        if collection_name == consts::COLLECTION_DEPLOYS {
+            pipeline.push(project(collection_name, txid_field));
+        } else {
+            pipeline.push(doc! {
+                "$unionWith": {
+                    "coll": collection_name,
+                    "pipeline": [project(collection_name, txid_field)],
+                }
+            });
+        }
+    }
+    pipeline.push(doc! {
+        "$group": {
+            "_id": "$txid",
+            "operations": {
+                "$push": {
+                    "collection": "$collection",
+                    "id": "$_id",
+                    "inscription": "$inscription",
+                    "inscription_id": "$inscription_id",
+                }
+            },
+            "unkeyed": {
+                "$sum": {
+                    "$cond": [{ "$eq": [{ "$type": "$inscription_id" }, "missing"] }, 1, 0]
+                }
+            },
+        }
+    });
+    pipeline.push(doc! {
+        "$match": { "operations.1": { "$exists": true }, "unkeyed": { "$gt": 0 } }
+    });
+    pipeline
+}
+
+// An operation of a transaction with several, as the migration groups them
+#[derive(Debug, Clone, PartialEq)]
+struct StoredOperation {
+    collection_name: String,
+    id: ObjectId,
+    inscription: Option<Document>,
+    inscription_id: Option<String>,
+}
+
+impl StoredOperation {
+    fn from_document(document: &Document) -> Result<Self, IndexerError> {
+        Ok(StoredOperation {
+            collection_name: document.get_str("collection")?.to_string(),
+            id: document.get_object_id("id")?,
+            inscription: document.get_document("inscription").ok().cloned(),
+            inscription_id: document.get_str("inscription_id").ok().map(String::from),
+        })
+    }
+}
+
+// The inscriptions of the envelopes of a transaction, in order, from the hex stored with one of
+// its valid operations
+async fn load_envelopes(
+    mongo_client: &MongoClient,
+    operation: &StoredOperation,
+) -> Result<Option<Vec<Document>>, IndexerError> {
+    let stored = mongo_client
+        .find_one_with_retries(
+            &operation.collection_name,
+            doc! { "_id": operation.id },
+            Some(
+                FindOneOptions::builder()
+                    .projection(doc! { "tx.hex": 1 })
+                    .build(),
+            ),
+        )
+        .await?;
+    let hex = match stored
+        .as_ref()
+        .and_then(|stored| stored.get_document("tx").ok())
+        .and_then(|tx| tx.get_str("hex").ok())
+    {
+        Some(hex) => hex,
+        None => return Ok(None),
+    };
+
+    let bytes = hex::decode(hex).map_err(|e| IndexerError::Decode(e.to_string()))?;
+    let transaction: Transaction =
+        consensus::deserialize(&bytes).map_err(|e| IndexerError::Decode(e.to_string()))?;
+    Ok(Some(
+        get_witness_data_from_tx(&transaction)
+            .into_iter()
+            .filter_map(extract_and_process_witness_data)
+            .map(|inscription| inscription.to_document())
+            .collect(),
+    ))
+}
+
+/// Numbers the operations of a transaction, returning the position and the new id of the
+/// ones without an id.
+///
+/// An operation gets the first free envelope with its inscription. One that matches none, or
+/// of a transaction without envelopes, gets the lowest free index in insertion order.
+fn number_operations(
+    txid: &str,
+    operations: &[StoredOperation],
+    envelopes: Option<&[Document]>,
+) -> Vec<(usize, String)> {
+    let mut taken: HashSet<usize> = operations
+        .iter()
+        .filter_map(|operation| operation.inscription_id.as_deref())
+        .filter_map(|inscription_id| inscription_id.strip_prefix(txid)?.strip_prefix('i'))
+        .filter_map(|index| index.parse().ok())
+        .collect();
+    let mut unkeyed: Vec<usize> = (0..operations.len())
+        .filter(|&position| operations[position].inscription_id.is_none())
+        .collect();
+    // ObjectIds grow in the order the documents were inserted
+    unkeyed.sort_by_key(|&position| operations[position].id);
+
+    let mut numbered: Vec<(usize, usize)> = Vec::new();
+    let mut unmatched = Vec::new();
+    for position in unkeyed {
+        let matched = envelopes.and_then(|envelopes| {
+            (0..envelopes.len()).find(|index| {
+                !taken.contains(index)
+                    && operations[position].inscription.as_ref() == Some(&envelopes[*index])
+            })
+        });
+        match matched {
+            Some(index) => {
+                taken.insert(index);
+                numbered.push((position, index));
+            }
+            None => unmatched.push(position),
+        }
+    }
+    for position in unmatched {
+        if envelopes.is_some() {
+            warn!(
+                "No envelope of {} has the inscription of {} {}",
+                txid, operations[position].collection_name, operations[position].id
+            );
+        }
+        let index = (0..)
+            .find(|index| !taken.contains(index))
+            .unwrap_or_default();
+        taken.insert(index);
+        numbered.push((position, index));
+    }
+
+    numbered
+        .into_iter()
+        .map(|(position, index)| (position, format!("{}i{}", txid, index)))
+        .collect()
+}
+
+// Writes the inscription ids of a batch of documents in one update
+async fn set_inscription_ids(
+    mongo_client: &MongoClient,
+    collection_name: &str,
+    batch: &[(ObjectId, String)],
+) -> Result<u64, IndexerError> {
+    if batch.is_empty() {
+        return Ok(0);
+    }
+    let ids: Vec<ObjectId> = batch.iter().map(|(id, _)| *id).collect();
+    let branches: Vec<Document> = batch
+        .iter()
+        .map(|(id, inscription_id)| {
+            doc! { "case": { "$eq": ["$_id", id] }, "then": inscription_id }
+        })
+        .collect();
+
+    mongo_client
+        .update_many_with_pipeline_with_retries(
+            collection_name,
+            doc! { "_id": { "$in": ids } },
+            vec![doc! { "$set": { "inscription_id": { "$switch": { "branches": branches } } } }],
+        )
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pending_migrations() {
+        let state = |version| SchemaState {
+            version,
+            migrating: None,
+        };
+
+        assert_eq!(state(0).pending(), &MIGRATIONS);
+        assert_eq!(state(1).pending(), &MIGRATIONS[1..]);
+        assert!(state(SCHEMA_VERSION).pending().is_empty());
+        assert!(state(SCHEMA_VERSION).ensure_supported("brc20").is_ok());
+        assert!(state(SCHEMA_VERSION + 1).ensure_supported("brc20").is_err());
+
+        for (index, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version, index as i64 + 1);
+        }
+    }
+    #[test]
+    fn test_number_operations() {
+        let inscription = |op: &str, amt: &str| doc! { "op": op, "tick": "ordi", "amt": amt };
+        let operation =
+            |collection_name: &str, inscription: Document, inscription_id: Option<&str>| {
+                StoredOperation {
+                    collection_name: collection_name.to_string(),
+                    id: ObjectId::new(),
+                    inscription: Some(inscription),
+                    inscription_id: inscription_id.map(String::from),
+                }
+            };
+
+        // Mints were inserted before invalids, the invalid mint is still the first envelope
+        let operations = vec![
+            operation(consts::COLLECTION_MINTS, inscription("mint", "1"), None),
+            operation(consts::COLLECTION_INVALIDS, inscription("mint", "2"), None),
+        ];
+        let envelopes = [inscription("mint", "2"), inscription("mint", "1")];
+        assert_eq!(
+            number_operations("aa", &operations, Some(&envelopes)),
+            vec![(0, "aai1".to_string()), (1, "aai0".to_string())]
+        );
+
+        // Equal inscriptions take their envelopes in insertion order, after the keyed ones
+        let operations = vec![
+            operation(
+                consts::COLLECTION_MINTS,
+                inscription("mint", "1"),
+                Some("aai1"),
+            ),
+            operation(consts::COLLECTION_MINTS, inscription("mint", "1"), None),
+            operation(consts::COLLECTION_INVALIDS, inscription("mint", "9"), None),
+        ];
+        let envelopes = [
+            inscription("mint", "1"),
+            inscription("mint", "1"),
+            inscription("mint", "1"),
+        ];
+        assert_eq!(
+            number_operations("aa", &operations, Some(&envelopes)),
+            vec![(1, "aai0".to_string()), (2, "aai2".to_string())]
+        );
+
+        // Without envelopes the lowest free index goes in insertion order
+        let operations = vec![
+            operation(
+                consts::COLLECTION_INVALIDS,
+                inscription("mint", "1"),
+                Some("bbi0"),
+            ),
+            operation(consts::COLLECTION_INVALIDS, inscription("mint", "1"), None),
+            operation(
+                consts::COLLECTION_INVALIDS,
+                inscription("deploy", "1"),
+                None,
+            ),
+        ];
+        assert_eq!(
+            number_operations("bb", &operations, None),
+            vec![(1, "bbi1".to_string()), (2, "bbi2".to_string())]
+        );
+    }
+}
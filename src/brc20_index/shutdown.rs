@@ -0,0 +1,110 @@
+use super::error::IndexerError;
+use log::warn;
+use tokio::sync::watch;
+
+// Shutdown tracks SIGINT and SIGTERM. The first signal asks the indexer to stop after
+// the current block, a second one to abandon the current block right away.
+#[derive(Clone)]
+pub struct Shutdown {
+    signals: watch::Receiver<u32>,
+}
+
+impl Shutdown {
+    // Starts listening for signals in the background
+    pub fn listen() -> Result<Self, IndexerError> {
+        let (sender, signals) = watch::channel(0);
+
+        #[cfg(unix)]
+        let mut terminate =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+                .map_err(|e| IndexerError::Config(format!("Can't listen for SIGTERM: {}", e)))?;
+
+        tokio::spawn(async move {
+            loop {
+                #[cfg(unix)]
+                let signal = tokio::select! {
+                    result = tokio::signal::ctrl_c() => result.map(|_| "SIGINT"),
+                    _ = terminate.recv() => Ok("SIGTERM"),
+                };
+                #[cfg(not(unix))]
+                let signal = tokio::signal::ctrl_c().await.map(|_| "SIGINT");
+
+                let signal = match signal {
+                    Ok(signal) => signal,
+                    Err(e) => {
+                        warn!("Stopped listening for signals: {}", e);
+                        return;
+                    }
+                };
+
+                sender.send_modify(|count| *count += 1);
+                if *sender.borrow() == 1 {
+                    warn!(
+                        "Received {}, stopping after the current block. Send it again to abandon the block.",
+                        signal
+                    );
+                } else {
+                    warn!("Received {} again, abandoning the current block", signal);
+                }
+            }
+        });
+
+        Ok(Shutdown { signals })
+    }
+
+    pub fn is_requested(&self) -> bool {
+        *self.signals.borrow() > 0
+    }
+
+    // Resolves once stopping after the current block was requested
+    pub async fn requested(&self) {
+        self.wait_for_signals(1).await
+    }
+
+    // Resolves once abandoning the current block was requested
+    pub async fn forced(&self) {
+        self.wait_for_signals(2).await
+    }
+
+    async fn wait_for_signals(&self, count: u32) {
+        let mut signals = self.signals.clone();
+        if signals
+            .wait_for(|received| *received >= count)
+            .await
+            .is_err()
+        {
+            // The listener is gone, no more signals will arrive
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_sigterm_requests_then_forces_shutdown() {
+        let shutdown = Shutdown::listen().unwrap();
+        assert!(!shutdown.is_requested());
+
+        let terminate = || {
+            std::process::Command::new("kill")
+                .args(["-TERM", &std::process::id().to_string()])
+                .status()
+                .unwrap()
+        };
+
+        terminate();
+        tokio::time::timeout(Duration::from_secs(5), shutdown.requested())
+            .await
+            .unwrap();
+        assert!(shutdown.is_requested());
+
+        terminate();
+        tokio::time::timeout(Duration::from_secs(5), shutdown.forced())
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,669 @@
+use super::{
+    brc20_ticker::Brc20Ticker,
+    consts,
+    deploy::Brc20Deploy,
+    error::{ErrorAction, IndexerError},
+    mint::Brc20Mint,
+    mongo::MongoClient,
+    resolve_sends,
+    transfer::{active_transfers_at, ActiveTransfers, Brc20Transfer},
+    utils::{
+        extract_and_process_witness_data, get_owner_of_vout, get_witness_data_from_raw_tx,
+        raw_tx_from_transaction,
+    },
+    ToDocument,
+};
+use crate::config::Config;
+use bitcoin::psbt::PartiallySignedTransaction;
+use bitcoin::{consensus, OutPoint, Transaction};
+use bitcoincore_rpc::bitcoincore_rpc_json::GetRawTransactionResult;
+use bitcoincore_rpc::Client;
+use log::debug;
+use mongodb::bson::{doc, Document};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+
+const OPS: [&str; 5] = ["deploy", "mint", "transfer", "send", "receive"];
+// Serialized PSBTs start with "psbt" and 0xff
+const PSBT_MAGIC: [u8; 5] = [0x70, 0x73, 0x62, 0x74, 0xff];
+
+// What a transaction does to the balance of one address in one tick, as simulated.
+// op is deploy, mint, transfer, send or receive.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Effect {
+    pub txid: String,
+    pub op: &'static str,
+    pub tick: String,
+    pub address: String,
+    pub amt: f64,
+    // (available_balance, transferable_balance, overall_balance) changes
+    pub delta: (f64, f64, f64),
+    // The output a sent transfer inscription leaves or lands in
+    pub outpoint: Option<String>,
+    // Why the operation is invalid, empty for valid ones
+    pub invalid_reasons: Vec<String>,
+}
+
+impl Effect {
+    fn valid(
+        txid: &str,
+        op: &'static str,
+        tick: &str,
+        address: &str,
+        amt: f64,
+        delta: (f64, f64, f64),
+    ) -> Self {
+        Effect {
+            txid: txid.to_string(),
+            op,
+            tick: tick.to_string(),
+            address: address.to_string(),
+            amt,
+            delta,
+            outpoint: None,
+            invalid_reasons: Vec::new(),
+        }
+    }
+
+    fn at(self, outpoint: Option<String>) -> Self {
+        Effect { outpoint, ..self }
+    }
+
+    fn invalid(
+        txid: &str,
+        op: &'static str,
+        tick: &str,
+        address: &str,
+        invalid_reasons: Vec<String>,
+    ) -> Self {
+        Effect {
+            invalid_reasons,
+            ..Effect::valid(txid, op, tick, address, 0.0, (0.0, 0.0, 0.0))
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.invalid_reasons.is_empty()
+    }
+}
+
+impl ToDocument for Effect {
+    fn to_document(&self) -> Document {
+        doc! {
+            "txid": &self.txid,
+            "op": self.op,
+            "tick": &self.tick,
+            "address": &self.address,
+            "amt": self.amt,
+            consts::AVAILABLE_BALANCE: self.delta.0,
+            consts::TRANSFERABLE_BALANCE: self.delta.1,
+            consts::OVERALL_BALANCE: self.delta.2,
+            "outpoint": &self.outpoint,
+            "is_valid": self.is_valid(),
+            "invalid_reasons": &self.invalid_reasons,
+        }
+    }
+}
+
+impl TryFrom<&Document> for Effect {
+    type Error = IndexerError;
+
+    fn try_from(document: &Document) -> Result<Self, Self::Error> {
+        let op = document.get_str("op")?;
+        let op = OPS
+            .into_iter()
+            .find(|known| *known == op)
+            .ok_or_else(|| IndexerError::Decode(format!("Unknown op: {}", op)))?;
+
+        Ok(Effect {
+            txid: document.get_str("txid")?.to_string(),
+            op,
+            tick: document.get_str("tick")?.to_string(),
+            address: document.get_str("address")?.to_string(),
+            amt: document.get_f64("amt")?,
+            delta: (
+                document.get_f64(consts::AVAILABLE_BALANCE)?,
+                document.get_f64(consts::TRANSFERABLE_BALANCE)?,
+                document.get_f64(consts::OVERALL_BALANCE)?,
+            ),
+            outpoint: document.get_str("outpoint").ok().map(String::from),
+            invalid_reasons: document
+                .get_array("invalid_reasons")?
+                .iter()
+                .filter_map(|reason| reason.as_str().map(String::from))
+                .collect(),
+        })
+    }
+}
+
+impl fmt::Display for Effect {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.is_valid() {
+            return write!(
+                f,
+                "invalid {} of {} by {} in {}: {}",
+                self.op,
+                self.tick,
+                self.address,
+                self.txid,
+                self.invalid_reasons.join(", ")
+            );
+        }
+
+        let (available, transferable, overall) = self.delta;
+        write!(
+            f,
+            "{} {} {} for {} in {}: available {:+}, transferable {:+}, overall {:+}",
+            self.op, self.amt, self.tick, self.address, self.txid, available, transferable, overall
+        )?;
+        match &self.outpoint {
+            Some(outpoint) => write!(f, " at {}", outpoint),
+            None => Ok(()),
+        }
+    }
+}
+
+// The reasons the validators recorded, or the error if they didn't get to record one
+fn invalid_reasons(invalid_brc20_docs: &[Document], error: Option<&IndexerError>) -> Vec<String> {
+    let mut reasons: Vec<String> = invalid_brc20_docs
+        .iter()
+        .filter_map(|invalid| invalid.get_str("reason").ok())
+        .map(String::from)
+        .collect();
+    if reasons.is_empty() {
+        reasons.extend(error.map(ToString::to_string));
+    }
+    reasons
+}
+
+/// Simulator applies transactions on top of the indexed state, in memory.
+///
+/// It runs the same validators as the indexer and keeps the state they change, so each
+/// simulated transaction sees the effects of the ones simulated before it. Nothing is written.
+pub struct Simulator<'a> {
+    rpc: &'a Client,
+    mongo_client: &'a MongoClient,
+    config: &'a Config,
+    // None records that a ticker or balance doesn't exist
+    tickers: HashMap<String, Option<Document>>,
+    balances: HashMap<(String, String), Option<Document>>,
+    active_transfers: Option<ActiveTransfers>,
+}
+
+impl<'a> Simulator<'a> {
+    pub async fn new(
+        rpc: &'a Client,
+        mongo_client: &'a MongoClient,
+        config: &'a Config,
+    ) -> Result<Simulator<'a>, IndexerError> {
+        let active_transfers = mongo_client.load_active_transfers_with_retry().await?;
+
+        Ok(Simulator {
+            rpc,
+            mongo_client,
+            config,
+            tickers: HashMap::new(),
+            balances: HashMap::new(),
+            active_transfers,
+        })
+    }
+
+    // Whether spending the outpoint sends a transfer inscription
+    pub fn is_active_transfer(&self, outpoint: &OutPoint) -> bool {
+        self.active_transfers
+            .as_ref()
+            .is_some_and(|active_transfers| {
+                !active_transfers_at(
+                    active_transfers,
+                    &outpoint.txid.to_string(),
+                    i64::from(outpoint.vout),
+                )
+                .is_empty()
+            })
+    }
+
+    /// Simulates a transaction and returns its effects, in the order the indexer would apply them.
+    ///
+    /// Like the indexer, a transaction with a valid inscription isn't checked for transfer sends.
+    pub async fn simulate(
+        &mut self,
+        raw_tx: &GetRawTransactionResult,
+    ) -> Result<Vec<Effect>, IndexerError> {
+        let txid = raw_tx.txid.to_string();
+        let transaction = raw_tx.transaction()?;
+        let mut effects = Vec::new();
+
+        let witness_data = match get_witness_data_from_raw_tx(raw_tx) {
+            Ok(data) => data,
+            Err(e) => {
+                debug!("Failed to get witness data of {}: {}", txid, e);
+                Vec::new()
+            }
+        };
+
+        let mut inscription_found = false;
+        let inscriptions = witness_data
+            .into_iter()
+            .filter_map(extract_and_process_witness_data);
+        for (inscription_index, inscription) in inscriptions.enumerate() {
+            // Numbered like the indexer numbers them
+            let inscription_id = format!("{}i{}", txid, inscription_index);
+            let owner = match get_owner_of_vout(raw_tx, 0, self.config.network) {
+                Ok(owner) => owner,
+                Err(e) => {
+                    debug!("Failed to get owner of {}: {}", txid, e);
+                    continue;
+                }
+            };
+            let tick = inscription.tick.to_lowercase();
+            let owner_address = owner.to_string();
+            let mut invalid_brc20_docs = Vec::new();
+
+            match &inscription.op[..] {
+                "deploy" => {
+                    let exists = self.ticker(&tick).await?.is_some();
+                    let deploy = Brc20Deploy::new(raw_tx, inscription, 0, 0, owner)
+                        .validate_deploy_script(exists, &mut invalid_brc20_docs)
+                        .await?;
+                    inscription_found = deploy.is_valid();
+                    if inscription_found {
+                        let ticker = Brc20Ticker::new(deploy).to_document();
+                        self.tickers.insert(tick.clone(), Some(ticker));
+                        effects.push(Effect::valid(
+                            &txid,
+                            "deploy",
+                            &tick,
+                            &owner_address,
+                            0.0,
+                            (0.0, 0.0, 0.0),
+                        ));
+                    } else {
+                        effects.push(Effect::invalid(
+                            &txid,
+                            "deploy",
+                            &tick,
+                            &owner_address,
+                            invalid_reasons(&invalid_brc20_docs, None),
+                        ));
+                    }
+                }
+                "mint" => {
+                    let ticker = self.ticker(&tick).await?;
+                    let mint = Brc20Mint::new(raw_tx, inscription, inscription_id, 0, 0, owner)
+                        .validate_mint(ticker.as_ref(), &mut invalid_brc20_docs)
+                        .await;
+                    match mint {
+                        Ok(mint) if mint.is_valid() => {
+                            inscription_found = true;
+                            if let Some(Some(ticker)) = self.tickers.get_mut(&tick) {
+                                let total_minted =
+                                    ticker.get_f64("total_minted").unwrap_or_default();
+                                ticker.insert("total_minted", total_minted + mint.amt);
+                            }
+                            let to = mint.to.to_string();
+                            let delta = (mint.amt, 0.0, mint.amt);
+                            self.apply(&to, &tick, delta).await?;
+                            effects.push(Effect::valid(&txid, "mint", &tick, &to, mint.amt, delta));
+                        }
+                        Ok(_) => effects.push(Effect::invalid(
+                            &txid,
+                            "mint",
+                            &tick,
+                            &owner_address,
+                            invalid_reasons(&invalid_brc20_docs, None),
+                        )),
+                        Err(e) if e.action() == ErrorAction::Skip => effects.push(Effect::invalid(
+                            &txid,
+                            "mint",
+                            &tick,
+                            &owner_address,
+                            invalid_reasons(&invalid_brc20_docs, Some(&e)),
+                        )),
+                        Err(e) => return Err(e),
+                    }
+                }
+                "transfer" => {
+                    let ticker = self.ticker(&tick).await?;
+                    let mut balance = match ticker {
+                        Some(_) => self.balance(&owner_address, &tick).await?,
+                        None => None,
+                    };
+                    let mut transfer =
+                        Brc20Transfer::new(raw_tx, inscription, inscription_id, 0, 0, owner);
+                    let result = transfer
+                        .validate_inscribe_transfer(
+                            ticker.as_ref(),
+                            balance.as_mut(),
+                            &mut self.active_transfers,
+                            &mut invalid_brc20_docs,
+                        )
+                        .await;
+                    match result {
+                        Ok(_) if transfer.is_valid() => {
+                            inscription_found = true;
+                            // The validator moved the amount from available to transferable
+                            self.balances
+                                .insert((owner_address.clone(), tick.clone()), balance);
+                            effects.push(Effect::valid(
+                                &txid,
+                                "transfer",
+                                &tick,
+                                &owner_address,
+                                transfer.amt,
+                                (-transfer.amt, transfer.amt, 0.0),
+                            ));
+                        }
+                        Ok(_) => effects.push(Effect::invalid(
+                            &txid,
+                            "transfer",
+                            &tick,
+                            &owner_address,
+                            invalid_reasons(&invalid_brc20_docs, None),
+                        )),
+                        Err(e) if e.action() == ErrorAction::Skip => effects.push(Effect::invalid(
+                            &txid,
+                            "transfer",
+                            &tick,
+                            &owner_address,
+                            invalid_reasons(&invalid_brc20_docs, Some(&e)),
+                        )),
+                        Err(e) => return Err(e),
+                    }
+                }
+                op => debug!("Unexpected operation {} in {}", op, txid),
+            }
+        }
+
+        if inscription_found {
+            return Ok(effects);
+        }
+
+        let sends = match &self.active_transfers {
+            Some(active_transfers) => {
+                resolve_sends(
+                    self.rpc,
+                    self.config,
+                    raw_tx,
+                    &transaction,
+                    active_transfers,
+                )
+                .await?
+            }
+            None => Vec::new(),
+        };
+
+        for send in sends {
+            if let Some(active_transfers) = self.active_transfers.as_mut() {
+                active_transfers.remove(&send.active_transfer.key());
+            }
+            let (sent, received) = send.deltas();
+            let from = &send.active_transfer.address;
+            let tick = &send.active_transfer.tick;
+            let amt = send.active_transfer.amt;
+            self.apply(from, tick, sent).await?;
+            self.apply(&send.receiver, tick, received).await?;
+            // The transfer inscription leaves the spent output for the receiving one,
+            // there is none when it's spent as fee
+            effects.push(
+                Effect::valid(&txid, "send", tick, from, amt, sent).at(Some(
+                    transaction.input[send.input_index]
+                        .previous_output
+                        .to_string(),
+                )),
+            );
+            effects.push(
+                Effect::valid(&txid, "receive", tick, &send.receiver, amt, received)
+                    .at(send.receiver_vout.map(|vout| format!("{}:{}", txid, vout))),
+            );
+        }
+
+        Ok(effects)
+    }
+
+    async fn ticker(&mut self, tick: &str) -> Result<Option<Document>, IndexerError> {
+        if let Some(ticker) = self.tickers.get(tick) {
+            return Ok(ticker.clone());
+        }
+
+        let ticker = self
+            .mongo_client
+            .get_document_by_field(consts::COLLECTION_TICKERS, "tick", tick)
+            .await?;
+        self.tickers.insert(tick.to_string(), ticker.clone());
+        Ok(ticker)
+    }
+
+    async fn balance(
+        &mut self,
+        address: &str,
+        tick: &str,
+    ) -> Result<Option<Document>, IndexerError> {
+        let key = (address.to_string(), tick.to_string());
+        if let Some(balance) = self.balances.get(&key) {
+            return Ok(balance.clone());
+        }
+
+        let balance = self.mongo_client.load_user_balance_with_retry(&key).await?;
+        self.balances.insert(key, balance.clone());
+        Ok(balance)
+    }
+
+    // Adds (available, transferable, overall) to a balance, creating it if needed
+    async fn apply(
+        &mut self,
+        address: &str,
+        tick: &str,
+        delta: (f64, f64, f64),
+    ) -> Result<(), IndexerError> {
+        let mut balance = self.balance(address, tick).await?.unwrap_or_else(|| {
+            doc! {
+                "address": address,
+                "tick": tick,
+                consts::AVAILABLE_BALANCE: 0.0,
+                consts::TRANSFERABLE_BALANCE: 0.0,
+                consts::OVERALL_BALANCE: 0.0,
+            }
+        });
+        for (field, change) in [
+            (consts::AVAILABLE_BALANCE, delta.0),
+            (consts::TRANSFERABLE_BALANCE, delta.1),
+            (consts::OVERALL_BALANCE, delta.2),
+        ] {
+            let value = balance.get_f64(field).unwrap_or_default();
+            balance.insert(field, value + change);
+        }
+
+        self.balances
+            .insert((address.to_string(), tick.to_string()), Some(balance));
+        Ok(())
+    }
+}
+
+// What a transaction would do if it was broadcast now: its effects in order and the resulting
+// change of every balance it touches
+#[derive(Debug, Clone, Serialize)]
+pub struct DryRun {
+    pub txid: String,
+    pub events: Vec<Effect>,
+    pub deltas: Vec<BalanceDelta>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BalanceDelta {
+    pub address: String,
+    pub tick: String,
+    pub available: f64,
+    pub transferable: f64,
+    pub overall: f64,
+}
+
+/// Decodes a transaction given as hex, or a PSBT given as hex or base64.
+///
+/// A PSBT is simulated as the transaction it finalizes to, so inscriptions in inputs that aren't
+/// finalized yet aren't seen. Transfer sends only depend on the spent outputs and are.
+pub fn parse_transaction(input: &str) -> Result<Transaction, IndexerError> {
+    let input = input.trim();
+
+    if let Ok(bytes) = hex::decode(input) {
+        if bytes.starts_with(&PSBT_MAGIC) {
+            let psbt = PartiallySignedTransaction::deserialize(&bytes)
+                .map_err(|e| IndexerError::Decode(format!("Invalid PSBT: {}", e)))?;
+            return Ok(psbt.extract_tx());
+        }
+        return consensus::deserialize(&bytes)
+            .map_err(|e| IndexerError::Decode(format!("Invalid transaction: {}", e)));
+    }
+
+    input
+        .parse::<PartiallySignedTransaction>()
+        .map(PartiallySignedTransaction::extract_tx)
+        .map_err(|e| {
+            IndexerError::Decode(format!(
+                "Expected a transaction or PSBT in hex or a PSBT in base64: {}",
+                e
+            ))
+        })
+}
+
+// Simulates a transaction on top of the indexed state without writing anything
+pub async fn dry_run(
+    rpc: &Client,
+    mongo_client: &MongoClient,
+    config: &Config,
+    transaction: &Transaction,
+) -> Result<DryRun, IndexerError> {
+    let raw_tx = raw_tx_from_transaction(transaction);
+    let events = Simulator::new(rpc, mongo_client, config)
+        .await?
+        .simulate(&raw_tx)
+        .await?;
+
+    Ok(DryRun {
+        txid: raw_tx.txid.to_string(),
+        deltas: balance_deltas(&events),
+        events,
+    })
+}
+
+// Sums the effects per address and tick, in the order they are first touched
+fn balance_deltas(effects: &[Effect]) -> Vec<BalanceDelta> {
+    let mut deltas: Vec<BalanceDelta> = Vec::new();
+
+    for effect in effects.iter().filter(|effect| effect.is_valid()) {
+        let index = match deltas
+            .iter()
+            .position(|delta| delta.address == effect.address && delta.tick == effect.tick)
+        {
+            Some(index) => index,
+            None => {
+                deltas.push(BalanceDelta {
+                    address: effect.address.clone(),
+                    tick: effect.tick.clone(),
+                    available: 0.0,
+                    transferable: 0.0,
+                    overall: 0.0,
+                });
+                deltas.len() - 1
+            }
+        };
+        let delta = &mut deltas[index];
+        delta.available += effect.delta.0;
+        delta.transferable += effect.delta.1;
+        delta.overall += effect.delta.2;
+    }
+
+    deltas
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_effect_document_round_trip() {
+        let send = Effect::valid(
+            "txid",
+            "send",
+            "ordi",
+            "bc1qsender",
+            10.0,
+            (0.0, -10.0, -10.0),
+        )
+        .at(Some("transfer:0".to_string()));
+        assert_eq!(Effect::try_from(&send.to_document()).unwrap(), send);
+        assert_eq!(
+            send.to_string(),
+            "send 10 ordi for bc1qsender in txid: available +0, transferable -10, overall -10 at transfer:0"
+        );
+
+        let mint = Effect::invalid(
+            "txid",
+            "mint",
+            "ordi",
+            "bc1qminter",
+            vec!["max supply reached".to_string()],
+        );
+        assert_eq!(Effect::try_from(&mint.to_document()).unwrap(), mint);
+        assert!(!mint.is_valid());
+    }
+
+    #[test]
+    fn test_parse_transaction() {
+        use bitcoin::absolute::LockTime;
+        use bitcoin::{ScriptBuf, Sequence, TxIn, TxOut, Witness};
+
+        let transaction = Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::default(),
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            output: vec![TxOut {
+                value: 546,
+                script_pubkey: ScriptBuf::new(),
+            }],
+        };
+        let psbt = PartiallySignedTransaction::from_unsigned_tx(transaction.clone()).unwrap();
+
+        let raw_hex = hex::encode(consensus::serialize(&transaction));
+        assert_eq!(parse_transaction(&raw_hex).unwrap(), transaction);
+        let psbt_hex = hex::encode(psbt.serialize());
+        assert_eq!(parse_transaction(&psbt_hex).unwrap(), transaction);
+        assert_eq!(parse_transaction(&psbt.to_string()).unwrap(), transaction);
+        assert!(parse_transaction("not a transaction").is_err());
+    }
+
+    #[test]
+    fn test_balance_deltas() {
+        let effects = [
+            Effect::valid("a", "transfer", "ordi", "sender", 10.0, (-10.0, 10.0, 0.0)),
+            Effect::valid("b", "send", "ordi", "sender", 10.0, (0.0, -10.0, -10.0)),
+            Effect::valid("b", "receive", "ordi", "receiver", 10.0, (10.0, 0.0, 10.0)),
+            Effect::invalid("c", "mint", "ordi", "sender", vec!["no".to_string()]),
+        ];
+
+        assert_eq!(
+            balance_deltas(&effects),
+            vec![
+                BalanceDelta {
+                    address: "sender".to_string(),
+                    tick: "ordi".to_string(),
+                    available: -10.0,
+                    transferable: 0.0,
+                    overall: -10.0,
+                },
+                BalanceDelta {
+                    address: "receiver".to_string(),
+                    tick: "ordi".to_string(),
+                    available: 10.0,
+                    transferable: 0.0,
+                    overall: 10.0,
+                },
+            ]
+        );
+    }
+}
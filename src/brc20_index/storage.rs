@@ -0,0 +1,148 @@
+use super::{
+    error::IndexerError,
+    retry::{RetryClass, RetryPolicy},
+    ToDocument,
+};
+use bitcoin::Txid;
+use bitcoincore_rpc::{Client, RpcApi};
+use mongodb::bson::{doc, Bson, Document};
+use std::fmt;
+use std::str::FromStr;
+
+// Fields of the operation documents holding a whole transaction
+const TX_FIELDS: [&str; 2] = ["tx", "send_tx"];
+
+// How much of the transactions is stored with the operations. Full embeds them as the node
+// returns them, slim only keeps the txid and the value and address of every output, the rest
+// can be fetched from the node with fetch_raw_tx. The inscription id is on the document itself.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StorageProfile {
+    #[default]
+    Full,
+    Slim,
+}
+
+impl StorageProfile {
+    // Shapes the transactions of a document about to be written
+    pub fn shape(&self, document: &mut Document) {
+        if *self == StorageProfile::Full {
+            return;
+        }
+
+        for field in TX_FIELDS {
+            if let Ok(tx) = document.get_document(field) {
+                let slim = slim_tx(tx);
+                document.insert(field, slim);
+            }
+        }
+    }
+}
+
+impl FromStr for StorageProfile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "full" => Ok(StorageProfile::Full),
+            "slim" => Ok(StorageProfile::Slim),
+            _ => Err(format!("must be full or slim, got {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for StorageProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageProfile::Full => write!(f, "full"),
+            StorageProfile::Slim => write!(f, "slim"),
+        }
+    }
+}
+
+// Keeps tx.txid, which the transfers are looked up by, and what the outputs pay to whom
+fn slim_tx(tx: &Document) -> Document {
+    let txid = tx.get_str("txid").unwrap_or_default();
+    let vout: Vec<Document> = tx
+        .get_array("vout")
+        .map(|vout| {
+            vout.iter()
+                .filter_map(Bson::as_document)
+                .map(|output| {
+                    let address = output
+                        .get_document("script_pub_key")
+                        .ok()
+                        .and_then(|script_pub_key| script_pub_key.get("address").cloned())
+                        .unwrap_or(Bson::Null);
+                    doc! {
+                        "n": output.get("n").cloned().unwrap_or(Bson::Null),
+                        "value": output.get("value").cloned().unwrap_or(Bson::Null),
+                        "address": address,
+                    }
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    doc! {
+        "txid": txid,
+        "vout": vout,
+    }
+}
+
+// The whole transaction, as the full profile stores it
+pub async fn fetch_raw_tx(
+    rpc: &Client,
+    retry_policy: &RetryPolicy,
+    txid: &Txid,
+) -> Result<Document, IndexerError> {
+    let raw_tx = retry_policy
+        .run(
+            RetryClass::Rpc,
+            "get_raw_transaction_info",
+            move || async move { Ok(rpc.get_raw_transaction_info(txid, None)?) },
+        )
+        .await?;
+
+    Ok(raw_tx.to_document())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shape() {
+        let document = doc! {
+            "amt": 1000.0,
+            "tx": {
+                "hex": "0200",
+                "txid": "mint",
+                "vin": [{ "txinwitness": ["00"] }],
+                "vout": [{
+                    "value": 0.00000546,
+                    "n": 0i64,
+                    "script_pub_key": { "hex": "5120", "address": "bc1pminter" },
+                }],
+            },
+        };
+
+        let mut full = document.clone();
+        StorageProfile::Full.shape(&mut full);
+        assert_eq!(full, document);
+
+        let mut slim = document;
+        StorageProfile::Slim.shape(&mut slim);
+        assert_eq!(
+            slim,
+            doc! {
+                "amt": 1000.0,
+                "tx": {
+                    "txid": "mint",
+                    "vout": [{ "n": 0i64, "value": 0.00000546, "address": "bc1pminter" }],
+                },
+            }
+        );
+        assert_eq!("slim".parse(), Ok(StorageProfile::Slim));
+        assert!("tiny".parse::<StorageProfile>().is_err());
+    }
+}
@@ -0,0 +1,99 @@
+use super::error::IndexerError;
+use log::{debug, error, info};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tokio::sync::Notify;
+
+// Topics published by Bitcoin Core for every new block, either one wakes the indexer
+const BLOCK_TOPICS: [&[u8]; 2] = [b"hashblock", b"rawblock"];
+
+// BlockNotifier wakes the indexer when the node announces a new block.
+// Without a ZMQ endpoint it only polls, and it always polls as a fallback
+// in case a notification is missed.
+pub struct BlockNotifier {
+    notify: Arc<Notify>,
+    poll_interval: Duration,
+}
+
+impl BlockNotifier {
+    pub fn new(zmq_endpoint: Option<&str>, poll_interval: Duration) -> Result<Self, IndexerError> {
+        let notify = Arc::new(Notify::new());
+
+        if let Some(endpoint) = zmq_endpoint {
+            let zmq_error =
+                |e: zmq::Error| IndexerError::Config(format!("ZMQ endpoint {}: {}", endpoint, e));
+
+            // zmq sockets are blocking, they get their own thread. The socket reconnects
+            // by itself, so the thread lives as long as the process.
+            let socket = zmq::Context::new().socket(zmq::SUB).map_err(zmq_error)?;
+            socket.connect(endpoint).map_err(zmq_error)?;
+            for topic in BLOCK_TOPICS {
+                socket.set_subscribe(topic).map_err(zmq_error)?;
+            }
+            info!("Subscribed to block notifications at {}", endpoint);
+
+            let notify = notify.clone();
+            thread::Builder::new()
+                .name("zmq-blocks".to_string())
+                .spawn(move || loop {
+                    match socket.recv_multipart(0) {
+                        Ok(message) => {
+                            debug!(
+                                "Block notification: {}",
+                                String::from_utf8_lossy(message.first().map_or(&[], |t| t))
+                            );
+                            // Several quick blocks collapse into a single wake-up,
+                            // the indexer catches up with the tip before waiting again
+                            notify.notify_one();
+                        }
+                        Err(e) => {
+                            error!("Failed to receive block notification: {}", e);
+                            thread::sleep(Duration::from_secs(1));
+                        }
+                    }
+                })
+                .map_err(|e| IndexerError::Config(format!("Can't start ZMQ thread: {}", e)))?;
+        }
+
+        Ok(BlockNotifier {
+            notify,
+            poll_interval,
+        })
+    }
+
+    // Waits for a block notification, or the poll interval at most
+    pub async fn wait(&self) {
+        let _ = tokio::time::timeout(self.poll_interval, self.notify.notified()).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    #[tokio::test]
+    async fn test_notification_wakes_before_poll_interval() {
+        let publisher = zmq::Context::new().socket(zmq::PUB).unwrap();
+        publisher.bind("tcp://127.0.0.1:*").unwrap();
+        let endpoint = publisher.get_last_endpoint().unwrap().unwrap();
+
+        let notifier = BlockNotifier::new(Some(&endpoint), Duration::from_secs(30)).unwrap();
+
+        // Subscriptions take a moment to reach the publisher, keep announcing until then
+        thread::spawn(move || loop {
+            if publisher
+                .send_multipart([&b"hashblock"[..], &[0u8; 32]], 0)
+                .is_err()
+            {
+                break;
+            }
+            thread::sleep(Duration::from_millis(50));
+        });
+
+        let start = Instant::now();
+        notifier.wait().await;
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+}
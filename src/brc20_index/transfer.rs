@@ -1,36 +1,89 @@
 use super::{
-    consts, invalid_brc20::InvalidBrc20Tx, mongo::MongoClient, user_balance::UserBalanceEntry,
+    consts,
+    error::IndexerError,
+    invalid_brc20::{InvalidBrc20Tx, InvalidReason},
+    metrics::metrics,
+    mongo::MongoClient,
+    user_balance::UserBalanceEntry,
     Brc20Inscription,
 };
 use crate::brc20_index::{
-    user_balance::UserBalanceEntryType, utils::update_sender_or_inscriber_user_balance_document,
+    user_balance::{EntrySource, UserBalanceEntryType},
+    utils::update_sender_or_inscriber_user_balance_document,
     ToDocument,
 };
 use bitcoin::Address;
 use bitcoincore_rpc::bitcoincore_rpc_json::GetRawTransactionResult;
 use log::{debug, error, info};
-use mongodb::bson::{doc, Bson, DateTime, Document};
+use mongodb::bson::{doc, Document};
 use serde::Serialize;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
 
-// create active transfer struct
+// A valid transfer inscription that wasn't sent yet, with what spending it sends
 #[derive(Debug, Clone, Serialize)]
 pub struct Brc20ActiveTransfer {
     pub tx_id: String,
     pub vout: i64,
     pub block_height: i64,
+    pub inscription_id: String,
+    // The inscriber, whose transferable balance holds the amount
+    pub address: String,
+    pub tick: String,
+    pub amt: f64,
+}
+
+// Active transfers by outpoint and inscription id. The inscriptions of a transaction can share
+// an outpoint, spending it sends all of them.
+pub type ActiveTransferKey = (String, i64, String);
+pub type ActiveTransfers = BTreeMap<ActiveTransferKey, Brc20ActiveTransfer>;
+
+// The keys of the active transfers at an outpoint
+pub fn active_transfers_at(
+    active_transfers: &ActiveTransfers,
+    tx_id: &str,
+    vout: i64,
+) -> Vec<ActiveTransferKey> {
+    active_transfers
+        .range((tx_id.to_string(), vout, String::new())..)
+        .map(|(key, _)| key)
+        .take_while(|(key_tx_id, key_vout, _)| key_tx_id == tx_id && *key_vout == vout)
+        .cloned()
+        .collect()
 }
 
 impl Brc20ActiveTransfer {
-    pub fn new(tx_id: String, vout: i64, block_height: i64) -> Self {
+    pub fn new(
+        tx_id: String,
+        vout: i64,
+        block_height: i64,
+        inscription_id: String,
+        address: String,
+        tick: String,
+        amt: f64,
+    ) -> Self {
         Brc20ActiveTransfer {
             tx_id,
             vout,
             block_height,
+            inscription_id,
+            address,
+            tick,
+            amt,
         }
     }
 }
 
+impl fmt::Display for Brc20ActiveTransfer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {} at {}:{}, inscribed in block {}",
+            self.inscription_id, self.amt, self.tick, self.tx_id, self.vout, self.block_height
+        )
+    }
+}
+
 #[derive(Debug, Clone, Serialize)]
 pub struct Brc20Transfer {
     pub amt: f64,
@@ -38,6 +91,7 @@ pub struct Brc20Transfer {
     pub tx_height: u32,
     pub tx: GetRawTransactionResult,
     pub inscription: Brc20Inscription,
+    pub inscription_id: String,
     pub send_tx: Option<GetRawTransactionResult>,
     pub send_block_height: Option<u32>,
     pub send_tx_height: Option<u32>,
@@ -50,6 +104,7 @@ impl Brc20Transfer {
     pub fn new(
         inscription_tx: &GetRawTransactionResult,
         inscription: Brc20Inscription,
+        inscription_id: String,
         block_height: u32,
         tx_height: u32,
         from: Address,
@@ -69,6 +124,7 @@ impl Brc20Transfer {
             send_block_height: None,
             send_tx_height: None,
             inscription,
+            inscription_id,
             from,
             to: None,
             is_valid: false,
@@ -81,74 +137,36 @@ impl Brc20Transfer {
 
     pub async fn validate_inscribe_transfer(
         &mut self,
-        mongo_client: &MongoClient,
-        active_transfers: &mut Option<HashMap<(String, i64), Brc20ActiveTransfer>>,
-        user_balances_to_update: &mut HashMap<(String, String), Document>,
-        user_balances_to_insert: &mut HashMap<(String, String), Document>,
+        ticker_doc_opt: Option<&Document>,
+        user_balance_opt: Option<&mut Document>,
+        active_transfers: &mut Option<ActiveTransfers>,
         invalid_brc20_docs: &mut Vec<Document>,
-    ) -> Result<UserBalanceEntry, Box<dyn std::error::Error>> {
+    ) -> Result<UserBalanceEntry, IndexerError> {
         let ticker_symbol = &self.inscription.tick.to_lowercase();
         let mut user_balance_entry = UserBalanceEntry::default();
-        let from = &self.from.to_string();
 
-        // Get the ticker document from MongoDB
-        let ticker_doc_from_mongo = mongo_client
-            .get_document_by_field(consts::COLLECTION_TICKERS, "tick", ticker_symbol)
-            .await?;
-
-        if ticker_doc_from_mongo.is_none() {
+        if ticker_doc_opt.is_none() {
             // Ticker not found, create invalid transaction
-            let reason = "Ticker not found";
+            let reason = InvalidReason::TickerNotFound;
             error!("INVALID Transfer Inscribe: {}", reason);
 
-            self.insert_invalid_tx(reason, invalid_brc20_docs).await?;
+            self.insert_invalid_tx(reason.clone(), invalid_brc20_docs)
+                .await?;
 
-            return Err(Box::new(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                reason,
-            )));
+            return Err(reason.into());
         }
 
-        // Get the user balance document from the hashmap
-        let user_balance_from =
-            user_balances_to_update.get_mut(&(from.clone(), ticker_symbol.clone()));
-
-        let user_balance = match user_balance_from {
+        let user_balance = match user_balance_opt {
             Some(user_balance) => user_balance,
             None => {
-                // User balance not found in the hashmap, check the 'user_balances_to_insert' hashmap
-                let user_balance =
-                    user_balances_to_insert.get_mut(&(from.clone(), ticker_symbol.clone()));
-
-                match user_balance {
-                    Some(user_balance) => user_balance,
-                    None => {
-                        // User balance not found in either hashmap, load it from the database
-                        let user_balance_doc = mongo_client
-                            .load_user_balance_with_retry(&(from.clone(), ticker_symbol.clone()))
-                            .await
-                            .map_err(|e| anyhow::anyhow!("{}", e))?;
-
-                        if let Some(doc) = user_balance_doc {
-                            user_balances_to_update
-                                .insert((from.clone(), ticker_symbol.clone()), doc.clone());
-                            user_balances_to_update
-                                .get_mut(&(from.clone(), ticker_symbol.clone()))
-                                .unwrap()
-                        } else {
-                            // User balance not found in the database either
-                            let reason = "User balance not found";
-                            error!("INVALID: {}", reason);
-
-                            self.insert_invalid_tx(reason, invalid_brc20_docs).await?;
-
-                            return Err(Box::new(std::io::Error::new(
-                                std::io::ErrorKind::Other,
-                                reason,
-                            )));
-                        }
-                    }
-                }
+                // User balance not found in the hashmaps or the database
+                let reason = InvalidReason::UserBalanceNotFound;
+                error!("INVALID: {}", reason);
+
+                self.insert_invalid_tx(reason.clone(), invalid_brc20_docs)
+                    .await?;
+
+                return Err(reason.into());
             }
         };
 
@@ -157,8 +175,8 @@ impl Brc20Transfer {
             user_balance
         );
 
-        let available_balance = mongo_client
-            .get_double(&user_balance, "available_balance")
+        let available_balance = user_balance
+            .get_f64(consts::AVAILABLE_BALANCE)
             .unwrap_or_default();
 
         // Get transfer amount
@@ -174,37 +192,44 @@ impl Brc20Transfer {
             info!("VALID: Transfer inscription from: {:?}", self.from);
             self.is_valid = true;
 
-            // Insert user balance entry
-            user_balance_entry = mongo_client
-                .insert_user_balance_entry(
-                    &self.from.to_string(),
-                    transfer_amount,
-                    ticker_symbol,
-                    self.block_height.into(),
-                    UserBalanceEntryType::Inscription,
-                )
-                .await?;
+            // Create user balance entry
+            user_balance_entry = UserBalanceEntry::new(
+                self.from.to_string(),
+                ticker_symbol.clone(),
+                self.block_height.into(),
+                transfer_amount,
+                UserBalanceEntryType::Inscription,
+                EntrySource::new(
+                    "transfer",
+                    &self.tx.txid.to_string(),
+                    self.tx_height.into(),
+                    self.inscription_id.clone(),
+                ),
+            );
 
             // Update the user balance document
             update_sender_or_inscriber_user_balance_document(user_balance, &user_balance_entry)?;
 
             // Create a new active transfer when the inscription is valid
-            let active_transfer =
-                Brc20ActiveTransfer::new(self.tx.txid.to_string(), 0, self.block_height.into());
-
-            // If active_transfers is None, create a new HashMap and assign it to active_transfers
-            if active_transfers.is_none() {
-                *active_transfers = Some(HashMap::new());
-            }
+            let active_transfer = Brc20ActiveTransfer::new(
+                self.tx.txid.to_string(),
+                0,
+                self.block_height.into(),
+                self.inscription_id.clone(),
+                self.from.to_string(),
+                ticker_symbol.clone(),
+                transfer_amount,
+            );
 
-            // We know active_transfers is Some at this point, so we can unwrap it
             active_transfers
-                .as_mut()
-                .unwrap()
-                .insert((self.tx.txid.to_string(), 0), active_transfer);
+                .get_or_insert_with(ActiveTransfers::new)
+                .insert(active_transfer.key(), active_transfer);
         } else {
             // If invalid, add invalid tx and return
-            let reason = "Transfer amount exceeds available balance";
+            let reason = InvalidReason::InsufficientBalance {
+                requested: transfer_amount,
+                available: available_balance,
+            };
             error!("INVALID: {}", reason);
 
             self.insert_invalid_tx(reason, invalid_brc20_docs).await?;
@@ -215,13 +240,13 @@ impl Brc20Transfer {
 
     pub async fn insert_invalid_tx(
         &self,
-        reason: &str,
+        reason: InvalidReason,
         invalid_brc20_docs: &mut Vec<Document>,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+    ) -> Result<(), IndexerError> {
         let invalid_tx = InvalidBrc20Tx::new(
             self.tx.txid,
             self.inscription.clone(),
-            reason.to_string(),
+            vec![reason],
             self.block_height,
         );
 
@@ -231,29 +256,86 @@ impl Brc20Transfer {
     }
 }
 
+// This function will try to get a user's balance document from the hashmaps
+// If it is in neither, it will load it from MongoDB and store it in 'user_balances_to_update'
+async fn get_user_balance<'a>(
+    key: &(String, String),
+    user_balances_to_update: &'a mut HashMap<(String, String), Document>,
+    user_balances_to_insert: &'a mut HashMap<(String, String), Document>,
+    mongo_client: &MongoClient,
+) -> Result<Option<&'a mut Document>, IndexerError> {
+    metrics().cache_lookup(
+        "user_balance",
+        user_balances_to_update.contains_key(key) || user_balances_to_insert.contains_key(key),
+    );
+
+    if user_balances_to_update.contains_key(key) {
+        return Ok(user_balances_to_update.get_mut(key));
+    }
+
+    if user_balances_to_insert.contains_key(key) {
+        return Ok(user_balances_to_insert.get_mut(key));
+    }
+
+    match mongo_client.load_user_balance_with_retry(key).await? {
+        Some(doc) => Ok(Some(
+            user_balances_to_update.entry(key.clone()).or_insert(doc),
+        )),
+        None => Ok(None),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn handle_transfer_operation(
     mongo_client: &MongoClient,
     block_height: u32,
     tx_height: u32,
     inscription: Brc20Inscription,
+    inscription_id: String,
     raw_tx: &GetRawTransactionResult,
     sender: Address,
-    active_transfers: &mut Option<HashMap<(String, i64), Brc20ActiveTransfer>>,
+    active_transfers: &mut Option<ActiveTransfers>,
     user_balances: &mut HashMap<(String, String), Document>,
     user_balances_to_insert: &mut HashMap<(String, String), Document>,
     invalid_brc20_docs: &mut Vec<Document>,
-) -> Result<(Brc20Transfer, UserBalanceEntry), Box<dyn std::error::Error>> {
+) -> Result<(Brc20Transfer, UserBalanceEntry), IndexerError> {
+    let ticker_symbol = inscription.tick.to_lowercase();
+
+    // Get the ticker document from MongoDB
+    let ticker_doc_opt = mongo_client
+        .get_document_by_field(consts::COLLECTION_TICKERS, "tick", &ticker_symbol)
+        .await?;
+
+    // The sender's balance is only needed when the ticker exists
+    let user_balance_opt = match ticker_doc_opt {
+        Some(_) => {
+            get_user_balance(
+                &(sender.to_string(), ticker_symbol),
+                user_balances,
+                user_balances_to_insert,
+                mongo_client,
+            )
+            .await?
+        }
+        None => None,
+    };
+
     // Create a new transfer transaction
-    let mut validated_transfer_tx =
-        Brc20Transfer::new(raw_tx, inscription, block_height, tx_height, sender);
+    let mut validated_transfer_tx = Brc20Transfer::new(
+        raw_tx,
+        inscription,
+        inscription_id,
+        block_height,
+        tx_height,
+        sender,
+    );
 
     // Handle the transfer inscription
     let user_balance_entry = validated_transfer_tx
         .validate_inscribe_transfer(
-            mongo_client,
+            ticker_doc_opt.as_ref(),
+            user_balance_opt,
             active_transfers,
-            user_balances,
-            user_balances_to_insert,
             invalid_brc20_docs,
         )
         .await?;
@@ -269,13 +351,13 @@ impl ToDocument for Brc20Transfer {
             "tx_height": self.tx_height,
             "tx": self.tx.to_document(), // Convert GetRawTransactionResult to document
             "inscription": self.inscription.to_document(),
+            "inscription_id": &self.inscription_id,
             "send_tx": self.send_tx.clone().map(|tx| tx.to_document()), // Convert Option<GetRawTransactionResult> to document
             "send_block_height": self.send_block_height,
             "send_tx_height": self.send_tx_height,
             "from": self.from.to_string(),
             "to": self.to.clone().map(|addr| addr.to_string()), // Convert Option<Address> to string
             "is_valid": self.is_valid,
-            "created_at": Bson::DateTime(DateTime::now())
         }
     }
 }
@@ -286,30 +368,220 @@ impl ToDocument for Brc20ActiveTransfer {
             "txid": self.tx_id.to_string(),
             "vout": self.vout,
             "block_height": self.block_height,
-            "created_at": Bson::DateTime(DateTime::now())
+            "inscription_id": &self.inscription_id,
+            "address": &self.address,
+            "tick": &self.tick,
+            "amt": self.amt,
         }
     }
 }
 
 impl Brc20ActiveTransfer {
-    pub fn from_document(document: Document) -> Result<Self, String> {
+    pub fn from_document(document: Document) -> Result<Self, IndexerError> {
         let tx_id = document
             .get_str("tx_id")
-            .map_err(|_| "Invalid txid".to_string())?
+            .map_err(|_| IndexerError::Decode("Invalid txid".to_string()))?
             .to_string();
 
         let vout = document
             .get_i64("vout")
-            .map_err(|_| "Invalid vout".to_string())?;
+            .map_err(|_| IndexerError::Decode("Invalid vout".to_string()))?;
 
         let block_height = document
             .get_i64("block_height")
-            .map_err(|_| "Invalid block_height".to_string())?;
+            .map_err(|_| IndexerError::Decode("Invalid block_height".to_string()))?;
+
+        // Active transfers stored before they carried the inscription's details have none
+        // until the schema migration fills them in
+        let inscription_id = match document.get_str("inscription_id") {
+            Ok(inscription_id) => inscription_id.to_string(),
+            Err(_) => format!("{}i0", tx_id),
+        };
 
-        Ok(Self {
+        Ok(Self::new(
             tx_id,
             vout,
             block_height,
+            inscription_id,
+            document.get_str("address").unwrap_or_default().to_string(),
+            document.get_str("tick").unwrap_or_default().to_string(),
+            document.get_f64("amt").unwrap_or_default(),
+        ))
+    }
+
+    pub fn key(&self) -> ActiveTransferKey {
+        (self.tx_id.clone(), self.vout, self.inscription_id.clone())
+    }
+
+    // Selects the active transfers inscribed by an address, only of one ticker if given
+    pub fn filter(address: &str, tick: Option<&str>) -> Document {
+        let mut filter = doc! { "address": address };
+        if let Some(tick) = tick {
+            filter.insert("tick", tick.to_lowercase());
+        }
+        filter
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::Network;
+    use std::str::FromStr;
+
+    const INSCRIBER: &str = "bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq";
+    const OTHER: &str = "bc1qxy2kgdygjrsqtzq2n0yrf2493p83kkfjhx0wlh";
+
+    fn raw_tx(index: u8) -> GetRawTransactionResult {
+        let tx_id = format!("{:064x}", index);
+        serde_json::from_value(serde_json::json!({
+            "hex": "",
+            "txid": tx_id,
+            "hash": tx_id,
+            "size": 0,
+            "vsize": 0,
+            "version": 1,
+            "locktime": 0,
+            "vin": [],
+            "vout": [],
+        }))
+        .unwrap()
+    }
+
+    fn inscription(tick: &str, amt: &str) -> Brc20Inscription {
+        Brc20Inscription {
+            p: "brc-20".to_string(),
+            op: "transfer".to_string(),
+            tick: tick.to_string(),
+            amt: Some(amt.to_string()),
+            max: None,
+            lim: None,
+            dec: None,
+        }
+    }
+
+    fn balance_doc(available: f64) -> Document {
+        doc! {
+            consts::AVAILABLE_BALANCE: available,
+            consts::TRANSFERABLE_BALANCE: 0.0,
+            consts::OVERALL_BALANCE: available,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_active_transfers_sum_to_transferable_balance() {
+        let ticker_doc = doc! { "tick": "ordi" };
+        let mut balances: HashMap<(String, String), Document> = [
+            ((INSCRIBER, "ordi"), 100.0),
+            ((INSCRIBER, "sats"), 50.0),
+            ((OTHER, "ordi"), 100.0),
+        ]
+        .into_iter()
+        .map(|((address, tick), available)| {
+            (
+                (address.to_string(), tick.to_string()),
+                balance_doc(available),
+            )
         })
+        .collect();
+
+        // Transactions and the envelopes in them. The first two inscriptions share a
+        // transaction and the last asks more than is left, it stays out of both sums.
+        let inscribed = [
+            (0, INSCRIBER, "ORDI", "30"),
+            (0, INSCRIBER, "ordi", "20.5"),
+            (1, INSCRIBER, "sats", "5"),
+            (2, OTHER, "ordi", "10"),
+            (3, INSCRIBER, "sats", "60"),
+        ];
+        let mut active_transfers = None;
+        let mut invalid_brc20_docs = Vec::new();
+        let mut envelopes: HashMap<u8, usize> = HashMap::new();
+        for (tx, address, tick, amt) in inscribed {
+            let from = Address::from_str(address)
+                .unwrap()
+                .require_network(Network::Bitcoin)
+                .unwrap();
+            let key = (address.to_string(), tick.to_lowercase());
+            let envelope = envelopes.entry(tx).or_default();
+            let inscription_id = format!("{:064x}i{}", tx, envelope);
+            *envelope += 1;
+            let entry = Brc20Transfer::new(
+                &raw_tx(tx),
+                inscription(tick, amt),
+                inscription_id.clone(),
+                800_000,
+                0,
+                from,
+            )
+            .validate_inscribe_transfer(
+                Some(&ticker_doc),
+                balances.get_mut(&key),
+                &mut active_transfers,
+                &mut invalid_brc20_docs,
+            )
+            .await
+            .unwrap();
+            if entry.amt > 0.0 {
+                assert_eq!(entry.source.inscription_id, inscription_id);
+            }
+        }
+        assert_eq!(invalid_brc20_docs.len(), 1);
+
+        // Both inscriptions of the first transaction are sent by spending its first output
+        let active_transfers = active_transfers.unwrap();
+        let first_tx = format!("{:064x}", 0);
+        let at_outpoint: Vec<String> = active_transfers_at(&active_transfers, &first_tx, 0)
+            .into_iter()
+            .map(|(_, _, inscription_id)| inscription_id)
+            .collect();
+        assert_eq!(
+            at_outpoint,
+            [format!("{}i0", first_tx), format!("{}i1", first_tx)]
+        );
+        assert!(active_transfers_at(&active_transfers, &first_tx, 1).is_empty());
+
+        // Listed the way get_active_transfers queries the stored documents
+        let stored: Vec<Document> = active_transfers
+            .values()
+            .map(|active_transfer| mongodb::bson::to_document(active_transfer).unwrap())
+            .collect();
+        let listed_sum = |address: &str, tick: Option<&str>| -> f64 {
+            let filter = Brc20ActiveTransfer::filter(address, tick);
+            stored
+                .iter()
+                .filter(|document| {
+                    filter
+                        .iter()
+                        .all(|(key, value)| document.get(key) == Some(value))
+                })
+                .map(|document| {
+                    Brc20ActiveTransfer::from_document(document.clone())
+                        .unwrap()
+                        .amt
+                })
+                .sum()
+        };
+        let transferable = |address: &str, tick: &str| {
+            balances[&(address.to_string(), tick.to_string())]
+                .get_f64(consts::TRANSFERABLE_BALANCE)
+                .unwrap()
+        };
+
+        assert_eq!(listed_sum(INSCRIBER, Some("ORDI")), 50.5);
+        assert_eq!(
+            listed_sum(INSCRIBER, Some("ordi")),
+            transferable(INSCRIBER, "ordi")
+        );
+        assert_eq!(
+            listed_sum(INSCRIBER, Some("sats")),
+            transferable(INSCRIBER, "sats")
+        );
+        assert_eq!(listed_sum(OTHER, Some("ordi")), transferable(OTHER, "ordi"));
+        assert_eq!(
+            listed_sum(INSCRIBER, None),
+            transferable(INSCRIBER, "ordi") + transferable(INSCRIBER, "sats")
+        );
+        assert_eq!(listed_sum(OTHER, None), 10.0);
     }
 }
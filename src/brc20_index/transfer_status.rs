@@ -0,0 +1,252 @@
+use super::{consts, error::IndexerError, mongo::MongoClient, transfer::Brc20ActiveTransfer};
+use bitcoin::{OutPoint, Txid};
+use futures_util::stream::TryStreamExt;
+use mongodb::{
+    bson::{doc, Bson, Document},
+    options::FindOptions,
+};
+use serde::Serialize;
+use std::fmt;
+use std::str::FromStr;
+
+// Whether a transfer inscription can still be sent, and where it is or went
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TransferStatus {
+    pub inscription_id: String,
+    pub tick: String,
+    pub amt: f64,
+    // The address that inscribed it
+    pub from: String,
+    pub block_height: Option<i64>,
+    // Still in brc20_active_transfers, the next spend sends it
+    pub active: bool,
+    // The inscriber while it's active, the receiver once it was sent
+    pub holder: String,
+    // txid:vout:offset of the inscribed satoshi while it's active
+    pub satpoint: Option<String>,
+    // The transaction that sent it, consuming the inscription
+    pub spent_by: Option<String>,
+    pub spent_block_height: Option<i64>,
+    // An unconfirmed transaction in the mempool that sends it
+    pub pending_spend: Option<String>,
+}
+
+impl fmt::Display for TransferStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: transfer of {} {} inscribed by {}",
+            self.inscription_id, self.amt, self.tick, self.from
+        )?;
+        if self.active {
+            write!(f, ", active, held by {}", self.holder)?;
+            if let Some(satpoint) = &self.satpoint {
+                write!(f, " at {}", satpoint)?;
+            }
+        } else {
+            write!(f, ", spent")?;
+            if let Some(spent_by) = &self.spent_by {
+                write!(f, " by {}", spent_by)?;
+            }
+            write!(f, " to {}", self.holder)?;
+        }
+        if let Some(pending_spend) = &self.pending_spend {
+            write!(f, ", sent by {} in the mempool", pending_spend)?;
+        }
+        Ok(())
+    }
+}
+
+// A transfer inscription named by its id, or the outpoint it was created at
+#[derive(Debug, Clone, PartialEq)]
+pub enum InscriptionRef {
+    Id(String),
+    Outpoint(OutPoint),
+}
+
+impl fmt::Display for InscriptionRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InscriptionRef::Id(inscription_id) => write!(f, "{}", inscription_id),
+            InscriptionRef::Outpoint(outpoint) => write!(f, "{}", outpoint),
+        }
+    }
+}
+
+/// Parses an inscription id (`<txid>i<index>`) or an outpoint (`<txid>:<vout>`).
+///
+/// The index of an inscription id numbers the envelopes of its transaction, it isn't an output:
+/// every inscription of a transaction is created at its first output.
+pub fn parse_inscription_ref(input: &str) -> Result<InscriptionRef, IndexerError> {
+    let input = input.trim();
+    if let Some((txid, index)) = input.split_once('i') {
+        if let (Ok(txid), Ok(index)) = (Txid::from_str(txid), index.parse::<u32>()) {
+            return Ok(InscriptionRef::Id(format!("{}i{}", txid, index)));
+        }
+    }
+
+    OutPoint::from_str(input)
+        .map(InscriptionRef::Outpoint)
+        .map_err(|_| {
+            IndexerError::Decode(format!(
+                "Expected an inscription id <txid>i<index> or an outpoint <txid>:<vout>, got {:?}",
+                input
+            ))
+        })
+}
+
+// The valid transfer inscriptions a reference names, in inscription order. An outpoint names
+// every one its transaction inscribed, they are all created at the first output.
+pub async fn get_transfer_statuses(
+    mongo_client: &MongoClient,
+    inscription_ref: &InscriptionRef,
+) -> Result<Vec<TransferStatus>, IndexerError> {
+    let inscription_ids = match inscription_ref {
+        InscriptionRef::Id(inscription_id) => vec![inscription_id.clone()],
+        InscriptionRef::Outpoint(outpoint) if outpoint.vout != 0 => Vec::new(),
+        InscriptionRef::Outpoint(outpoint) => {
+            let find_options = FindOptions::builder()
+                .sort(doc! { "_id": 1 })
+                .projection(doc! { "inscription_id": 1 })
+                .build();
+            let mut cursor = mongo_client
+                .find_with_retries(
+                    consts::COLLECTION_TRANSFERS,
+                    Some(doc! { "tx.txid": outpoint.txid.to_string(), "is_valid": true }),
+                    Some(find_options),
+                )
+                .await?;
+            let mut inscription_ids = Vec::new();
+            while let Some(transfer) = cursor.try_next().await? {
+                inscription_ids.push(transfer.get_str("inscription_id")?.to_string());
+            }
+            inscription_ids
+        }
+    };
+
+    let mut statuses = Vec::new();
+    for inscription_id in inscription_ids {
+        if let Some(status) = get_transfer_status(mongo_client, &inscription_id).await? {
+            statuses.push(status);
+        }
+    }
+
+    Ok(statuses)
+}
+
+// Looks up a transfer inscription by its id, None if there is no valid one
+pub async fn get_transfer_status(
+    mongo_client: &MongoClient,
+    inscription_id: &str,
+) -> Result<Option<TransferStatus>, IndexerError> {
+    let transfer = match mongo_client
+        .get_document_by_filter(
+            consts::COLLECTION_TRANSFERS,
+            doc! { "inscription_id": inscription_id, "is_valid": true },
+        )
+        .await?
+    {
+        Some(transfer) => transfer,
+        None => return Ok(None),
+    };
+    // The outpoint it's at while it's active, spending it sends the inscription
+    let outpoint = match mongo_client
+        .get_document_by_filter(
+            consts::COLLECTION_BRC20_ACTIVE_TRANSFERS,
+            doc! { "inscription_id": inscription_id },
+        )
+        .await?
+    {
+        Some(active_transfer) => {
+            let active_transfer = Brc20ActiveTransfer::from_document(active_transfer)?;
+            Some(format!(
+                "{}:{}",
+                active_transfer.tx_id, active_transfer.vout
+            ))
+        }
+        None => None,
+    };
+    let active = outpoint.is_some();
+    let pending_spend = match &outpoint {
+        Some(outpoint) => mongo_client
+            .get_document_by_filter(
+                consts::COLLECTION_MEMPOOL,
+                doc! { "op": "send", "outpoint": outpoint },
+            )
+            .await?
+            .and_then(|effect| effect.get_str("txid").ok().map(String::from)),
+        None => None,
+    };
+
+    let from = transfer.get_str("from")?.to_string();
+    let spent_by = transfer
+        .get_document("send_tx")
+        .ok()
+        .and_then(|send_tx| send_tx.get_str("txid").ok())
+        .map(String::from);
+    let holder = match transfer.get_str("to") {
+        Ok(to) if !active => to.to_string(),
+        _ => from.clone(),
+    };
+
+    Ok(Some(TransferStatus {
+        inscription_id: inscription_id.to_string(),
+        tick: transfer
+            .get_document("inscription")?
+            .get_str("tick")?
+            .to_lowercase(),
+        amt: transfer.get_f64("amt").unwrap_or_default(),
+        from,
+        block_height: get_height(&transfer, "block_height"),
+        active,
+        holder,
+        satpoint: outpoint.map(|outpoint| format!("{}:0", outpoint)),
+        spent_by: if active { None } else { spent_by },
+        spent_block_height: if active {
+            None
+        } else {
+            get_height(&transfer, "send_block_height")
+        },
+        pending_spend,
+    }))
+}
+
+// Heights are stored as 32 or 64 bit integers depending on where they were written
+fn get_height(document: &Document, key: &str) -> Option<i64> {
+    match document.get(key) {
+        Some(Bson::Int32(height)) => Some(i64::from(*height)),
+        Some(Bson::Int64(height)) => Some(*height),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_inscription_ref() {
+        let txid = "b61b0172d95e266c18aea0c624db987e971a5d6d4ebc2aaed85da4642d635735";
+        let outpoint = |vout| OutPoint::new(Txid::from_str(txid).unwrap(), vout);
+
+        assert_eq!(
+            parse_inscription_ref(&format!("{}i0", txid)).unwrap(),
+            InscriptionRef::Id(format!("{}i0", txid))
+        );
+        // The second envelope of the transaction, not its second output
+        assert_eq!(
+            parse_inscription_ref(&format!(" {}i1 ", txid)).unwrap(),
+            InscriptionRef::Id(format!("{}i1", txid))
+        );
+        assert_eq!(
+            parse_inscription_ref(&format!("{}:0", txid)).unwrap(),
+            InscriptionRef::Outpoint(outpoint(0))
+        );
+        assert_eq!(
+            parse_inscription_ref(&format!("{}:1", txid)).unwrap(),
+            InscriptionRef::Outpoint(outpoint(1))
+        );
+        assert!(parse_inscription_ref(txid).is_err());
+        assert!(parse_inscription_ref(&format!("{}i", txid)).is_err());
+    }
+}
@@ -1,6 +1,7 @@
-use super::ToDocument;
+use super::{error::IndexerError, ToDocument};
 use mongodb::bson::{doc, Document};
-use serde::Serialize;
+use serde::{ser::SerializeStruct, Serialize, Serializer};
+use std::collections::BTreeMap;
 use std::fmt;
 
 #[derive(Debug, Clone, Serialize)]
@@ -26,6 +27,114 @@ impl ToDocument for UserBalance {
     }
 }
 
+// The balance of an address in a tick as of the last final block and as of the last
+// completed block, they differ by what provisional blocks changed. Balances are
+// (available_balance, transferable_balance, overall_balance).
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct BalanceView {
+    pub tick: String,
+    #[serde(serialize_with = "serialize_balance")]
+    pub confirmed: (f64, f64, f64),
+    #[serde(serialize_with = "serialize_balance")]
+    pub pending: (f64, f64, f64),
+}
+
+impl BalanceView {
+    /// Combines the balances of an address by tick as of the last completed block with the
+    /// balances as of the last final block of the ticks that provisional blocks changed.
+    ///
+    /// The other ticks are confirmed as they are. A changed tick without a balance anymore
+    /// dropped to zero in the provisional blocks.
+    pub fn combine(
+        balances: BTreeMap<String, (f64, f64, f64)>,
+        confirmed_changes: BTreeMap<String, (f64, f64, f64)>,
+    ) -> Vec<BalanceView> {
+        let mut views: BTreeMap<String, BalanceView> = balances
+            .into_iter()
+            .map(|(tick, balance)| {
+                let view = BalanceView {
+                    tick: tick.clone(),
+                    confirmed: balance,
+                    pending: balance,
+                };
+                (tick, view)
+            })
+            .collect();
+
+        for (tick, confirmed) in confirmed_changes {
+            views
+                .entry(tick.clone())
+                .or_insert_with(|| BalanceView {
+                    tick,
+                    ..Default::default()
+                })
+                .confirmed = confirmed;
+        }
+
+        views.into_values().collect()
+    }
+}
+
+// Balances are serialized with the names of their parts
+fn serialize_balance<S: Serializer>(
+    balance: &(f64, f64, f64),
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    let mut state = serializer.serialize_struct("Balance", 3)?;
+    state.serialize_field("available", &balance.0)?;
+    state.serialize_field("transferable", &balance.1)?;
+    state.serialize_field("overall", &balance.2)?;
+    state.end()
+}
+
+impl fmt::Display for BalanceView {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (available, transferable, overall) = self.confirmed;
+        write!(
+            f,
+            "{}: confirmed {} (available {}, transferable {})",
+            self.tick, overall, available, transferable
+        )?;
+        if self.pending != self.confirmed {
+            let (available, transferable, overall) = self.pending;
+            write!(
+                f,
+                ", pending {} (available {}, transferable {})",
+                overall, available, transferable
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// The transaction and inscription a ledger entry comes from
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct EntrySource {
+    // The operation that made the entry: mint, transfer or send
+    pub op: String,
+    pub txid: String,
+    // Position of the transaction in its block, orders the entries of a block
+    pub tx_index: i64,
+    pub inscription_id: String,
+    // The output of the transaction holding the inscription, none when it was spent as fee
+    pub vout: Option<i64>,
+    // The other address of a send or receive
+    pub counterparty: Option<String>,
+}
+
+impl EntrySource {
+    pub fn new(op: &str, txid: &str, tx_index: i64, inscription_id: String) -> Self {
+        EntrySource {
+            op: op.to_string(),
+            txid: txid.to_string(),
+            tx_index,
+            inscription_id,
+            vout: Some(0),
+            counterparty: None,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize)]
 pub struct UserBalanceEntry {
     pub address: String,
@@ -33,6 +142,7 @@ pub struct UserBalanceEntry {
     pub block_height: u64,
     pub amt: f64,
     pub entry_type: UserBalanceEntryType,
+    pub source: EntrySource,
 }
 
 impl Default for UserBalanceEntry {
@@ -43,6 +153,7 @@ impl Default for UserBalanceEntry {
             block_height: 0,
             amt: 0.0,
             entry_type: UserBalanceEntryType::Inscription,
+            source: EntrySource::default(),
         }
     }
 }
@@ -54,15 +165,16 @@ impl UserBalanceEntry {
         block_height: u64,
         amount: f64,
         entry_type: UserBalanceEntryType,
+        source: EntrySource,
     ) -> Self {
-        let entry = UserBalanceEntry {
+        UserBalanceEntry {
             address,
             tick,
             block_height,
             amt: amount,
             entry_type,
-        };
-        entry
+            source,
+        }
     }
 }
 
@@ -74,11 +186,66 @@ impl ToDocument for UserBalanceEntry {
             "block_height": self.block_height as i64,
             "amt": self.amt,
             "entry_type": &self.entry_type.to_string(),
+            "op": &self.source.op,
+            "txid": &self.source.txid,
+            "tx_index": self.source.tx_index,
+            "inscription_id": &self.source.inscription_id,
+            "vout": self.source.vout,
+            "counterparty": &self.source.counterparty,
         }
     }
 }
 
-use std::convert::From;
+// Entries written before they had a source have an empty one
+impl TryFrom<&Document> for UserBalanceEntry {
+    type Error = IndexerError;
+
+    fn try_from(document: &Document) -> Result<Self, Self::Error> {
+        Ok(UserBalanceEntry {
+            address: document.get_str("address")?.to_string(),
+            tick: document.get_str("tick")?.to_string(),
+            block_height: document.get_i64("block_height")? as u64,
+            amt: document.get_f64("amt")?,
+            entry_type: UserBalanceEntryType::try_from(document.get_str("entry_type")?)?,
+            source: EntrySource {
+                op: document.get_str("op").unwrap_or_default().to_string(),
+                txid: document.get_str("txid").unwrap_or_default().to_string(),
+                tx_index: document.get_i64("tx_index").unwrap_or_default(),
+                inscription_id: document
+                    .get_str("inscription_id")
+                    .unwrap_or_default()
+                    .to_string(),
+                vout: document.get_i64("vout").ok(),
+                counterparty: document.get_str("counterparty").ok().map(String::from),
+            },
+        })
+    }
+}
+
+impl fmt::Display for UserBalanceEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "block {} tx {}: {} {} {}",
+            self.block_height, self.source.tx_index, self.entry_type, self.amt, self.tick
+        )?;
+        match (&self.entry_type, &self.source.counterparty) {
+            (UserBalanceEntryType::Send, Some(counterparty)) => write!(f, " to {}", counterparty)?,
+            (UserBalanceEntryType::Receive, Some(counterparty)) => {
+                write!(f, " from {}", counterparty)?
+            }
+            _ => {}
+        }
+        if !self.source.op.is_empty() {
+            write!(
+                f,
+                " by {} of {} in {}",
+                self.source.op, self.source.inscription_id, self.source.txid
+            )?;
+        }
+        Ok(())
+    }
+}
 
 #[derive(Debug, Clone, Serialize)]
 pub enum UserBalanceEntryType {
@@ -87,6 +254,26 @@ pub enum UserBalanceEntryType {
     Receive,
 }
 
+impl UserBalanceEntryType {
+    // Applies an entry of `amount` to (available_balance, transferable_balance, overall_balance)
+    pub fn apply(&self, balance: &mut (f64, f64, f64), amount: f64) {
+        match self {
+            UserBalanceEntryType::Receive => {
+                balance.0 += amount; // Increase the available balance
+                balance.2 += amount; // Increase the overall balance
+            }
+            UserBalanceEntryType::Send => {
+                balance.1 -= amount; // Decrease the transferable balance
+                balance.2 -= amount; // Decrease the overall balance
+            }
+            UserBalanceEntryType::Inscription => {
+                balance.0 -= amount; // Decrease the available balance
+                balance.1 += amount; // Increase the transferable balance
+            }
+        }
+    }
+}
+
 impl fmt::Display for UserBalanceEntryType {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -97,13 +284,108 @@ impl fmt::Display for UserBalanceEntryType {
     }
 }
 
-impl From<&str> for UserBalanceEntryType {
-    fn from(item: &str) -> Self {
+impl TryFrom<&str> for UserBalanceEntryType {
+    type Error = IndexerError;
+
+    fn try_from(item: &str) -> Result<Self, Self::Error> {
         match item {
-            "inscription" => UserBalanceEntryType::Inscription,
-            "send" => UserBalanceEntryType::Send,
-            "receive" => UserBalanceEntryType::Receive,
-            _ => panic!("Invalid UserBalanceEntryType"),
+            "inscription" => Ok(UserBalanceEntryType::Inscription),
+            "send" => Ok(UserBalanceEntryType::Send),
+            "receive" => Ok(UserBalanceEntryType::Receive),
+            _ => Err(IndexerError::Decode(format!(
+                "Invalid UserBalanceEntryType: {}",
+                item
+            ))),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entry_document_round_trip() {
+        let mut source = EntrySource::new("send", "sendtxid", 12, "transfertxidi0".to_string());
+        source.counterparty = Some("bc1qreceiver".to_string());
+        let entry = UserBalanceEntry::new(
+            "bc1qsender".to_string(),
+            "ordi".to_string(),
+            800_000,
+            10.0,
+            UserBalanceEntryType::Send,
+            source,
+        );
+
+        let decoded = UserBalanceEntry::try_from(&entry.to_document()).unwrap();
+        assert_eq!(decoded.source, entry.source);
+        assert_eq!(
+            decoded.to_string(),
+            "block 800000 tx 12: send 10 ordi to bc1qreceiver by send of transfertxidi0 in sendtxid"
+        );
+
+        // Entries written before they had a source
+        let mut document = entry.to_document();
+        for key in [
+            "op",
+            "txid",
+            "tx_index",
+            "inscription_id",
+            "vout",
+            "counterparty",
+        ] {
+            document.remove(key);
+        }
+        let decoded = UserBalanceEntry::try_from(&document).unwrap();
+        assert_eq!(decoded.source, EntrySource::default());
+        assert_eq!(decoded.to_string(), "block 800000 tx 0: send 10 ordi");
+    }
+
+    #[test]
+    fn test_combine_balance_views() {
+        let balances = BTreeMap::from([
+            ("ordi".to_string(), (90.0, 10.0, 100.0)),
+            ("sats".to_string(), (5.0, 0.0, 5.0)),
+        ]);
+        // A provisional block received 40 ordi and sent away every pepe
+        let confirmed_changes = BTreeMap::from([
+            ("ordi".to_string(), (50.0, 10.0, 60.0)),
+            ("pepe".to_string(), (0.0, 7.0, 7.0)),
+        ]);
+
+        let views = BalanceView::combine(balances, confirmed_changes);
+        assert_eq!(
+            views,
+            vec![
+                BalanceView {
+                    tick: "ordi".to_string(),
+                    confirmed: (50.0, 10.0, 60.0),
+                    pending: (90.0, 10.0, 100.0),
+                },
+                BalanceView {
+                    tick: "pepe".to_string(),
+                    confirmed: (0.0, 7.0, 7.0),
+                    pending: (0.0, 0.0, 0.0),
+                },
+                BalanceView {
+                    tick: "sats".to_string(),
+                    confirmed: (5.0, 0.0, 5.0),
+                    pending: (5.0, 0.0, 5.0),
+                },
+            ]
+        );
+        assert_eq!(
+            views[0].to_string(),
+            "ordi: confirmed 60 (available 50, transferable 10), \
+             pending 100 (available 90, transferable 10)"
+        );
+        assert_eq!(
+            serde_json::to_value(&views[2]).unwrap(),
+            serde_json::json!({
+                "tick": "sats",
+                "confirmed": { "available": 5.0, "transferable": 0.0, "overall": 5.0 },
+                "pending": { "available": 5.0, "transferable": 0.0, "overall": 5.0 },
+            })
+        );
+    }
+}
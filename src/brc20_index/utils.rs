@@ -1,22 +1,32 @@
 use super::{
-    brc20_ticker::Brc20Ticker,
     consts,
+    error::IndexerError,
+    invalid_brc20::InvalidReason,
+    metrics::metrics,
     mongo::MongoClient,
+    retry::{RetryClass, RetryPolicy},
     user_balance::{UserBalance, UserBalanceEntry, UserBalanceEntryType},
     Brc20Inscription, ToDocument,
 };
-use bitcoin::{Address, Network, TxIn};
-use bitcoincore_rpc::{bitcoincore_rpc_json::GetRawTransactionResult, Client, RpcApi};
+use bitcoin::{consensus, Address, Network, Transaction, TxIn};
+use bitcoincore_rpc::bitcoincore_rpc_json::{
+    GetRawTransactionResult, GetRawTransactionResultVin, GetRawTransactionResultVinScriptSig,
+    GetRawTransactionResultVout, GetRawTransactionResultVoutScriptPubKey,
+};
+use bitcoincore_rpc::{Client, RpcApi};
 use log::{debug, error};
 use mongodb::bson::{Bson, Document};
-use serde::Serialize;
 use std::collections::HashMap;
 
 pub fn get_witness_data_from_raw_tx(
     raw_tx_info: &GetRawTransactionResult,
-) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+) -> Result<Vec<String>, IndexerError> {
     let transaction = raw_tx_info.transaction()?;
 
+    Ok(get_witness_data_from_tx(&transaction))
+}
+
+pub fn get_witness_data_from_tx(transaction: &Transaction) -> Vec<String> {
     let mut witness_data_strings: Vec<String> = Vec::new();
 
     // Get the first transaction input
@@ -28,7 +38,65 @@ pub fn get_witness_data_from_raw_tx(
         }
     }
 
-    Ok(witness_data_strings)
+    witness_data_strings
+}
+
+// Builds the RPC view of a transaction that isn't known to the node,
+// fields only the node can know, like the block hash, are left empty
+pub fn raw_tx_from_transaction(transaction: &Transaction) -> GetRawTransactionResult {
+    let vin = transaction
+        .input
+        .iter()
+        .map(|input| {
+            let is_coinbase = input.previous_output.is_null();
+            GetRawTransactionResultVin {
+                sequence: input.sequence.0,
+                coinbase: is_coinbase.then(|| input.script_sig.to_bytes()),
+                txid: (!is_coinbase).then_some(input.previous_output.txid),
+                vout: (!is_coinbase).then_some(input.previous_output.vout),
+                script_sig: (!is_coinbase).then(|| GetRawTransactionResultVinScriptSig {
+                    asm: input.script_sig.to_asm_string(),
+                    hex: input.script_sig.to_bytes(),
+                }),
+                txinwitness: (!is_coinbase).then(|| input.witness.to_vec()),
+            }
+        })
+        .collect();
+
+    let vout = transaction
+        .output
+        .iter()
+        .enumerate()
+        .map(|(n, output)| GetRawTransactionResultVout {
+            value: bitcoin::Amount::from_sat(output.value),
+            n: n as u32,
+            script_pub_key: GetRawTransactionResultVoutScriptPubKey {
+                asm: output.script_pubkey.to_asm_string(),
+                hex: output.script_pubkey.to_bytes(),
+                req_sigs: None,
+                type_: None,
+                addresses: Vec::new(),
+                address: None,
+            },
+        })
+        .collect();
+
+    GetRawTransactionResult {
+        in_active_chain: None,
+        hex: consensus::serialize(transaction),
+        txid: transaction.txid(),
+        hash: transaction.wtxid(),
+        size: transaction.size(),
+        vsize: transaction.vsize(),
+        version: transaction.version as u32,
+        locktime: transaction.lock_time.to_consensus_u32(),
+        vin,
+        vout,
+        blockhash: None,
+        confirmations: None,
+        time: None,
+        blocktime: None,
+    }
 }
 
 // extracts only inscriptions that read "brc-20", many will be invalid
@@ -70,14 +138,17 @@ pub fn extract_and_process_witness_data(witness_data: String) -> Option<Brc20Ins
 pub fn get_owner_of_vout(
     raw_tx_info: &GetRawTransactionResult,
     vout_index: usize,
-) -> Result<Address, anyhow::Error> {
+    network: Network,
+) -> Result<Address, IndexerError> {
     if raw_tx_info.vout.is_empty() {
-        return Err(anyhow::anyhow!("Transaction has no outputs"));
+        return Err(IndexerError::Decode(
+            "Transaction has no outputs".to_string(),
+        ));
     }
 
     if raw_tx_info.vout.len() <= vout_index {
-        return Err(anyhow::anyhow!(
-            "Transaction doesn't have vout at given index"
+        return Err(IndexerError::Decode(
+            "Transaction doesn't have vout at given index".to_string(),
         ));
     }
 
@@ -85,45 +156,56 @@ pub fn get_owner_of_vout(
     let script_pubkey = &raw_tx_info.vout[vout_index].script_pub_key;
     let script = match script_pubkey.script() {
         Ok(script) => script,
-        Err(e) => return Err(anyhow::anyhow!("Failed to get script: {:?}", e)),
+        Err(e) => {
+            return Err(IndexerError::Decode(format!(
+                "Failed to get script: {:?}",
+                e
+            )))
+        }
     };
-    let this_address = Address::from_script(&script, Network::Bitcoin).map_err(|e| {
+    let this_address = Address::from_script(&script, network).map_err(|e| {
         error!("Couldn't derive address from scriptPubKey: {:?}", e);
-        anyhow::anyhow!("Couldn't derive address from scriptPubKey: {:?}", e)
+        IndexerError::Decode(format!(
+            "Couldn't derive address from scriptPubKey: {:?}",
+            e
+        ))
     })?;
 
     Ok(this_address)
 }
 
-pub fn convert_to_float(number_string: &str, decimals: u8) -> Result<f64, &'static str> {
+pub fn convert_to_float(number_string: &str, decimals: u8) -> Result<f64, InvalidReason> {
+    let malformed = || InvalidReason::MalformedNumber {
+        value: number_string.to_string(),
+    };
+
     let parts: Vec<&str> = number_string.split('.').collect();
     match parts.len() {
         1 => {
             // No decimal point in the string
-            let result = number_string.parse::<f64>();
-            match result {
-                Ok(value) => Ok(value),
-                Err(_) => Err("Malformed inscription"),
-            }
+            number_string.parse::<f64>().map_err(|_| malformed())
         }
         2 => {
             // There is a decimal point in the string
             if parts[1].len() > decimals as usize {
                 error!("There are too many digits to the right of the decimal");
-                return Err("There are too many digits to the right of the decimal");
+                Err(InvalidReason::TooManyDecimals {
+                    value: number_string.to_string(),
+                    dec: decimals,
+                })
             } else {
-                let result = number_string.parse::<f64>();
-                match result {
-                    Ok(value) => Ok(value),
-                    Err(_) => Err("Malformed inscription"),
-                }
+                number_string.parse::<f64>().map_err(|_| malformed())
             }
         }
-        _ => Err("Malformed inscription"), // More than one decimal point
+        _ => Err(malformed()), // More than one decimal point
     }
 }
 
-pub fn transaction_inputs_to_values(client: &Client, inputs: &[TxIn]) -> anyhow::Result<Vec<u64>> {
+pub async fn transaction_inputs_to_values(
+    client: &Client,
+    retry_policy: &RetryPolicy,
+    inputs: &[TxIn],
+) -> Result<Vec<u64>, IndexerError> {
     let mut values: Vec<u64> = vec![];
 
     for input in inputs {
@@ -133,16 +215,34 @@ pub fn transaction_inputs_to_values(client: &Client, inputs: &[TxIn]) -> anyhow:
             prev_output.txid, prev_output.vout
         );
 
-        let prev_tx_info = client.get_raw_transaction_info(&prev_output.txid, None)?;
+        let prev_tx_info = retry_policy
+            .run(
+                RetryClass::Rpc,
+                "get_raw_transaction_info",
+                move || async move {
+                    Ok(client.get_raw_transaction_info(&prev_output.txid, None)?)
+                },
+            )
+            .await?;
         let prev_tx = prev_tx_info.transaction()?;
-        let output = &prev_tx.output[usize::try_from(prev_output.vout).unwrap()];
+        let output = prev_tx
+            .output
+            .get(prev_output.vout as usize)
+            .ok_or_else(|| {
+                IndexerError::Decode(format!(
+                    "Previous transaction {} has no vout {}",
+                    prev_output.txid, prev_output.vout
+                ))
+            })?;
 
         // Add the value of the output to the list
         values.push(output.value);
     }
 
     if values.is_empty() {
-        return Err(anyhow::anyhow!("Couldn't derive any values from inputs"));
+        Err(IndexerError::Decode(
+            "Couldn't derive any values from inputs".to_string(),
+        ))
     } else {
         Ok(values)
     }
@@ -153,12 +253,17 @@ pub async fn update_receiver_balance_document(
     user_balance_docs_to_update: &mut HashMap<(String, String), Document>,
     user_balance_docs_to_insert: &mut HashMap<(String, String), Document>,
     user_balance_entry: &UserBalanceEntry,
-) -> Result<(), anyhow::Error> {
+) -> Result<(), IndexerError> {
     // Create the key from the address and ticker
     let key = (
         user_balance_entry.address.to_string(),
         user_balance_entry.tick.clone(),
     );
+    metrics().cache_lookup(
+        "user_balance",
+        user_balance_docs_to_update.contains_key(&key)
+            || user_balance_docs_to_insert.contains_key(&key),
+    );
 
     // Check if the user balance document exists in the 'user_balance_docs_to_update' hashmap
     if let Some(user_balance) = user_balance_docs_to_update.get_mut(&key) {
@@ -179,10 +284,7 @@ pub async fn update_receiver_balance_document(
             update_receiver(user_balance, user_balance_entry)?;
         } else {
             // Load the user balance document from MongoDB with retry
-            let user_balance_doc = mongo_client
-                .load_user_balance_with_retry(&key)
-                .await
-                .map_err(|e| anyhow::anyhow!("{}", e))?;
+            let user_balance_doc = mongo_client.load_user_balance_with_retry(&key).await?;
 
             if let Some(user_balance) = user_balance_doc {
                 // Document found in MongoDB, update it and add it to 'user_balance_docs'
@@ -227,7 +329,7 @@ pub async fn update_receiver_balance_document(
 fn update_receiver(
     user_balance: &mut Document,
     user_balance_entry: &UserBalanceEntry,
-) -> Result<(), anyhow::Error> {
+) -> Result<(), IndexerError> {
     // Get the overall and available balance values from the document
     let overall_balance = user_balance
         .get_f64(consts::OVERALL_BALANCE)
@@ -261,7 +363,7 @@ fn update_receiver(
 pub fn update_sender_or_inscriber_user_balance_document(
     user_balance: &mut Document,
     user_balance_entry: &UserBalanceEntry,
-) -> Result<(), anyhow::Error> {
+) -> Result<(), IndexerError> {
     // Get the available balance, transferable balance, and overall balance values
     let available_balance = user_balance
         .get_f64(consts::AVAILABLE_BALANCE)
@@ -302,7 +404,7 @@ pub fn update_sender_or_inscriber_user_balance_document(
         }
         _ => {
             // Other entry types are not applicable for this function
-            return Err(anyhow::anyhow!("Invalid entry type"));
+            return Err(IndexerError::Protocol("Invalid entry type".to_string()));
         }
     }
 
@@ -320,12 +422,16 @@ pub async fn update_sender_user_balance_document(
     user_balances: &mut HashMap<(String, String), Document>,
     user_balance_docs_to_insert: &mut HashMap<(String, String), Document>,
     user_balance_entry: &UserBalanceEntry,
-) -> Result<(), anyhow::Error> {
+) -> Result<(), IndexerError> {
     // Create the key from the address and ticker
     let key = (
         user_balance_entry.address.to_string(),
         user_balance_entry.tick.clone(),
     );
+    metrics().cache_lookup(
+        "user_balance",
+        user_balances.contains_key(&key) || user_balance_docs_to_insert.contains_key(&key),
+    );
 
     // Check if the user balance document exists in the in-memory hashmap
     if let Some(user_balance) = user_balances.get_mut(&key) {
@@ -340,10 +446,7 @@ pub async fn update_sender_user_balance_document(
             update_sender_or_inscriber_user_balance_document(user_balance, user_balance_entry)?;
         } else {
             // Load the user balance document with retry
-            let user_balance_doc = mongo_client
-                .load_user_balance_with_retry(&key)
-                .await
-                .map_err(|e| anyhow::anyhow!("{}", e))?;
+            let user_balance_doc = mongo_client.load_user_balance_with_retry(&key).await?;
 
             if let Some(user_balance) = user_balance_doc {
                 // Update the existing user balance document
@@ -355,7 +458,9 @@ pub async fn update_sender_user_balance_document(
                 )?;
             } else {
                 // User balance document not found in any hashmap or database
-                return Err(anyhow::anyhow!("User balance document not found"));
+                return Err(IndexerError::Protocol(
+                    "User balance document not found".to_string(),
+                ));
             }
         }
     }
@@ -363,24 +468,42 @@ pub async fn update_sender_user_balance_document(
     Ok(())
 }
 
-//this is for logging to file
-#[derive(Serialize)]
-struct BalanceInfo {
-    overall_balance: f64,
-    available_balance: f64,
-    transferable_balance: f64,
-}
-
-#[derive(Serialize)]
-struct TickerWithBalances {
-    ticker: Brc20Ticker,
-    balances: HashMap<String, BalanceInfo>,
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    // A transfer inscription sent to an output without an address, like OP_RETURN, isn't burned:
+    // the send is skipped and it stays active with its sender, `verify` relies on it
+    #[test]
+    fn test_owner_of_unspendable_vout() {
+        use super::super::error::ErrorAction;
+        use bitcoin::absolute::LockTime;
+        use bitcoin::blockdata::{opcodes::all::OP_RETURN, script::Builder};
+        use bitcoin::hashes::Hash;
+        use bitcoin::{ScriptBuf, TxOut, WPubkeyHash};
+
+        let transaction = Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: Vec::new(),
+            output: vec![
+                TxOut {
+                    value: 0,
+                    script_pubkey: Builder::new().push_opcode(OP_RETURN).into_script(),
+                },
+                TxOut {
+                    value: 546,
+                    script_pubkey: ScriptBuf::new_v0_p2wpkh(&WPubkeyHash::from_byte_array([1; 20])),
+                },
+            ],
+        };
+        let raw_tx = raw_tx_from_transaction(&transaction);
+
+        let error = get_owner_of_vout(&raw_tx, 0, Network::Bitcoin).unwrap_err();
+        assert_eq!(error.action(), ErrorAction::Skip);
+        assert!(get_owner_of_vout(&raw_tx, 1, Network::Bitcoin).is_ok());
+    }
+
     #[test]
     fn test_convert_to_float_no_decimal() {
         let result = convert_to_float("1000", 2);
@@ -398,7 +521,13 @@ mod tests {
     #[test]
     fn test_convert_to_float_too_many_decimals() {
         let result = convert_to_float("1234.567", 2);
-        assert!(result.is_err());
+        assert_eq!(
+            result.unwrap_err(),
+            InvalidReason::TooManyDecimals {
+                value: "1234.567".to_string(),
+                dec: 2
+            }
+        );
     }
 
     #[test]
@@ -0,0 +1,378 @@
+use super::consts;
+use super::error::IndexerError;
+use super::mongo::MongoClient;
+use super::user_balance::UserBalanceEntryType;
+use futures_util::StreamExt;
+use mongodb::bson::doc;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+
+// Amounts are stored as f64, sums of many mints drift by a few ulps
+const TOLERANCE: f64 = 1e-6;
+
+// (available_balance, transferable_balance, overall_balance) by address and tick
+type Balances = BTreeMap<(String, String), (f64, f64, f64)>;
+
+// A check that failed, with the values that disagree
+#[derive(Debug, Clone, PartialEq)]
+pub struct Discrepancy {
+    pub check: &'static str,
+    pub tick: String,
+    // The address of a balance, None for checks of a whole ticker
+    pub address: Option<String>,
+    pub expected: f64,
+    pub actual: f64,
+}
+
+impl fmt::Display for Discrepancy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} [{}]", self.check, self.tick)?;
+        if let Some(address) = &self.address {
+            write!(f, " {}", address)?;
+        }
+        write!(f, ": expected {}, found {}", self.expected, self.actual)
+    }
+}
+
+fn differs(a: f64, b: f64) -> bool {
+    (a - b).abs() > TOLERANCE * a.abs().max(b.abs()).max(1.0)
+}
+
+// Records a discrepancy if the values differ
+fn compare(
+    discrepancies: &mut Vec<Discrepancy>,
+    check: &'static str,
+    (address, tick): (Option<&str>, &str),
+    expected: f64,
+    actual: f64,
+) {
+    if differs(expected, actual) {
+        discrepancies.push(Discrepancy {
+            check,
+            tick: tick.to_string(),
+            address: address.map(String::from),
+            expected,
+            actual,
+        });
+    }
+}
+
+/// Checks the indexed data for internal consistency without modifying it.
+///
+/// Every ticker's `total_minted` must equal the sum of its valid mints, and the overall balances
+/// of the ticker must add up to it. This indexer never burns: a transfer inscription sent to an
+/// output without an address, like OP_RETURN, isn't applied and stays active with its sender,
+/// so nothing leaves the balances. Every user balance must have an
+/// overall balance of its available plus its transferable balance, a transferable balance of
+/// the amounts of its active transfers, the balances a replay of its balance entries arrives
+/// at, and no negative balance.
+pub async fn verify(mongo_client: &MongoClient) -> Result<Vec<Discrepancy>, IndexerError> {
+    let mut discrepancies = Vec::new();
+    let total_minted = verify_mints(mongo_client, &mut discrepancies).await?;
+
+    let balances = load_balances(mongo_client).await?;
+    let active_transfers = sum_active_transfers(mongo_client).await?;
+    let replayed = replay_entries(mongo_client).await?;
+    discrepancies.extend(check_balances(
+        &total_minted,
+        &balances,
+        &active_transfers,
+        &replayed,
+    ));
+
+    Ok(discrepancies)
+}
+
+// Checks total_minted against the mints, returns it by tick
+async fn verify_mints(
+    mongo_client: &MongoClient,
+    discrepancies: &mut Vec<Discrepancy>,
+) -> Result<HashMap<String, f64>, IndexerError> {
+    let mut minted: HashMap<String, f64> = HashMap::new();
+    let mut cursor = mongo_client
+        .find_with_retries(
+            consts::COLLECTION_MINTS,
+            Some(doc! { "is_valid": true }),
+            None,
+        )
+        .await?;
+    while let Some(result) = cursor.next().await {
+        let mint = result?;
+        let tick = mint.get_document("inscription")?.get_str("tick")?;
+        *minted.entry(tick.to_lowercase()).or_default() += mint.get_f64("amt")?;
+    }
+
+    let mut total_minted_by_tick = HashMap::new();
+    let mut cursor = mongo_client
+        .find_with_retries(consts::COLLECTION_TICKERS, None, None)
+        .await?;
+    while let Some(result) = cursor.next().await {
+        let ticker = result?;
+        let tick = ticker.get_str("tick")?.to_lowercase();
+        let total_minted = ticker.get_f64("total_minted")?;
+        let expected = minted.remove(&tick).unwrap_or_default();
+        compare(
+            discrepancies,
+            "total_minted equals the sum of mints",
+            (None, &tick),
+            expected,
+            total_minted,
+        );
+        total_minted_by_tick.insert(tick, total_minted);
+    }
+
+    // Mints of a ticker that doesn't exist
+    for (tick, amount) in minted {
+        discrepancies.push(Discrepancy {
+            check: "mints belong to a deployed ticker",
+            tick,
+            address: None,
+            expected: 0.0,
+            actual: amount,
+        });
+    }
+
+    Ok(total_minted_by_tick)
+}
+
+async fn load_balances(mongo_client: &MongoClient) -> Result<Balances, IndexerError> {
+    let mut balances = Balances::new();
+    let mut cursor = mongo_client
+        .find_with_retries(consts::COLLECTION_USER_BALANCES, None, None)
+        .await?;
+    while let Some(result) = cursor.next().await {
+        let document = result?;
+        let key = (
+            document.get_str("address")?.to_string(),
+            document.get_str("tick")?.to_lowercase(),
+        );
+        balances.insert(
+            key,
+            (
+                document.get_f64(consts::AVAILABLE_BALANCE)?,
+                document.get_f64(consts::TRANSFERABLE_BALANCE)?,
+                document.get_f64(consts::OVERALL_BALANCE)?,
+            ),
+        );
+    }
+
+    Ok(balances)
+}
+
+// The amounts of the unsent transfer inscriptions by address and tick
+async fn sum_active_transfers(
+    mongo_client: &MongoClient,
+) -> Result<HashMap<(String, String), f64>, IndexerError> {
+    let mut transferable = HashMap::new();
+    let mut cursor = mongo_client
+        .find_with_retries(consts::COLLECTION_BRC20_ACTIVE_TRANSFERS, None, None)
+        .await?;
+    while let Some(result) = cursor.next().await {
+        let document = result?;
+        let key = (
+            document.get_str("address")?.to_string(),
+            document.get_str("tick")?.to_lowercase(),
+        );
+        *transferable.entry(key).or_default() += document.get_f64("amt")?;
+    }
+
+    Ok(transferable)
+}
+
+// The balances every balance entry adds up to, as rebuild-balances would write them
+async fn replay_entries(mongo_client: &MongoClient) -> Result<Balances, IndexerError> {
+    let mut replayed = Balances::new();
+    let mut cursor = mongo_client
+        .find_with_retries(consts::COLLECTION_USER_BALANCE_ENTRY, None, None)
+        .await?;
+    while let Some(result) = cursor.next().await {
+        let document = result?;
+        let key = (
+            document.get_str("address")?.to_string(),
+            document.get_str("tick")?.to_lowercase(),
+        );
+        let entry_type = UserBalanceEntryType::try_from(document.get_str("entry_type")?)?;
+        entry_type.apply(replayed.entry(key).or_default(), document.get_f64("amt")?);
+    }
+
+    Ok(replayed)
+}
+
+fn check_balances(
+    total_minted: &HashMap<String, f64>,
+    balances: &Balances,
+    active_transfers: &HashMap<(String, String), f64>,
+    replayed: &Balances,
+) -> Vec<Discrepancy> {
+    let mut discrepancies = Vec::new();
+    let mut supply: BTreeMap<&str, f64> = BTreeMap::new();
+
+    for ((address, tick), &(available, transferable, overall)) in balances {
+        let key = (Some(address.as_str()), tick.as_str());
+        *supply.entry(tick).or_default() += overall;
+
+        compare(
+            &mut discrepancies,
+            "overall balance equals available plus transferable",
+            key,
+            available + transferable,
+            overall,
+        );
+        for (check, balance) in [
+            ("available balance is not negative", available),
+            ("transferable balance is not negative", transferable),
+            ("overall balance is not negative", overall),
+        ] {
+            if balance < 0.0 {
+                compare(&mut discrepancies, check, key, 0.0, balance);
+            }
+        }
+
+        let sent = active_transfers
+            .get(&(address.clone(), tick.clone()))
+            .copied()
+            .unwrap_or_default();
+        compare(
+            &mut discrepancies,
+            "transferable balance equals its active transfers",
+            key,
+            sent,
+            transferable,
+        );
+
+        let (replayed_available, replayed_transferable, replayed_overall) = replayed
+            .get(&(address.clone(), tick.clone()))
+            .copied()
+            .unwrap_or_default();
+        compare(
+            &mut discrepancies,
+            "available balance equals the replayed entries",
+            key,
+            replayed_available,
+            available,
+        );
+        compare(
+            &mut discrepancies,
+            "transferable balance equals the replayed entries",
+            key,
+            replayed_transferable,
+            transferable,
+        );
+        compare(
+            &mut discrepancies,
+            "overall balance equals the replayed entries",
+            key,
+            replayed_overall,
+            overall,
+        );
+    }
+
+    // Active transfers and entries of balances that aren't stored, zeroed balances aren't
+    let unstored: BTreeSet<&(String, String)> = active_transfers
+        .keys()
+        .chain(replayed.keys())
+        .filter(|key| !balances.contains_key(*key))
+        .collect();
+    for key in unstored {
+        let sent = active_transfers.get(key).copied().unwrap_or_default();
+        let (available, transferable, overall) = replayed.get(key).copied().unwrap_or_default();
+        for (check, expected) in [
+            ("transferable balance equals its active transfers", sent),
+            ("available balance equals the replayed entries", available),
+            (
+                "transferable balance equals the replayed entries",
+                transferable,
+            ),
+            ("overall balance equals the replayed entries", overall),
+        ] {
+            compare(
+                &mut discrepancies,
+                check,
+                (Some(&key.0), &key.1),
+                expected,
+                0.0,
+            );
+        }
+    }
+
+    let mut ticks: Vec<&str> = total_minted.keys().map(String::as_str).collect();
+    ticks.extend(
+        supply
+            .keys()
+            .filter(|tick| !total_minted.contains_key(**tick)),
+    );
+    ticks.sort_unstable();
+    for tick in ticks {
+        compare(
+            &mut discrepancies,
+            "overall balances add up to total_minted",
+            (None, tick),
+            total_minted.get(tick).copied().unwrap_or_default(),
+            supply.get(tick).copied().unwrap_or_default(),
+        );
+    }
+
+    discrepancies
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_balances() {
+        let key = |address: &str| (address.to_string(), "ordi".to_string());
+        let total_minted = HashMap::from([("ordi".to_string(), 1000.0)]);
+        let active_transfers = HashMap::from([(key("bc1qa"), 100.0), (key("bc1qc"), 5.0)]);
+        let replayed = Balances::from([
+            (key("bc1qa"), (500.0, 100.0, 600.0)),
+            (key("bc1qb"), (400.0, 0.0, 400.0)),
+        ]);
+
+        let consistent = Balances::from([
+            (key("bc1qa"), (500.0, 100.0, 600.0)),
+            (key("bc1qb"), (400.0, 0.0, 400.0)),
+        ]);
+        let discrepancies =
+            check_balances(&total_minted, &consistent, &active_transfers, &replayed);
+        // Only the active transfer of bc1qc has no balance
+        assert_eq!(
+            discrepancies,
+            vec![Discrepancy {
+                check: "transferable balance equals its active transfers",
+                tick: "ordi".to_string(),
+                address: Some("bc1qc".to_string()),
+                expected: 5.0,
+                actual: 0.0,
+            }]
+        );
+
+        let active_transfers = HashMap::from([(key("bc1qa"), 100.0)]);
+        let inconsistent = Balances::from([
+            (key("bc1qa"), (500.0, 100.0, 610.0)),
+            (key("bc1qb"), (400.0, 0.0, 400.0)),
+        ]);
+        let checks: Vec<&str> =
+            check_balances(&total_minted, &inconsistent, &active_transfers, &replayed)
+                .iter()
+                .map(|discrepancy| discrepancy.check)
+                .collect();
+        assert_eq!(
+            checks,
+            vec![
+                "overall balance equals available plus transferable",
+                "overall balance equals the replayed entries",
+                "overall balances add up to total_minted",
+            ]
+        );
+
+        let negative = Balances::from([(key("bc1qb"), (-1.0, 0.0, -1.0))]);
+        let discrepancies = check_balances(&HashMap::new(), &negative, &HashMap::new(), &negative);
+        assert_eq!(discrepancies.len(), 3);
+        assert_eq!(discrepancies[0].check, "available balance is not negative");
+        assert_eq!(
+            discrepancies[2].to_string(),
+            "overall balances add up to total_minted [ordi]: expected 0, found -1"
+        );
+    }
+}
@@ -0,0 +1,633 @@
+use crate::brc20_index::{
+    consts,
+    error::IndexerError,
+    health::health,
+    index_brc20,
+    inspect::{find_indexed, parse_envelopes, trace_inscription, Verdict},
+    mempool::MempoolWatcher,
+    mongo::MongoClient,
+    rollback_to_block_height,
+    schema::{check_schema, migrate, schema_state, SCHEMA_VERSION},
+    shutdown::Shutdown,
+    simulate::{dry_run, parse_transaction, Effect},
+    transfer_status::{get_transfer_statuses, parse_inscription_ref},
+    utils::{
+        extract_and_process_witness_data, get_owner_of_vout, get_witness_data_from_tx,
+        raw_tx_from_transaction,
+    },
+    verify::verify,
+    Brc20Inscription,
+};
+use crate::config::Config;
+use crate::server;
+use bitcoin::{consensus, Network, Transaction, Txid};
+use bitcoincore_rpc::{Client, RpcApi};
+use futures_util::StreamExt;
+use log::{debug, info, warn};
+use mongodb::bson::Bson;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, OnceLock};
+use std::time::Instant;
+
+// Connects to Bitcoin Core and makes sure the node is on the configured network
+fn connect_rpc(config: &Config) -> Result<Client, IndexerError> {
+    let rpc = Client::new(&config.rpc.url, config.rpc.auth.clone())?;
+    info!("Connected to Bitcoin Core");
+
+    let chain = rpc.get_blockchain_info()?.chain;
+    if chain != config.network.to_core_arg() {
+        return Err(IndexerError::Config(format!(
+            "network is {} but the node is on {}",
+            config.network, chain
+        )));
+    }
+
+    Ok(rpc)
+}
+
+// Connects to MongoDB and makes sure the database doesn't belong to another network. Nothing
+// is written, `index` and `migrate` record the network of a new database.
+async fn open_mongo(config: &Config) -> Result<MongoClient, IndexerError> {
+    let mongo_client = MongoClient::new(
+        &config.mongo.uri,
+        &config.mongo.db_name,
+        config.mongo.direct_connection,
+        config.retry_policy.clone(),
+    )
+    .await?;
+
+    // Never touch a database of another network
+    mongo_client.check_network(config.network).await?;
+
+    Ok(mongo_client)
+}
+
+// Opens the database and makes sure it's at the schema version of this indexer
+async fn connect_mongo(config: &Config) -> Result<MongoClient, IndexerError> {
+    let mongo_client = open_mongo(config).await?;
+    check_schema(&mongo_client, &config.mongo.db_name).await?;
+
+    Ok(mongo_client)
+}
+
+// Indexes from the last completed block, or from `from` after rolling back to it,
+// up to `to` or forever
+pub async fn index(
+    config: &Config,
+    from: Option<i64>,
+    to: Option<i64>,
+) -> Result<(), IndexerError> {
+    info!("Configuration: {:?}", config);
+
+    let mut config = config.clone();
+    if to.is_some() {
+        config.stop_height = to;
+    }
+
+    let api = Arc::new(OnceLock::new());
+    if let Some(addr) = config.http_addr {
+        server::serve(addr, config.health, api.clone())?;
+    }
+
+    let rpc = connect_rpc(&config)?;
+    health().node_reachable(true);
+    let mongo_client = open_mongo(&config).await?;
+    mongo_client.ensure_network(config.network).await?;
+    health().storage_reachable(true);
+
+    // Brings the schema up to date, creating the indexes on the way
+    migrate(&mongo_client, &config.mongo.db_name).await?;
+
+    // The watcher has its own connections, it runs next to the indexer until the process exits
+    if let Some(poll_interval) = config.mempool_poll_interval {
+        let watcher = MempoolWatcher::new(
+            connect_rpc(&config)?,
+            connect_mongo(&config).await?,
+            config.clone(),
+        );
+        tokio::spawn(watcher.run(poll_interval));
+    }
+
+    // The API is served with its own connections too
+    if config.http_addr.is_some() {
+        let _ = api.set(server::Api {
+            rpc: connect_rpc(&config)?,
+            mongo_client: connect_mongo(&config).await?,
+            config: config.clone(),
+        });
+    }
+
+    let start = Instant::now();
+    // get block height to start indexing from
+    let last_completed_block = mongo_client.get_last_completed_block_height().await?;
+    let next_block_height = match last_completed_block {
+        Some(height) => height + 1,  // Start from the next block
+        None => config.start_height, // default starting point
+    };
+
+    let start_block_height = match from {
+        Some(from) => {
+            let activation_height = consts::brc20_starting_block_height(config.network);
+            if from < activation_height {
+                return Err(IndexerError::Config(format!(
+                    "--from {} is below the activation height {} of {}",
+                    from, activation_height, config.network
+                )));
+            }
+            // Skipping blocks would leave balances that don't add up
+            if last_completed_block.is_some() && from > next_block_height {
+                return Err(IndexerError::Config(format!(
+                    "--from {} would skip blocks, the next block to index is {}",
+                    from, next_block_height
+                )));
+            }
+            from
+        }
+        None => next_block_height,
+    };
+    info!(
+        "Starting indexing from block height: {}",
+        start_block_height
+    );
+
+    debug!("Retrieved starting block height: {:?}", start.elapsed());
+
+    // Reproducible rebuilds need to stop at the same block every time
+    if let Some(stop_height) = config.stop_height {
+        if start_block_height > stop_height + 1 {
+            warn!(
+                "Already indexed beyond the stop height {}, roll back with `rollback --to {}`",
+                stop_height, stop_height
+            );
+        }
+    }
+
+    // delete everything in db that is >= start_block_height, including a block
+    // that wasn't completed
+    if last_completed_block.is_some() || config.start_height < start_block_height {
+        rollback_to_block_height(&mongo_client, start_block_height).await?;
+    }
+
+    let start_block_height = u32::try_from(start_block_height).map_err(|_| {
+        IndexerError::Config(format!(
+            "invalid start block height: {}",
+            start_block_height
+        ))
+    })?;
+
+    let shutdown = Shutdown::listen()?;
+
+    // LFG!
+    index_brc20(&rpc, &mongo_client, &config, start_block_height, &shutdown).await?;
+    info!("Finished indexing BRC20 tokens");
+
+    Ok(())
+}
+
+// Deletes everything indexed above `to`, so indexing resumes at `to + 1`
+pub async fn rollback(config: &Config, to: i64) -> Result<(), IndexerError> {
+    let mongo_client = connect_mongo(config).await?;
+
+    let last_completed_block = mongo_client.get_last_completed_block_height().await?;
+    match last_completed_block {
+        Some(height) if height > to => {
+            rollback_to_block_height(&mongo_client, to + 1).await?;
+            println!("Rolled back from block {} to block {}", height, to);
+        }
+        Some(height) => println!("Last completed block is {}, nothing to roll back", height),
+        None => println!("Nothing has been indexed yet"),
+    }
+
+    Ok(())
+}
+
+// Rebuilds every user balance from the balance entries
+pub async fn rebuild_balances(config: &Config) -> Result<(), IndexerError> {
+    let mongo_client = connect_mongo(config).await?;
+
+    let next_block_height = match mongo_client.get_last_completed_block_height().await? {
+        Some(height) => height + 1,
+        None => {
+            println!("Nothing has been indexed yet");
+            return Ok(());
+        }
+    };
+
+    let start = Instant::now();
+    let (rebuilt, deleted) = mongo_client
+        .rebuild_user_balances(next_block_height)
+        .await?;
+    println!(
+        "Rebuilt {} user balances and deleted {} without entries in {:?}",
+        rebuilt,
+        deleted,
+        start.elapsed()
+    );
+
+    Ok(())
+}
+
+// Recomputes total_minted of every ticker from its mints
+pub async fn recompute_supply(config: &Config) -> Result<(), IndexerError> {
+    let mongo_client = connect_mongo(config).await?;
+
+    let start = Instant::now();
+    let mut cursor = mongo_client
+        .find_with_retries(consts::COLLECTION_TICKERS, None, None)
+        .await?;
+    let mut count = 0;
+    while let Some(result) = cursor.next().await {
+        let mut ticker_doc = result?;
+        mongo_client
+            .calculate_and_update_total_minted_for_ticker(&mut ticker_doc)
+            .await?;
+        count += 1;
+    }
+    println!(
+        "Recomputed total_minted of {} tickers in {:?}",
+        count,
+        start.elapsed()
+    );
+
+    Ok(())
+}
+
+// Brings the database to the schema version of this indexer, or lists what that takes
+pub async fn migrate_schema(config: &Config, dry_run: bool) -> Result<(), IndexerError> {
+    let mongo_client = open_mongo(config).await?;
+
+    let state = match schema_state(&mongo_client).await? {
+        Some(state) => state,
+        None => {
+            println!(
+                "Database {} isn't indexed yet, it starts at schema version {}",
+                config.mongo.db_name, SCHEMA_VERSION
+            );
+            if !dry_run {
+                mongo_client.ensure_network(config.network).await?;
+                migrate(&mongo_client, &config.mongo.db_name).await?;
+            }
+            return Ok(());
+        }
+    };
+    println!(
+        "Database {} is at schema version {}, this indexer uses {}",
+        config.mongo.db_name, state.version, SCHEMA_VERSION
+    );
+    if dry_run {
+        for migration in state.pending() {
+            println!("  - {}: {}", migration.version, migration.description);
+        }
+        return Ok(());
+    }
+    mongo_client.ensure_network(config.network).await?;
+
+    let start = Instant::now();
+    let applied = migrate(&mongo_client, &config.mongo.db_name).await?;
+    for migration in &applied {
+        println!("  - {}: {}", migration.version, migration.description);
+    }
+    println!(
+        "Applied {} migrations in {:?}",
+        applied.len(),
+        start.elapsed()
+    );
+
+    Ok(())
+}
+
+// Checks the indexed data, returns whether it is consistent
+pub async fn verify_index(config: &Config) -> Result<bool, IndexerError> {
+    let mongo_client = connect_mongo(config).await?;
+
+    let discrepancies = verify(&mongo_client).await?;
+    println!(
+        "Balances of a ticker must add up to its total_minted: this indexer never burns, a \
+         transfer sent to an output without an address stays with its sender"
+    );
+    if discrepancies.is_empty() {
+        println!("No discrepancies found");
+        return Ok(true);
+    }
+
+    println!("Found {} discrepancies:", discrepancies.len());
+    for discrepancy in &discrepancies {
+        println!("  - {}", discrepancy);
+    }
+
+    Ok(false)
+}
+
+// Writes each collection as relaxed extended JSON, one document per line
+pub async fn export(
+    config: &Config,
+    out: &Path,
+    collections: &[String],
+) -> Result<(), IndexerError> {
+    let mongo_client = open_mongo(config).await?;
+
+    let collections: Vec<&str> = if collections.is_empty() {
+        consts::COLLECTIONS.to_vec()
+    } else {
+        collections.iter().map(String::as_str).collect()
+    };
+
+    std::fs::create_dir_all(out)
+        .map_err(|e| IndexerError::Config(format!("can't create {}: {}", out.display(), e)))?;
+
+    for collection in collections {
+        let path = out.join(format!("{}.jsonl", collection));
+        let write_error = |e: std::io::Error| {
+            IndexerError::Config(format!("can't write {}: {}", path.display(), e))
+        };
+        let mut writer = BufWriter::new(File::create(&path).map_err(write_error)?);
+
+        let mut cursor = mongo_client
+            .find_with_retries(collection, None, None)
+            .await?;
+        let mut count = 0;
+        while let Some(result) = cursor.next().await {
+            let json = Bson::Document(result?).into_relaxed_extjson();
+            writeln!(writer, "{}", json).map_err(write_error)?;
+            count += 1;
+        }
+        writer.flush().map_err(write_error)?;
+
+        println!("Exported {} documents to {}", count, path.display());
+    }
+
+    Ok(())
+}
+
+// Decodes a transaction, given as hex or as a txid looked up over RPC, and validates its
+// inscriptions against the indexed state without writing anything. Without a valid
+// configuration only the hex can be decoded and nothing is validated.
+pub async fn decode_tx(
+    config: Result<Config, Vec<String>>,
+    tx: &str,
+    network: Network,
+    as_of: Option<i64>,
+) -> Result<(), IndexerError> {
+    let tx = tx.trim();
+    let network = config.as_ref().map_or(network, |config| config.network);
+
+    let raw_tx = match Txid::from_str(tx) {
+        Ok(txid) => {
+            let config = config.as_ref().map_err(|problems| {
+                IndexerError::Config(format!(
+                    "looking up a txid needs RPC: {}",
+                    problems.join("; ")
+                ))
+            })?;
+            connect_rpc(config)?.get_raw_transaction_info(&txid, None)?
+        }
+        Err(_) => {
+            let bytes = hex::decode(tx)
+                .map_err(|e| IndexerError::Decode(format!("Invalid transaction hex: {}", e)))?;
+            let transaction: Transaction = consensus::deserialize(&bytes)
+                .map_err(|e| IndexerError::Decode(format!("Invalid transaction: {}", e)))?;
+            raw_tx_from_transaction(&transaction)
+        }
+    };
+    let transaction = raw_tx.transaction()?;
+
+    println!("txid: {}", raw_tx.txid);
+    if let Some(block_hash) = raw_tx.blockhash {
+        println!("block: {}", block_hash);
+    }
+
+    let envelopes = parse_envelopes(&transaction);
+    if envelopes.is_empty() {
+        println!("envelopes: none");
+    }
+    for envelope in &envelopes {
+        println!(
+            "envelope in input {}: content type {}, body {}",
+            envelope.input,
+            envelope.content_type.as_deref().unwrap_or("none"),
+            String::from_utf8_lossy(&envelope.body)
+        );
+    }
+
+    // The indexer only reads the witness of the first input
+    let inscriptions: Vec<Brc20Inscription> = get_witness_data_from_tx(&transaction)
+        .into_iter()
+        .filter_map(extract_and_process_witness_data)
+        .collect();
+    if inscriptions.is_empty() {
+        println!("no BRC20 inscription found");
+        return Ok(());
+    }
+
+    let owner = get_owner_of_vout(&raw_tx, 0, network);
+    match &owner {
+        Ok(owner) => println!("owner: {}", owner),
+        Err(e) => println!("owner: unknown, {}", e),
+    }
+
+    let config = match config {
+        Ok(config) => config,
+        Err(problems) => {
+            println!(
+                "not validated, the configuration is invalid: {}",
+                problems.join("; ")
+            );
+            for inscription in inscriptions {
+                println!("inscription: {}", inscription);
+            }
+            return Ok(());
+        }
+    };
+    let mongo_client = connect_mongo(&config).await?;
+
+    for indexed in find_indexed(&mongo_client, &raw_tx.txid.to_string()).await? {
+        println!("{}", indexed);
+    }
+
+    for (inscription_index, inscription) in inscriptions.into_iter().enumerate() {
+        let inscription_id = format!("{}i{}", raw_tx.txid, inscription_index);
+        println!("inscription {}: {}", inscription_id, inscription);
+
+        // Inscriptions without an owner are skipped by the indexer
+        let owner = match &owner {
+            Ok(owner) => owner.clone(),
+            Err(_) => {
+                println!("  result: skipped, the owner is unknown");
+                continue;
+            }
+        };
+
+        let trace = trace_inscription(
+            &mongo_client,
+            &raw_tx,
+            inscription,
+            inscription_id,
+            owner,
+            as_of,
+        )
+        .await?;
+        for step in &trace.steps {
+            println!("  {}", step);
+        }
+        match trace.verdict {
+            Verdict::Valid(effect) => println!("  result: valid, {}", effect),
+            Verdict::Invalid(reasons) => println!("  result: invalid, {}", reasons.join("; ")),
+            Verdict::Skipped(reason) => println!("  result: skipped, {}", reason),
+        }
+    }
+
+    Ok(())
+}
+
+// Simulates a transaction or PSBT on top of the indexed state and prints the predicted
+// events and balance deltas as JSON, the same as POST /simulate
+pub async fn simulate(config: &Config, tx: &str) -> Result<(), IndexerError> {
+    let transaction = parse_transaction(tx)?;
+    let rpc = connect_rpc(config)?;
+    let mongo_client = connect_mongo(config).await?;
+
+    let dry_run = dry_run(&rpc, &mongo_client, config, &transaction).await?;
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&dry_run).unwrap_or_default()
+    );
+
+    Ok(())
+}
+
+// Prints whether a transfer inscription, given by inscription id or outpoint, can still be sent
+pub async fn transfer(config: &Config, inscription: &str) -> Result<(), IndexerError> {
+    let inscription_ref = parse_inscription_ref(inscription)?;
+    let mongo_client = connect_mongo(config).await?;
+
+    let statuses = get_transfer_statuses(&mongo_client, &inscription_ref).await?;
+    if statuses.is_empty() {
+        println!("No valid transfer inscription at {}", inscription_ref);
+    }
+    for status in statuses {
+        println!("{}", status);
+    }
+
+    Ok(())
+}
+
+// Prints the unsent transfer inscriptions of an address and how much of each tick they hold,
+// next to its transferable balance
+pub async fn transfers(
+    config: &Config,
+    address: &str,
+    tick: Option<&str>,
+) -> Result<(), IndexerError> {
+    let mongo_client = connect_mongo(config).await?;
+
+    let active_transfers = mongo_client.get_active_transfers(address, tick).await?;
+    if active_transfers.is_empty() {
+        println!("No active transfer inscriptions found for {}", address);
+    }
+    let mut totals = BTreeMap::new();
+    for active_transfer in &active_transfers {
+        println!("  {}", active_transfer);
+        *totals.entry(active_transfer.tick.clone()).or_insert(0.0) += active_transfer.amt;
+    }
+
+    for (tick, total) in totals {
+        let transferable = mongo_client
+            .load_user_balance_with_retry(&(address.to_string(), tick.clone()))
+            .await?
+            .and_then(|balance| balance.get_f64(consts::TRANSFERABLE_BALANCE).ok())
+            .unwrap_or_default();
+        println!(
+            "{}: {} in transfer inscriptions, transferable balance {}",
+            tick, total, transferable
+        );
+    }
+
+    Ok(())
+}
+
+// Prints a page of the ledger entries of an address, newest first
+pub async fn activity(
+    config: &Config,
+    address: &str,
+    offset: u64,
+    limit: i64,
+) -> Result<(), IndexerError> {
+    let mongo_client = connect_mongo(config).await?;
+
+    let entries = mongo_client
+        .get_address_activity(address, offset, limit)
+        .await?;
+    if entries.is_empty() {
+        println!("No activity found for {}", address);
+    }
+    for entry in entries {
+        println!("  {}", entry);
+    }
+
+    Ok(())
+}
+
+// Prints the balances of an address, the pending ones only where provisional blocks changed them
+pub async fn balance(config: &Config, address: &str) -> Result<(), IndexerError> {
+    let mongo_client = connect_mongo(config).await?;
+
+    match mongo_client.get_final_block_height().await? {
+        Some(height) => println!("final block: {}", height),
+        None => println!("final block: none"),
+    }
+
+    let views = mongo_client.get_balance_views(address).await?;
+    if views.is_empty() {
+        println!("No balances found for {}", address);
+    }
+    for view in views {
+        println!("  {}", view);
+    }
+
+    let effects = mongo_client.get_mempool_effects(address).await?;
+    if !effects.is_empty() {
+        println!("mempool:");
+    }
+    for effect in effects {
+        println!("  {}", Effect::try_from(&effect)?);
+    }
+
+    Ok(())
+}
+
+// Prints the number of documents of each collection and the indexing progress
+pub async fn stats(config: &Config) -> Result<(), IndexerError> {
+    let mongo_client = open_mongo(config).await?;
+
+    println!("network: {}", config.network);
+    println!("database: {}", config.mongo.db_name);
+    match schema_state(&mongo_client).await? {
+        Some(schema) => println!(
+            "schema version: {} ({} migrations pending)",
+            schema.version,
+            schema.pending().len()
+        ),
+        None => println!("schema version: none, not indexed yet"),
+    }
+    match mongo_client.get_last_completed_block_height().await? {
+        Some(height) => println!("last completed block: {}", height),
+        None => println!("last completed block: none"),
+    }
+    match mongo_client.get_final_block_height().await? {
+        Some(height) => println!("final block: {}", height),
+        None => println!("final block: none"),
+    }
+
+    for collection in consts::COLLECTIONS {
+        let count = mongo_client
+            .count_documents_with_retries(collection, None)
+            .await?;
+        println!("{:<32} {:>12}", collection, count);
+    }
+
+    Ok(())
+}
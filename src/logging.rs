@@ -0,0 +1,98 @@
+use crate::brc20_index::progress;
+use opentelemetry::KeyValue;
+use opentelemetry_sdk::{runtime, trace, Resource};
+use std::env;
+use std::io::{self, IsTerminal, Write};
+use tracing_subscriber::{
+    layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer, Registry,
+};
+
+// Logs are written to stderr as text unless LOG_FORMAT=json. The level is set with RUST_LOG as before,
+// records of the `log` macros are forwarded to the same subscriber.
+const LOG_FORMAT: &str = "LOG_FORMAT";
+// Setting the standard OTLP endpoint variable exports the spans to that collector over HTTP
+const OTLP_ENDPOINT: &str = "OTEL_EXPORTER_OTLP_ENDPOINT";
+
+// Flushes the exported spans when dropped
+pub struct Logging {
+    exporting: bool,
+}
+
+impl Drop for Logging {
+    fn drop(&mut self) {
+        if self.exporting {
+            opentelemetry::global::shutdown_tracer_provider();
+        }
+    }
+}
+
+type BoxedLayer =
+    Box<dyn Layer<tracing_subscriber::layer::Layered<EnvFilter, Registry>> + Send + Sync>;
+
+// Installs the global subscriber, must be called once from within the tokio runtime
+pub fn init() -> Result<Logging, String> {
+    let mut layers: Vec<BoxedLayer> = Vec::new();
+
+    match env::var(LOG_FORMAT).as_deref() {
+        Ok("json") => layers.push(
+            tracing_subscriber::fmt::layer()
+                .with_writer(|| StderrWriter)
+                .json()
+                .with_current_span(true)
+                .with_span_list(true)
+                .boxed(),
+        ),
+        Ok("text") | Err(_) => layers.push(
+            tracing_subscriber::fmt::layer()
+                .with_writer(|| StderrWriter)
+                .with_ansi(io::stderr().is_terminal())
+                .boxed(),
+        ),
+        Ok(other) => {
+            return Err(format!(
+                "{} must be text or json, got {:?}",
+                LOG_FORMAT, other
+            ))
+        }
+    }
+
+    let exporting = env::var_os(OTLP_ENDPOINT).is_some();
+    if exporting {
+        // The exporter reads the endpoint and the other OTEL_EXPORTER_OTLP_* variables itself
+        let tracer =
+            opentelemetry_otlp::new_pipeline()
+                .tracing()
+                .with_exporter(opentelemetry_otlp::new_exporter().http())
+                .with_trace_config(trace::config().with_resource(Resource::new(vec![
+                    KeyValue::new("service.name", env!("CARGO_PKG_NAME")),
+                ])))
+                .install_batch(runtime::Tokio)
+                .map_err(|e| format!("Can't export traces: {}", e))?;
+        layers.push(tracing_opentelemetry::layer().with_tracer(tracer).boxed());
+    }
+
+    tracing_subscriber::registry()
+        .with(EnvFilter::from_default_env())
+        .with(layers)
+        .try_init()
+        .map_err(|e| format!("Can't install the logger: {}", e))?;
+
+    Ok(Logging { exporting })
+}
+
+// Writes to stderr around the progress bar
+struct StderrWriter;
+
+impl Write for StderrWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        progress::suspend(|| io::stderr().write(buf))
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        progress::suspend(|| io::stderr().write_all(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()
+    }
+}
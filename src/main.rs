@@ -1,244 +1,216 @@
-use crate::brc20_index::{consts, mongo::MongoClient};
-use bitcoincore_rpc;
-use bitcoincore_rpc::{Auth, Client};
-use brc20_index::index_brc20;
-use consulrs::{
-    client::{ConsulClient, ConsulClientSettingsBuilder},
-    kv,
-};
+use crate::brc20_index::error::IndexerError;
+use crate::config::Config;
+use bitcoin::Network;
+use clap::{Parser, Subcommand};
 use dotenv::dotenv;
-use log::{error, info, warn};
-use serde_json;
-use serde_json::Value;
-use std::env;
-use std::time::Instant;
+use log::error;
+use std::path::PathBuf;
+use std::process::ExitCode;
 
 mod brc20_index;
+mod commands;
+mod config;
+mod logging;
+mod server;
+
+#[derive(Parser)]
+#[command(version, about = "BRC20 indexer")]
+struct Cli {
+    /// TOML configuration file, takes precedence over env vars and Consul
+    #[arg(long, env = "INDEXER_CONFIG", global = true)]
+    config: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    dotenv().ok();
-    env_logger::init();
-
-    // Variables for configuration
-    let rpc_url: String;
-    let rpc_user: String;
-    let rpc_password: String;
-    let mongo_connection_str: String;
-    let mut mongo_direct_connection_str: String;
-    let mongo_direct_connection;
-
-    // Check for CONSUL_HOST environment variable
-    if let Ok(consul_host) = env::var("CONSUL_HOST") {
-        let client = ConsulClient::new(
-            ConsulClientSettingsBuilder::default()
-                .address(consul_host)
-                .build()
-                .unwrap(),
-        )
-        .unwrap();
-        let mut res = kv::read(&client, "omnisat-api", None).await.unwrap();
-        let mykey: String = res
-            .response
-            .pop()
-            .unwrap()
-            .value
-            .unwrap()
-            .try_into()
-            .unwrap();
-        let json_value: Value = serde_json::from_str(&mykey).unwrap();
-
-        rpc_url = json_value
-            .get("btc_rpc_host")
-            .unwrap()
-            .as_str()
-            .unwrap()
-            .to_string();
-
-        rpc_user = json_value
-            .get("btc_rpc_user")
-            .unwrap()
-            .as_str()
-            .unwrap()
-            .to_string();
-
-        rpc_password = json_value
-            .get("btc_rpc_pass")
-            .unwrap()
-            .as_str()
-            .unwrap()
-            .to_string();
+#[derive(Subcommand)]
+enum Command {
+    /// Index blocks, this is the default when no command is given
+    Index {
+        /// Roll back to this block and index from it
+        #[arg(long)]
+        from: Option<i64>,
+        /// Stop after indexing this block, overrides STOP_HEIGHT
+        #[arg(long)]
+        to: Option<i64>,
+    },
+    /// Delete everything indexed above a block
+    Rollback {
+        /// Last block to keep
+        #[arg(long)]
+        to: i64,
+    },
+    /// Rebuild every user balance from the balance entries
+    RebuildBalances,
+    /// Recompute the total minted supply of every ticker from its mints
+    RecomputeSupply,
+    /// Bring the database to the schema version of this indexer, indexing does it on startup too
+    Migrate {
+        /// Only list the pending migrations
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Check the indexed data for consistency, exits with an error if it isn't
+    Verify,
+    /// Export collections as JSON lines
+    Export {
+        /// Directory the <collection>.jsonl files are written to
+        #[arg(long, default_value = ".")]
+        out: PathBuf,
+        /// Collection to export, can be repeated, defaults to every collection
+        #[arg(long = "collection")]
+        collections: Vec<String>,
+    },
+    /// Decode a transaction and explain how its BRC20 inscriptions are validated
+    DecodeTx {
+        /// The transaction in hex, or its txid to look it up over RPC
+        tx: String,
+        /// Validate against the state at the start of this block instead of the current one
+        #[arg(long)]
+        height: Option<i64>,
+        /// Network used to derive addresses when there is no valid configuration
+        #[arg(long, default_value = "bitcoin")]
+        network: Network,
+    },
+    /// Predict the BRC20 events and balance changes of a transaction without broadcasting it
+    Simulate {
+        /// The signed transaction in hex, or a PSBT in hex or base64
+        tx: String,
+    },
+    /// Tell whether a transfer inscription is still active, who holds it and what spent it
+    Transfer {
+        /// The inscription id <txid>i0 or the outpoint <txid>:0
+        inscription: String,
+    },
+    /// List the transfer inscriptions of an address that weren't sent yet
+    Transfers {
+        /// The address to look up
+        address: String,
+        /// Only list the inscriptions of this ticker
+        #[arg(long)]
+        tick: Option<String>,
+    },
+    /// Print the balance changes of an address, newest first
+    Activity {
+        /// The address to look up
+        address: String,
+        /// Number of entries to skip
+        #[arg(long, default_value_t = 0)]
+        offset: u64,
+        /// Number of entries to print
+        #[arg(long, default_value_t = 50)]
+        limit: i64,
+    },
+    /// Print the confirmed and pending balances of an address
+    Balance {
+        /// The address to look up
+        address: String,
+    },
+    /// Print document counts and the last completed and final blocks
+    Stats,
+    /// Inspect the configuration
+    #[command(subcommand)]
+    Config(ConfigCommand),
+}
 
-        mongo_direct_connection_str = json_value
-            .get("mongo_direct_connection")
-            .unwrap()
-            .as_str()
-            .unwrap()
-            .to_string();
+#[derive(Subcommand)]
+enum ConfigCommand {
+    /// Load and validate the configuration, reporting every problem found
+    Check,
+}
 
-        // mongo_direct_connection = mongo_direct_connection_str.to_lowercase() == "true";
-        // let mongo_direct_connection_str_env = env::var("MONGO_DIRECT_CONNECTION").ok();
-        if let Ok(mongo_direct_connection_str_env) = env::var("MONGO_DIRECT_CONNECTION") {
-            mongo_direct_connection_str = mongo_direct_connection_str_env;
+#[tokio::main]
+async fn main() -> ExitCode {
+    dotenv().ok();
+    let _logging = match logging::init() {
+        Ok(logging) => logging,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
         }
+    };
 
-        mongo_direct_connection = mongo_direct_connection_str.to_lowercase() == "true";
-        //MongoDB connection string
-        let mongo_host_consul = json_value.get("mongo_rc").unwrap().as_array().unwrap();
-        let mongo_host_env = env::var("MONGO_DB_HOST").ok();
-
-        mongo_connection_str = if let Some(mongo_host_env) = mongo_host_env {
-            format!("mongodb://{}:27017", mongo_host_env)
-        } else {
-            format!(
-                "mongodb://{}:27017,{}:27017,{}:27017/omnisat?replicaSet=rs0",
-                mongo_host_consul[0].as_str().unwrap(),
-                mongo_host_consul[1].as_str().unwrap(),
-                mongo_host_consul[2].as_str().unwrap(),
-            )
-        };
-    } else {
-        mongo_direct_connection_str = env::var("MONGO_DIRECT_CONNECTION").unwrap();
-        mongo_direct_connection = mongo_direct_connection_str.to_lowercase() == "true";
+    let cli = Cli::parse();
 
-        // Pick up environment vars from .env file
-        rpc_url = env::var("RPC_URL").unwrap();
-        rpc_user = env::var("RPC_USER").unwrap();
-        rpc_password = env::var("RPC_PASSWORD").unwrap();
+    let config = Config::load(cli.config.as_deref()).await;
 
-        let mongo_user = env::var("MONGO_USER").ok();
-        let mongo_password = env::var("MONGO_PASSWORD").ok();
-        let mongo_db_host = env::var("MONGO_DB_HOST").unwrap();
+    let command = cli.command.unwrap_or(Command::Index {
+        from: None,
+        to: None,
+    });
 
-        mongo_connection_str = if let (Some(user), Some(password)) = (mongo_user, mongo_password) {
-            format!("mongodb://{}:{}@{}:27017", user, password, mongo_db_host)
-        } else {
-            format!("mongodb://{}:27017", mongo_db_host)
-        };
-    }
+    // Commands that don't need a valid configuration
+    let command = match command {
+        Command::Config(ConfigCommand::Check) => return check_config(config),
+        Command::DecodeTx {
+            tx,
+            height,
+            network,
+        } => return exit_code(commands::decode_tx(config, &tx, network, height).await),
+        command => command,
+    };
 
-    // Connect to Bitcoin Core RPC server
-    let rpc = Client::new(&rpc_url, Auth::UserPass(rpc_user, rpc_password))?;
-    info!("Connected to Bitcoin Core");
+    let config = match config {
+        Ok(config) => config,
+        Err(problems) => {
+            return exit_code(Err(IndexerError::Config(problems.join("; "))));
+        }
+    };
 
-    // Get the mongo database name from environment variable
-    let db_name = env::var("MONGO_DB_NAME").unwrap();
-    let mongo_client =
-        MongoClient::new(&mongo_connection_str, &db_name, mongo_direct_connection).await?;
+    let result = match command {
+        Command::Index { from, to } => commands::index(&config, from, to).await,
+        Command::Rollback { to } => commands::rollback(&config, to).await,
+        Command::RebuildBalances => commands::rebuild_balances(&config).await,
+        Command::RecomputeSupply => commands::recompute_supply(&config).await,
+        Command::Migrate { dry_run } => commands::migrate_schema(&config, dry_run).await,
+        Command::Verify => match commands::verify_index(&config).await {
+            Ok(true) => Ok(()),
+            Ok(false) => return ExitCode::FAILURE,
+            Err(e) => Err(e),
+        },
+        Command::Export { out, collections } => commands::export(&config, &out, &collections).await,
+        Command::Simulate { tx } => commands::simulate(&config, &tx).await,
+        Command::Transfer { inscription } => commands::transfer(&config, &inscription).await,
+        Command::Transfers { address, tick } => {
+            commands::transfers(&config, &address, tick.as_deref()).await
+        }
+        Command::Activity {
+            address,
+            offset,
+            limit,
+        } => commands::activity(&config, &address, offset, limit).await,
+        Command::Balance { address } => commands::balance(&config, &address).await,
+        Command::Stats => commands::stats(&config).await,
+        Command::Config(_) | Command::DecodeTx { .. } => unreachable!(),
+    };
 
-    // Call create_indexes after MongoClient has been initialized
-    mongo_client.create_indexes().await?;
+    exit_code(result)
+}
 
-    let start = Instant::now();
-    // get block height to start indexing from
-    let mut start_block_height = consts::BRC20_STARTING_BLOCK_HEIGHT; // default starting point
-    let last_completed_block = mongo_client
-        .get_last_completed_block_height()
-        .await
-        .unwrap();
-    if let Some(height) = last_completed_block {
-        start_block_height = height + 1; // Start from the next block
+fn exit_code(result: Result<(), IndexerError>) -> ExitCode {
+    match result {
+        Ok(_) => ExitCode::SUCCESS,
+        Err(e) => {
+            error!("{}", e);
+            ExitCode::FAILURE
+        }
     }
-    info!(
-        "Starting indexing from block height: {}",
-        start_block_height
-    );
-
-    warn!("Retrieved starting block height: {:?}", start.elapsed());
-
-    // if BRC20_STARTING_BLOCK_HEIGHT is < start_block_height, then we need to delete everything in db that is >= start_block_height
-    // delete deploys, mints, transfers, inscriptions, tickers, invalids, entries
-    if consts::BRC20_STARTING_BLOCK_HEIGHT < start_block_height {
-        info!("Deleting incomplete records...");
-        let start = Instant::now();
-
-        let collections = vec![
-            consts::COLLECTION_DEPLOYS,
-            consts::COLLECTION_MINTS,
-            consts::COLLECTION_TRANSFERS,
-            consts::COLLECTION_INVALIDS,
-            consts::COLLECTION_TICKERS,
-            consts::COLLECTION_USER_BALANCE_ENTRY,
-            consts::COLLECTION_BRC20_ACTIVE_TRANSFERS,
-            consts::COLLECTION_TOTAL_MINTED_AT_BLOCK_HEIGHT,
-        ];
+}
 
-        for collection in collections {
-            mongo_client
-                .delete_from_collection(collection, start_block_height)
-                .await?;
+// Prints the effective configuration, or every problem that prevents loading it
+fn check_config(config: Result<Config, Vec<String>>) -> ExitCode {
+    match config {
+        Ok(config) => {
+            println!("Configuration is valid:\n{:#?}", config);
+            ExitCode::SUCCESS
         }
-
-        warn!("Incomplete Block Records deleted: {:?}", start.elapsed());
-
-        info!("Resetting total_minted for selected tickers...");
-        let start = Instant::now();
-        match mongo_client
-            .reset_tickers_total_minted(start_block_height)
-            .await
-        {
-            Ok(updated_tickers) => {
-                info!("Reset total_minted for the following tickers:");
-                for ticker in &updated_tickers {
-                    info!("{}", ticker);
-                }
-                warn!("Reset total_minted for tickers in: {:?}", start.elapsed());
-                updated_tickers
-            }
-            Err(e) => {
-                error!("Error resetting total_minted for tickers: {:?}", e);
-                Vec::new() // Return an empty vector if there is an error
-            }
-        };
-
-        // let start = Instant::now();
-        // //recalculate total_minted for each ticker
-        // info!("Recalculating total_minted for all tickers...");
-        // match mongo_client
-        //     .update_ticker_totals(start_block_height - 1)
-        //     .await
-        // {
-        //     Ok(_) => {
-        //         warn!("Recalculation complete: {:?}", start.elapsed())
-        //     }
-        //     Err(e) => error!("Error recalculating total_minted for all tickers: {:?}", e),
-        // };
-
-        info!("Deleting User Balances...");
-        let start = Instant::now();
-        //delete user balance collection
-        let deleted_user_balances = mongo_client
-            .delete_user_balances_by_block_height(start_block_height)
-            .await;
-        info!("Deleted User Balances: {:?}", deleted_user_balances);
-
-        warn!("User Balances Deleted: {:?}", start.elapsed());
-
-        // rebuild userbalances
-        info!("Rebuilding User Balances...");
-        let start = Instant::now();
-        match deleted_user_balances {
-            Ok(deleted_balances) => {
-                // Call the `rebuild_deleted_user_balances` function
-                let rebuilt_result = mongo_client
-                    .rebuild_deleted_user_balances(start_block_height, deleted_balances)
-                    .await;
-                if let Err(err) = rebuilt_result {
-                    error!("Failed to rebuild user balances: {:?}", err);
-                }
-            }
-            Err(err) => {
-                error!("Failed to delete user balances: {:?}", err);
+        Err(problems) => {
+            eprintln!("Found {} configuration problem(s):", problems.len());
+            for problem in problems {
+                eprintln!("  - {}", problem);
             }
+            ExitCode::FAILURE
         }
-        warn!("User Balances Rebuilt: {:?}", start.elapsed());
     }
-
-    // LFG!
-    match index_brc20(&rpc, &mongo_client, start_block_height.try_into().unwrap()).await {
-        Ok(_) => info!("Finished indexing BRC20 tokens"),
-        Err(e) => error!("Error indexing BRC20 tokens: {:?}", e),
-    };
-
-    Ok(())
 }
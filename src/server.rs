@@ -0,0 +1,337 @@
+use crate::brc20_index::{
+    error::{ErrorAction, IndexerError},
+    health::{health, Check, HealthThresholds},
+    metrics::metrics,
+    mongo::MongoClient,
+    simulate::{dry_run, parse_transaction, Effect},
+    storage::fetch_raw_tx,
+    transfer_status::{get_transfer_statuses, parse_inscription_ref, InscriptionRef},
+};
+use crate::config::Config;
+use bitcoin::Txid;
+use bitcoincore_rpc::Client;
+use hyper::body::HttpBody;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Method, Request, Response, Server, StatusCode};
+use log::{error, info};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::{Arc, OnceLock};
+
+// A PSBT in base64 of the largest standard transaction fits with room to spare
+const MAX_SIMULATE_BODY_BYTES: usize = 4 * 1024 * 1024;
+
+// Entries of an activity page when no limit is given, and the most a page can have
+const DEFAULT_ACTIVITY_LIMIT: i64 = 50;
+const MAX_ACTIVITY_LIMIT: i64 = 500;
+
+// The connections the API endpoints query, set once the indexer is connected
+pub struct Api {
+    pub rpc: Client,
+    pub mongo_client: MongoClient,
+    pub config: Config,
+}
+
+// Serves the operational endpoints in the background for as long as the process runs
+pub fn serve(
+    addr: SocketAddr,
+    thresholds: HealthThresholds,
+    api: Arc<OnceLock<Api>>,
+) -> Result<(), IndexerError> {
+    let server = Server::try_bind(&addr)
+        .map_err(|e| IndexerError::Config(format!("Can't listen on {}: {}", addr, e)))?
+        .serve(make_service_fn(move |_| {
+            let api = api.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    handle(request, thresholds, api.clone())
+                }))
+            }
+        }));
+    info!(
+        "Serving /metrics, /livez, /readyz, /transfers/<id> and POST /simulate at http://{}",
+        addr
+    );
+
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            error!("HTTP server stopped: {}", e);
+        }
+    });
+
+    Ok(())
+}
+
+async fn handle(
+    request: Request<Body>,
+    thresholds: HealthThresholds,
+    api: Arc<OnceLock<Api>>,
+) -> Result<Response<Body>, Infallible> {
+    let response = match (request.method(), request.uri().path()) {
+        (&Method::GET, "/metrics") => Response::builder()
+            .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+            .body(Body::from(metrics().render())),
+        (&Method::GET, "/livez") => check_response(health().liveness(&thresholds)),
+        (&Method::GET, "/readyz") => check_response(health().readiness(&thresholds)),
+        (&Method::POST, "/simulate") => simulate(request.into_body(), api.get()).await,
+        (&Method::GET, path) if path.starts_with("/addresses/") && path.ends_with("/balances") => {
+            let address = &path["/addresses/".len()..path.len() - "/balances".len()];
+            balances(address, api.get()).await
+        }
+        (&Method::GET, path) if path.starts_with("/addresses/") && path.ends_with("/transfers") => {
+            let address = &path["/addresses/".len()..path.len() - "/transfers".len()];
+            let tick = query_param(&request, "tick");
+            active_transfers(address, tick.as_deref(), api.get()).await
+        }
+        (&Method::GET, path) if path.starts_with("/addresses/") && path.ends_with("/activity") => {
+            let address = &path["/addresses/".len()..path.len() - "/activity".len()];
+            let offset = query_param(&request, "offset").and_then(|offset| offset.parse().ok());
+            let limit = query_param(&request, "limit").and_then(|limit| limit.parse().ok());
+            activity(
+                address,
+                offset.unwrap_or(0),
+                limit
+                    .unwrap_or(DEFAULT_ACTIVITY_LIMIT)
+                    .clamp(1, MAX_ACTIVITY_LIMIT),
+                api.get(),
+            )
+            .await
+        }
+        (&Method::GET, path) if path.starts_with("/tx/") => {
+            raw_tx(&path["/tx/".len()..], api.get()).await
+        }
+        (&Method::GET, path) if path.starts_with("/transfers/") => {
+            transfer_status(&path["/transfers/".len()..], api.get()).await
+        }
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty()),
+    };
+
+    Ok(response.unwrap_or_default())
+}
+
+// 200 with "ok", or 503 with one problem per line
+fn check_response(check: Check) -> Result<Response<Body>, hyper::http::Error> {
+    let (status, body) = if check.ok {
+        (StatusCode::OK, "ok\n".to_string())
+    } else {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            check.problems.join("\n") + "\n",
+        )
+    };
+
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "text/plain")
+        .body(Body::from(body))
+}
+
+// Simulates the transaction or PSBT in the body, as hex or base64, and answers with the
+// predicted events and balance deltas as JSON. Nothing is written.
+async fn simulate(mut body: Body, api: Option<&Api>) -> Result<Response<Body>, hyper::http::Error> {
+    let api = match api {
+        Some(api) => api,
+        None => return not_connected(),
+    };
+
+    let mut input = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => return text_response(StatusCode::BAD_REQUEST, &e.to_string()),
+        };
+        if input.len() + chunk.len() > MAX_SIMULATE_BODY_BYTES {
+            return text_response(StatusCode::PAYLOAD_TOO_LARGE, "transaction too large");
+        }
+        input.extend_from_slice(&chunk);
+    }
+
+    let result = match parse_transaction(&String::from_utf8_lossy(&input)) {
+        Ok(transaction) => dry_run(&api.rpc, &api.mongo_client, &api.config, &transaction).await,
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(dry_run) => Response::builder()
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                serde_json::to_string(&dry_run).unwrap_or_default(),
+            )),
+        // The transaction can't be decoded or breaks the rules
+        Err(e) if e.action() == ErrorAction::Skip => {
+            text_response(StatusCode::BAD_REQUEST, &e.to_string())
+        }
+        Err(e) => {
+            error!("Failed to simulate a transaction: {}", e);
+            text_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string())
+        }
+    }
+}
+
+// The status of a transfer inscription by inscription id, or of the ones created at an outpoint
+// as a list, as JSON
+async fn transfer_status(
+    inscription: &str,
+    api: Option<&Api>,
+) -> Result<Response<Body>, hyper::http::Error> {
+    let api = match api {
+        Some(api) => api,
+        None => return not_connected(),
+    };
+    let inscription_ref = match parse_inscription_ref(inscription) {
+        Ok(inscription_ref) => inscription_ref,
+        Err(e) => return text_response(StatusCode::BAD_REQUEST, &e.to_string()),
+    };
+
+    match get_transfer_statuses(&api.mongo_client, &inscription_ref).await {
+        Ok(statuses) if statuses.is_empty() => {
+            text_response(StatusCode::NOT_FOUND, "no valid transfer inscription")
+        }
+        Ok(statuses) => {
+            let body = match inscription_ref {
+                InscriptionRef::Id(_) => serde_json::to_string(&statuses[0]),
+                InscriptionRef::Outpoint(_) => serde_json::to_string(&statuses),
+            };
+            Response::builder()
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.unwrap_or_default()))
+        }
+        Err(e) => {
+            error!("Failed to look up transfer {}: {}", inscription, e);
+            text_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string())
+        }
+    }
+}
+
+// The confirmed and pending balances of an address by tick and the pending effects of the
+// mempool transactions on it, oldest first, as JSON
+async fn balances(address: &str, api: Option<&Api>) -> Result<Response<Body>, hyper::http::Error> {
+    let api = match api {
+        Some(api) => api,
+        None => return not_connected(),
+    };
+
+    let result = async {
+        let views = api.mongo_client.get_balance_views(address).await?;
+        let effects = api
+            .mongo_client
+            .get_mempool_effects(address)
+            .await?
+            .iter()
+            .map(Effect::try_from)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok::<_, IndexerError>(serde_json::json!({ "balances": views, "mempool": effects }))
+    }
+    .await;
+    match result {
+        Ok(balances) => Response::builder()
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(balances.to_string())),
+        Err(e) => {
+            error!("Failed to load the balances of {}: {}", address, e);
+            text_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string())
+        }
+    }
+}
+
+// The unsent transfer inscriptions of an address as JSON, optionally of one tick
+async fn active_transfers(
+    address: &str,
+    tick: Option<&str>,
+    api: Option<&Api>,
+) -> Result<Response<Body>, hyper::http::Error> {
+    let api = match api {
+        Some(api) => api,
+        None => return not_connected(),
+    };
+
+    match api.mongo_client.get_active_transfers(address, tick).await {
+        Ok(active_transfers) => Response::builder()
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                serde_json::to_string(&active_transfers).unwrap_or_default(),
+            )),
+        Err(e) => {
+            error!("Failed to load the transfers of {}: {}", address, e);
+            text_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string())
+        }
+    }
+}
+
+// A page of the ledger entries of an address as JSON, newest first
+async fn activity(
+    address: &str,
+    offset: u64,
+    limit: i64,
+    api: Option<&Api>,
+) -> Result<Response<Body>, hyper::http::Error> {
+    let api = match api {
+        Some(api) => api,
+        None => return not_connected(),
+    };
+
+    match api
+        .mongo_client
+        .get_address_activity(address, offset, limit)
+        .await
+    {
+        Ok(entries) => Response::builder()
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                serde_json::to_string(&entries).unwrap_or_default(),
+            )),
+        Err(e) => {
+            error!("Failed to load the activity of {}: {}", address, e);
+            text_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string())
+        }
+    }
+}
+
+// A whole transaction fetched from the node as JSON, for what the slim storage profile leaves out
+async fn raw_tx(txid: &str, api: Option<&Api>) -> Result<Response<Body>, hyper::http::Error> {
+    let api = match api {
+        Some(api) => api,
+        None => return not_connected(),
+    };
+    let txid = match Txid::from_str(txid) {
+        Ok(txid) => txid,
+        Err(e) => return text_response(StatusCode::BAD_REQUEST, &format!("Invalid txid: {}", e)),
+    };
+
+    match fetch_raw_tx(&api.rpc, &api.config.retry_policy, &txid).await {
+        Ok(raw_tx) => Response::builder()
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                serde_json::to_string(&raw_tx).unwrap_or_default(),
+            )),
+        Err(e) => {
+            error!("Failed to fetch transaction {}: {}", txid, e);
+            text_response(StatusCode::BAD_GATEWAY, &e.to_string())
+        }
+    }
+}
+
+// The decoded value of a query string parameter
+fn query_param(request: &Request<Body>, name: &str) -> Option<String> {
+    request.uri().query().and_then(|query| {
+        form_urlencoded::parse(query.as_bytes())
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
+    })
+}
+
+fn not_connected() -> Result<Response<Body>, hyper::http::Error> {
+    text_response(
+        StatusCode::SERVICE_UNAVAILABLE,
+        "not connected to the node and MongoDB yet",
+    )
+}
+
+fn text_response(status: StatusCode, body: &str) -> Result<Response<Body>, hyper::http::Error> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "text/plain")
+        .body(Body::from(format!("{}\n", body)))
+}